@@ -0,0 +1,139 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use market_model::{Encoding, Envelope};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+// How long to wait for any one message before failing the test
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A WebSocket client of the proxy, as a browser would use it
+pub struct TestClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    pub async fn connect(proxy: SocketAddr) -> Self {
+        let url = format!("ws://127.0.0.1:{}/ws", proxy.port());
+        let (socket, _) = connect_async(url).await.expect("WebSocket connects to the proxy");
+        TestClient { socket }
+    }
+
+    pub async fn send(&mut self, request: Value) {
+        self.socket
+            .send(Message::Text(request.to_string()))
+            .await
+            .expect("request is sent");
+    }
+
+    // Subscribe to `subject` and wait for the confirmation
+    pub async fn subscribe(&mut self, subject: &str) {
+        self.subscribe_encoded(subject, Encoding::Json).await;
+    }
+
+    // Subscribe to `subject` with messages sent in `encoding`
+    pub async fn subscribe_encoded(&mut self, subject: &str, encoding: Encoding) {
+        self.subscribe_with(json!({ "action": "subscribe", "subject": subject, "encoding": encoding })).await;
+    }
+
+    // Send a subscribe `request` with options of its own and wait for the
+    // confirmation
+    pub async fn subscribe_with(&mut self, request: Value) {
+        let subject = request["subject"].clone();
+        self.send(request).await;
+        loop {
+            let reply = self.next_json().await;
+            match reply["type"].as_str() {
+                Some("subscription_confirmed") if reply["subject"] == subject => return,
+                Some("error") => panic!("subscribing to {} failed: {}", subject, reply),
+                _ => {}
+            }
+        }
+    }
+
+    // The next text frame as JSON, skipping pings and other control frames
+    pub async fn next_json(&mut self) -> Value {
+        loop {
+            let frame = timeout(RECEIVE_TIMEOUT, self.socket.next())
+                .await
+                .expect("a message arrives in time")
+                .expect("the connection stays open")
+                .expect("the frame is valid");
+            if let Message::Text(text) = frame {
+                return serde_json::from_str(&text).expect("the proxy sends JSON");
+            }
+        }
+    }
+
+    // Every text frame that arrives within `window`, as JSON
+    pub async fn json_within(&mut self, window: Duration) -> Vec<Value> {
+        let deadline = tokio::time::Instant::now() + window;
+        let mut values = Vec::new();
+        while let Ok(frame) = tokio::time::timeout_at(deadline, self.socket.next()).await {
+            match frame.expect("the connection stays open").expect("the frame is valid") {
+                Message::Text(text) => values.push(serde_json::from_str(&text).expect("the proxy sends JSON")),
+                _ => continue,
+            }
+        }
+        values
+    }
+
+    // The next binary frame decoded from `encoding`, skipping text frames
+    pub async fn next_encoded(&mut self, encoding: Encoding) -> Envelope {
+        loop {
+            let frame = timeout(RECEIVE_TIMEOUT, self.socket.next())
+                .await
+                .expect("a message arrives in time")
+                .expect("the connection stays open")
+                .expect("the frame is valid");
+            if let Message::Binary(bytes) = frame {
+                return encoding.decode(&bytes).expect("binary frames hold envelopes");
+            }
+        }
+    }
+
+    // The next binary frame decoded from `encoding` as a reply, such as a
+    // snapshot, skipping text frames
+    pub async fn next_encoded_reply(&mut self, encoding: Encoding) -> Value {
+        loop {
+            let frame = timeout(RECEIVE_TIMEOUT, self.socket.next())
+                .await
+                .expect("a message arrives in time")
+                .expect("the connection stays open")
+                .expect("the frame is valid");
+            if let Message::Binary(bytes) = frame {
+                return encoding.decode(&bytes).expect("binary frames hold replies");
+            }
+        }
+    }
+
+    // Read until the proxy closes the connection, answering its close
+    // frame as a browser would, and return the `shutdown` notice it sent
+    pub async fn wait_for_shutdown(mut self) -> Option<Value> {
+        let mut notice = None;
+        while let Ok(Some(Ok(frame))) = timeout(RECEIVE_TIMEOUT, self.socket.next()).await {
+            if let Message::Text(text) = frame {
+                let reply: Value = serde_json::from_str(&text).expect("the proxy sends JSON");
+                if reply["type"] == "shutdown" {
+                    notice = Some(reply);
+                }
+            }
+        }
+        notice
+    }
+
+    // The next published message, skipping replies and history snapshots
+    pub async fn next_message(&mut self) -> Envelope {
+        loop {
+            let value = self.next_json().await;
+            if value.get("type").is_none() {
+                return serde_json::from_value(value).expect("messages are envelopes");
+            }
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use std::net::SocketAddr;
+
+use hyper::{Body, Client, Request};
+use serde_json::Value;
+
+// POST `body` to `path` on the proxy, with a bearer token if given, and
+// return the status and the JSON reply
+pub async fn post(proxy: SocketAddr, path: &str, content_type: &str, token: Option<&str>, body: String) -> (u16, Value) {
+    let mut request = Request::post(format!("http://127.0.0.1:{}{}", proxy.port(), path))
+        .header("Content-Type", content_type);
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {}", token));
+    }
+    let request = request.body(Body::from(body)).expect("request is valid");
+
+    let response = Client::new().request(request).await.expect("the proxy answers");
+    let status = response.status().as_u16();
+    let body = hyper::body::to_bytes(response.into_body()).await.expect("the body arrives");
+    (status, serde_json::from_slice(&body).expect("the proxy replies with JSON"))
+}
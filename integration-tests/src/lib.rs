@@ -0,0 +1,25 @@
+// Helpers for end-to-end tests: a proxy started in-process on free ports, a
+// stand-in NATS server for it to connect to, a WebSocket client to
+// subscribe through it and an HTTP client to publish through it
+pub mod client;
+pub mod http;
+pub mod nats;
+
+use std::ffi::OsString;
+
+use rt_duckdb_coinbase_server::server::{load_args, Server};
+
+pub use client::TestClient;
+pub use http::post;
+pub use nats::Broker;
+
+// Start a proxy with `flags` on top of free ports for both listeners
+pub async fn start_proxy(flags: &[&str]) -> Server {
+    let argv: Vec<OsString> = ["rt-duckdb-coinbase-server", "--proxy-port", "0", "--http-port", "0"]
+        .iter()
+        .chain(flags)
+        .map(OsString::from)
+        .collect();
+    let (args, config) = load_args(&argv).unwrap_or_else(|e| panic!("invalid proxy flags {:?}: {}", flags, e));
+    Server::start(args, config).await.expect("proxy starts")
+}
@@ -0,0 +1,181 @@
+// Just enough of a NATS server for the proxy and publisher to talk through:
+// the handshake, PING/PONG, SUB/UNSUB and PUB/HPUB routed to matching
+// subscriptions, and a "no responders" status for requests nobody answers.
+// No JetStream, queue groups or authentication.
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+use rt_duckdb_coinbase_server::subject;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+struct Subscription {
+    connection: u64,
+    sid: String,
+    pattern: String,
+    outbox: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Routes {
+    subscriptions: Vec<Subscription>,
+    next_connection: u64,
+}
+
+pub struct Broker {
+    addr: SocketAddr,
+    accept: JoinHandle<()>,
+}
+
+impl Broker {
+    // Listen on a free local port
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("broker binds");
+        let addr = listener.local_addr().expect("broker has an address");
+        let routes = Arc::new(Mutex::new(Routes::default()));
+        let accept = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let routes = routes.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, addr, &routes).await {
+                        debug!("NATS test connection ended: {}", e);
+                    }
+                });
+            }
+        });
+        Broker { addr, accept }
+    }
+
+    pub fn url(&self) -> String {
+        format!("nats://{}", self.addr)
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        self.accept.abort();
+    }
+}
+
+async fn serve(stream: TcpStream, addr: SocketAddr, routes: &Mutex<Routes>) -> std::io::Result<()> {
+    let connection = {
+        let mut routes = routes.lock().unwrap();
+        routes.next_connection += 1;
+        routes.next_connection
+    };
+    let (reader, mut writer) = stream.into_split();
+    let (outbox, mut outgoing) = mpsc::unbounded_channel::<Vec<u8>>();
+    let writer_task = tokio::spawn(async move {
+        while let Some(bytes) = outgoing.recv().await {
+            if writer.write_all(&bytes).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let info = format!(
+        "INFO {{\"server_id\":\"test\",\"server_name\":\"test\",\"version\":\"2.10.0\",\"go\":\"go1.21\",\
+         \"host\":\"{}\",\"port\":{},\"headers\":true,\"max_payload\":1048576,\"proto\":1}}\r\n",
+        addr.ip(),
+        addr.port()
+    );
+    let _ = outbox.send(info.into_bytes());
+
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    let result = loop {
+        line.clear();
+        match reader.read_line(&mut line).await {
+            Ok(0) => break Ok(()),
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some(op) = words.first() else {
+            continue;
+        };
+        match op.to_ascii_uppercase().as_str() {
+            "PING" => {
+                let _ = outbox.send(b"PONG\r\n".to_vec());
+            },
+            "SUB" => {
+                // SUB <subject> [queue group] <sid>
+                let (Some(pattern), Some(sid)) = (words.get(1), words.last()) else {
+                    continue;
+                };
+                routes.lock().unwrap().subscriptions.push(Subscription {
+                    connection,
+                    sid: sid.to_string(),
+                    pattern: pattern.to_string(),
+                    outbox: outbox.clone(),
+                });
+            },
+            "UNSUB" => {
+                if let Some(sid) = words.get(1) {
+                    routes
+                        .lock()
+                        .unwrap()
+                        .subscriptions
+                        .retain(|sub| !(sub.connection == connection && sub.sid == *sid));
+                }
+            },
+            // PUB <subject> [reply-to] <size> and HPUB <subject> [reply-to] <header size> <size>
+            "PUB" | "HPUB" => {
+                let headers = op.eq_ignore_ascii_case("HPUB");
+                let sizes = if headers { 2 } else { 1 };
+                if words.len() < 2 + sizes {
+                    continue;
+                }
+                let subject = words[1];
+                let reply = (words.len() == 3 + sizes).then(|| words[2]);
+                let size: usize = words[words.len() - 1].parse().unwrap_or(0);
+                let mut payload = vec![0; size + 2];
+                if let Err(e) = reader.read_exact(&mut payload).await {
+                    break Err(e);
+                }
+                payload.truncate(size);
+                let header_size = headers.then(|| words[words.len() - 2]);
+                let delivered = publish(routes, subject, reply, header_size, &payload);
+
+                // As a real server does, so requests such as JetStream
+                // API calls fail at once instead of timing out
+                if let (Some(reply), false) = (reply, delivered) {
+                    let status = NO_RESPONDERS.len().to_string();
+                    publish(routes, reply, None, Some(&status), NO_RESPONDERS);
+                }
+            },
+            // CONNECT needs no reply without verbose mode
+            _ => {}
+        }
+    };
+
+    routes.lock().unwrap().subscriptions.retain(|sub| sub.connection != connection);
+    writer_task.abort();
+    result
+}
+
+// Headers of the status message sent back for a request nobody received
+const NO_RESPONDERS: &[u8] = b"NATS/1.0 503\r\n\r\n";
+
+// Route a message to every matching subscription; returns whether there
+// was any
+fn publish(routes: &Mutex<Routes>, subject: &str, reply: Option<&str>, header_size: Option<&str>, payload: &[u8]) -> bool {
+    let routes = routes.lock().unwrap();
+    let mut delivered = false;
+    for sub in routes.subscriptions.iter().filter(|sub| subject::matches(&sub.pattern, subject)) {
+        delivered = true;
+        let reply = reply.map(|reply| format!(" {}", reply)).unwrap_or_default();
+        let head = match header_size {
+            Some(header_size) => format!("HMSG {} {}{} {} {}\r\n", subject, sub.sid, reply, header_size, payload.len()),
+            None => format!("MSG {} {}{} {}\r\n", subject, sub.sid, reply, payload.len()),
+        };
+        let mut message = head.into_bytes();
+        message.extend_from_slice(payload);
+        message.extend_from_slice(b"\r\n");
+        let _ = sub.outbox.send(message);
+    }
+    delivered
+}
@@ -0,0 +1,88 @@
+// Subscriptions that ask for their messages batched or conflated
+use integration_tests::{post, start_proxy, TestClient};
+use serde_json::{json, Value};
+
+fn trade(price: f64) -> Value {
+    json!({ "price": price, "size": 0.5, "side": "buy", "exchange": "test", "pair": "BTC-USD", "timestamp": 1700000000000u64 })
+}
+
+fn ticker(price: f64) -> Value {
+    json!({ "pair": "BTC-USD", "price": price, "timestamp": 1700000000000u64 })
+}
+
+fn ndjson(payloads: &[Value]) -> String {
+    payloads.iter().map(|payload| format!("{}\n", payload)).collect()
+}
+
+#[tokio::test]
+async fn batched_subscriptions_get_messages_together() {
+    let server = start_proxy(&[]).await;
+    let addr = server.proxy_addr();
+    let mut client = TestClient::connect(addr).await;
+    client
+        .subscribe_with(json!({
+            "action": "subscribe",
+            "subject": "market.btc-usd.trades",
+            "delivery": { "mode": "batch", "interval_ms": 200 },
+        }))
+        .await;
+
+    let trades = [trade(100.0), trade(101.0), trade(102.0)];
+    let (status, _) = post(addr, "/publish/market.btc-usd.trades", "application/x-ndjson", None, ndjson(&trades)).await;
+    assert_eq!(status, 200);
+
+    let batch = loop {
+        let reply = client.next_json().await;
+        if reply["type"] == "batch" {
+            break reply;
+        }
+        assert_ne!(reply["type"], Value::Null, "no message arrives on its own: {}", reply);
+    };
+    assert_eq!(batch["subject"], "market.btc-usd.trades");
+    let prices: Vec<Value> = batch["messages"].as_array().unwrap().iter().map(|m| m["data"].clone()).collect();
+    assert_eq!(prices, trades);
+
+    let (notice, ()) = tokio::join!(client.wait_for_shutdown(), server.stop());
+    assert!(notice.is_some(), "clients are told about the shutdown");
+}
+
+#[tokio::test]
+async fn conflated_subscriptions_get_the_latest_message_per_subject() {
+    let server = start_proxy(&[]).await;
+    let addr = server.proxy_addr();
+    let mut client = TestClient::connect(addr).await;
+    client
+        .subscribe_with(json!({
+            "action": "subscribe",
+            "subject": "market.*.ticker",
+            "delivery": { "mode": "conflate", "interval_ms": 200 },
+        }))
+        .await;
+
+    let (status, _) = post(addr, "/publish/market.btc-usd.ticker", "application/x-ndjson", None, ndjson(&[ticker(1.0), ticker(2.0), ticker(3.0)])).await;
+    assert_eq!(status, 200);
+    let message = client.next_message().await;
+    assert_eq!((message.subject.as_str(), message.data), ("market.btc-usd.ticker", ticker(3.0)));
+
+    // The earlier tickers were never queued, so the next message is newer still
+    let (status, _) = post(addr, "/publish/market.btc-usd.ticker", "application/json", None, ticker(4.0).to_string()).await;
+    assert_eq!(status, 200);
+    assert_eq!(client.next_message().await.data, ticker(4.0));
+
+    // Intervals out of range, and replays, are refused
+    let refused = [
+        json!({ "delivery": { "mode": "batch", "interval_ms": 0 } }),
+        json!({ "delivery": { "mode": "conflate", "interval_ms": 3_600_000 } }),
+        json!({ "delivery": { "mode": "batch", "interval_ms": 100 }, "replay": { "last": 5 } }),
+    ];
+    for mut request in refused {
+        request["action"] = json!("subscribe");
+        request["subject"] = json!("market.eth-usd.ticker");
+        client.send(request).await;
+        let reply = client.next_json().await;
+        assert_eq!((reply["type"].clone(), reply["code"].clone()), (json!("error"), json!("invalid_request")), "{}", reply);
+    }
+
+    let (notice, ()) = tokio::join!(client.wait_for_shutdown(), server.stop());
+    assert!(notice.is_some(), "clients are told about the shutdown");
+}
@@ -0,0 +1,71 @@
+// Messages delivered in the encoding each subscription asked for
+use integration_tests::{post, start_proxy, Broker, TestClient};
+use market_model::{Encoding, Envelope, MarketData};
+use serde_json::json;
+
+#[tokio::test]
+async fn subscribers_get_messages_in_their_encoding() {
+    let server = start_proxy(&[]).await;
+    let addr = server.proxy_addr();
+    let mut json_client = TestClient::connect(addr).await;
+    json_client.subscribe("market.btc-usd.trades").await;
+    let mut binary_clients = Vec::new();
+    for encoding in [Encoding::Msgpack, Encoding::Cbor] {
+        let mut client = TestClient::connect(addr).await;
+        client.subscribe_encoded("market.btc-usd.trades", encoding).await;
+        binary_clients.push((encoding, client));
+    }
+
+    let trade = json!({ "price": 30012.4, "size": 0.07, "side": "sell", "exchange": "test", "pair": "BTC-USD", "timestamp": 1700000000000u64 });
+    let (status, _) = post(addr, "/publish/market.btc-usd.trades", "application/json", None, trade.to_string()).await;
+    assert_eq!(status, 200);
+
+    let expected = json_client.next_message().await;
+    for (encoding, client) in &mut binary_clients {
+        let message = client.next_encoded(*encoding).await;
+        assert_eq!(message, expected, "{:?} decodes to the same envelope", encoding);
+        assert!(matches!(message.market_data(), Ok(MarketData::Trade(_))));
+    }
+
+    let mut clients = vec![json_client];
+    clients.extend(binary_clients.into_iter().map(|(_, client)| client));
+    let notices = futures::future::join_all(clients.into_iter().map(TestClient::wait_for_shutdown));
+    let (notices, ()) = tokio::join!(notices, server.stop());
+    assert!(notices.iter().all(Option::is_some), "clients are told about the shutdown");
+}
+
+#[tokio::test]
+async fn snapshots_and_replays_follow_the_subscription_encoding() {
+    let broker = Broker::start().await;
+    let server = start_proxy(&["--nex-url", &broker.url()]).await;
+    let addr = server.proxy_addr();
+    let trade = json!({ "price": 30012.4, "size": 0.07, "side": "sell", "exchange": "test", "pair": "BTC-USD", "timestamp": 1700000000000u64 });
+    let (status, _) = post(addr, "/publish/market.btc-usd.trades", "application/json", None, trade.to_string()).await;
+    assert_eq!(status, 200);
+
+    // The history snapshot a new subscription starts with
+    let mut snapshot_client = TestClient::connect(addr).await;
+    snapshot_client.subscribe_encoded("market.btc-usd.trades", Encoding::Msgpack).await;
+    let snapshot = snapshot_client.next_encoded_reply(Encoding::Msgpack).await;
+    assert_eq!(snapshot["type"], "snapshot");
+    assert_eq!(snapshot["messages"][0]["data"], trade);
+
+    // The stand-in broker has no JetStream, so the replay ends in failure,
+    // after which live messages flow in the same encoding
+    let mut replay_client = TestClient::connect(addr).await;
+    replay_client
+        .subscribe_with(json!({ "action": "subscribe", "subject": "market.btc-usd.trades", "encoding": "msgpack", "replay": { "last": 5 } }))
+        .await;
+    let outcome = replay_client.next_encoded_reply(Encoding::Msgpack).await;
+    assert_eq!(outcome["type"], "error");
+    assert_eq!(outcome["code"], "replay_failed");
+
+    let (status, _) = post(addr, "/publish/market.btc-usd.trades", "application/json", None, trade.to_string()).await;
+    assert_eq!(status, 200);
+    let message: Envelope = replay_client.next_encoded(Encoding::Msgpack).await;
+    assert_eq!(message.subject, "market.btc-usd.trades");
+
+    let notices = futures::future::join_all([snapshot_client, replay_client].map(TestClient::wait_for_shutdown));
+    let (notices, ()) = tokio::join!(notices, server.stop());
+    assert!(notices.iter().all(Option::is_some), "clients are told about the shutdown");
+}
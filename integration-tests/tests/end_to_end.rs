@@ -0,0 +1,113 @@
+// Messages from a publisher through the proxy to WebSocket subscribers
+use std::time::Duration;
+
+use integration_tests::{start_proxy, Broker, TestClient};
+use market_model::{Level2, MarketData, Validate};
+
+#[tokio::test]
+async fn simulated_market_reaches_subscribers() {
+    let server = start_proxy(&["--simulate"]).await;
+    let mut client = TestClient::connect(server.proxy_addr()).await;
+    client.subscribe("market.btc-usd.>").await;
+
+    let (mut trades, mut tickers, mut updates) = (0, 0, 0);
+    let mut sequence = None;
+    while trades == 0 || tickers == 0 || updates < 2 {
+        let message = client.next_message().await;
+        message.validate().expect("envelope is valid");
+        let data = message.market_data().expect("simulator publishes market data");
+        data.validate().expect("market data is valid");
+        assert_eq!(data.pair(), "BTC-USD");
+        match data {
+            MarketData::Trade(_) => {
+                assert_eq!(message.subject, "market.btc-usd.trades");
+                trades += 1;
+            },
+            MarketData::Ticker(_) => {
+                assert_eq!(message.subject, "market.btc-usd.ticker");
+                tickers += 1;
+            },
+            MarketData::Level2(level2) => {
+                assert_eq!(message.subject, "market.btc-usd.level2");
+                // Updates follow on from the snapshot and from each other
+                match (&level2, sequence) {
+                    (Level2::Update(update), Some(last)) => {
+                        assert_eq!(update.sequence, last + 1);
+                        updates += 1;
+                    },
+                    (Level2::Update(_), None) => {},
+                    (Level2::Snapshot(snapshot), Some(last)) => assert_eq!(snapshot.sequence, last),
+                    (Level2::Snapshot(_), None) => {},
+                }
+                sequence = Some(level2.sequence());
+            },
+            MarketData::Candle(_) => panic!("the simulator publishes no candles"),
+        }
+    }
+
+    let (notice, ()) = tokio::join!(client.wait_for_shutdown(), server.stop());
+    assert!(notice.is_some(), "clients are told about the shutdown");
+}
+
+#[tokio::test]
+async fn published_messages_flow_through_nats() {
+    let broker = Broker::start().await;
+    let server = start_proxy(&["--nex-url", &broker.url()]).await;
+    let mut client = TestClient::connect(server.proxy_addr()).await;
+    client.subscribe("market.btc-usd.trades").await;
+
+    let nats = async_nats::connect(broker.url()).await.expect("publisher connects");
+    let publisher = tokio::spawn(async move {
+        nex_publisher::publish_simulated_data(nats, "market.btc-usd.{channel}".to_string(), "BTC-USD".to_string(), 100, 30000.0, 0.3)
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    // Only the subscribed channel arrives, as published
+    for _ in 0..5 {
+        let message = client.next_message().await;
+        assert_eq!(message.subject, "market.btc-usd.trades");
+        assert!(message.timestamp.is_some());
+        match message.market_data().expect("publisher sends market data") {
+            MarketData::Trade(trade) => {
+                trade.validate().expect("trade is valid");
+                assert_eq!(trade.exchange, "nex");
+                assert_eq!(trade.pair, "BTC-USD");
+            },
+            other => panic!("expected a trade, got {:?}", other),
+        }
+    }
+
+    publisher.abort();
+    let stopped = tokio::time::timeout(Duration::from_secs(10), server.stop());
+    let (notice, stopped) = tokio::join!(client.wait_for_shutdown(), stopped);
+    stopped.expect("proxy shuts down");
+    assert!(notice.is_some(), "clients are told about the shutdown");
+}
+
+#[tokio::test]
+async fn unsubscribed_clients_stop_receiving() {
+    let server = start_proxy(&["--simulate"]).await;
+    let mut client = TestClient::connect(server.proxy_addr()).await;
+    client.subscribe("market.btc-usd.ticker").await;
+    client.next_message().await;
+
+    client.send(serde_json::json!({ "action": "unsubscribe", "subject": "market.btc-usd.ticker" })).await;
+    client.send(serde_json::json!({ "action": "list" })).await;
+    loop {
+        let reply = client.next_json().await;
+        if reply["type"] == "subscriptions" {
+            assert_eq!(reply["subjects"], serde_json::json!([]));
+            break;
+        }
+    }
+
+    // The simulator ticks every second; nothing follows the reply over
+    // several of its ticks
+    let late = client.json_within(Duration::from_millis(3500)).await;
+    let tickers: Vec<_> = late.iter().filter(|value| value["subject"] == "market.btc-usd.ticker").collect();
+    assert!(tickers.is_empty(), "messages arrived after unsubscribing: {:?}", tickers);
+
+    let (notice, ()) = tokio::join!(client.wait_for_shutdown(), server.stop());
+    assert!(notice.is_some(), "clients are told about the shutdown");
+}
@@ -0,0 +1,157 @@
+// Producers sending messages into the proxy over `/publish` instead of NATS
+use futures::{SinkExt, StreamExt};
+use integration_tests::{post, start_proxy, TestClient};
+use market_model::{MarketData, Validate};
+use serde_json::{json, Value};
+use simple_publisher::ProxyConnection;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::connect_async;
+use url::Url;
+
+#[tokio::test]
+async fn simple_publisher_reaches_subscribers() {
+    let server = start_proxy(&[]).await;
+    let mut client = TestClient::connect(server.proxy_addr()).await;
+    client.subscribe("market.btc-usd.trades").await;
+
+    let url = Url::parse(&format!("ws://127.0.0.1:{}/publish", server.proxy_addr().port())).unwrap();
+    let publisher = tokio::spawn(async move {
+        simple_publisher::publish_simulated_data(
+            ProxyConnection::new(url, None),
+            "market.btc-usd.{channel}".to_string(),
+            "BTC-USD".to_string(),
+            100,
+            30000.0,
+            0.3,
+        )
+        .await
+        .map_err(|e| e.to_string())
+    });
+
+    for _ in 0..5 {
+        let message = client.next_message().await;
+        assert_eq!(message.subject, "market.btc-usd.trades");
+        match message.market_data().expect("publisher sends market data") {
+            MarketData::Trade(trade) => {
+                trade.validate().expect("trade is valid");
+                assert_eq!(trade.exchange, "simple");
+            },
+            other => panic!("expected a trade, got {:?}", other),
+        }
+    }
+
+    publisher.abort();
+    let (notice, ()) = tokio::join!(client.wait_for_shutdown(), server.stop());
+    assert!(notice.is_some(), "clients are told about the shutdown");
+}
+
+fn trade(price: f64) -> Value {
+    json!({ "price": price, "size": 0.5, "side": "buy", "exchange": "test", "pair": "BTC-USD", "timestamp": 1700000000000u64 })
+}
+
+#[tokio::test]
+async fn http_publishes_single_messages_and_ndjson_batches() {
+    let server = start_proxy(&[]).await;
+    let addr = server.proxy_addr();
+    let mut client = TestClient::connect(addr).await;
+    client.subscribe("market.btc-usd.trades").await;
+    let path = "/publish/market.btc-usd.trades";
+
+    let (status, reply) = post(addr, path, "application/json", None, trade(100.0).to_string()).await;
+    assert_eq!(status, 200);
+    assert_eq!(reply["type"], "published");
+    assert_eq!(reply["count"], 1);
+    let message = client.next_message().await;
+    assert_eq!(message.subject, "market.btc-usd.trades");
+    assert_eq!(message.data, trade(100.0));
+
+    let batch = format!("{}\n{}\n", trade(101.0), trade(102.0));
+    let (status, reply) = post(addr, path, "application/x-ndjson", None, batch).await;
+    assert_eq!((status, reply["count"].clone()), (200, json!(2)));
+    assert_eq!(client.next_message().await.data, trade(101.0));
+    assert_eq!(client.next_message().await.data, trade(102.0));
+
+    // A ticker is not a trade, and one bad line fails the whole batch
+    let ticker = json!({ "pair": "BTC-USD", "price": 100.0, "timestamp": 1700000000000u64 });
+    let (status, reply) = post(addr, path, "application/json", None, ticker.to_string()).await;
+    assert_eq!((status, reply["code"].clone()), (400, json!("invalid_message")));
+    let batch = format!("{}\n{}\n", trade(103.0), trade(-1.0));
+    let (status, reply) = post(addr, path, "application/x-ndjson", None, batch).await;
+    assert_eq!((status, reply["code"].clone()), (400, json!("invalid_message")));
+    assert!(reply["message"].as_str().unwrap().starts_with("Line 2"), "{}", reply);
+    let (status, reply) = post(addr, "/publish/market.btc-usd.*", "application/json", None, trade(1.0).to_string()).await;
+    assert_eq!((status, reply["code"].clone()), (400, json!("invalid_subject")));
+
+    // A WebSocket bound to the subject carries bare payloads
+    let url = format!("ws://127.0.0.1:{}{}", addr.port(), path);
+    let (mut socket, _) = connect_async(url).await.expect("publisher connects");
+    socket.send(Message::Text(trade(104.0).to_string())).await.unwrap();
+    assert_eq!(client.next_message().await.data, trade(104.0));
+
+    drop(socket);
+    let (notice, ()) = tokio::join!(client.wait_for_shutdown(), server.stop());
+    assert!(notice.is_some(), "clients are told about the shutdown");
+}
+
+#[tokio::test]
+async fn publishers_need_a_valid_token_and_subject() {
+    let dir = std::env::temp_dir();
+    let tokens = dir.join(format!("publish-tokens-{}", std::process::id()));
+    let subscriber_tokens = dir.join(format!("subscriber-tokens-{}", std::process::id()));
+    std::fs::write(&tokens, "publisher-token market.btc-usd.>\n").unwrap();
+    std::fs::write(&subscriber_tokens, "subscriber-token\n").unwrap();
+    let server = start_proxy(&[
+        "--publish-token-file",
+        tokens.to_str().unwrap(),
+        "--token-file",
+        subscriber_tokens.to_str().unwrap(),
+    ])
+    .await;
+    let url = format!("ws://127.0.0.1:{}/publish", server.proxy_addr().port());
+
+    // Turned away without a token
+    match connect_async(url.as_str()).await {
+        Err(WsError::Http(response)) => assert_eq!(response.status(), 401),
+        other => panic!("expected a 401 response, got {:?}", other.map(|_| ())),
+    }
+
+    // Subscriber tokens do not allow publishing
+    let path = "/publish/market.btc-usd.trades";
+    let body = trade(100.0).to_string();
+    let (status, _) = post(server.proxy_addr(), path, "application/json", Some("subscriber-token"), body.clone()).await;
+    assert_eq!(status, 401);
+    let (status, _) = post(server.proxy_addr(), path, "application/json", Some("publisher-token"), body.clone()).await;
+    assert_eq!(status, 200);
+    let (status, reply) = post(server.proxy_addr(), "/publish/market.eth-usd.trades", "application/json", Some("publisher-token"), body).await;
+    assert_eq!((status, reply["code"].clone()), (403, json!("forbidden")));
+
+    let mut request = url.as_str().into_client_request().unwrap();
+    request.headers_mut().insert("Authorization", "Bearer publisher-token".parse().unwrap());
+    let (mut socket, _) = connect_async(request).await.expect("publisher connects with its token");
+
+    // Wildcards, other subjects, malformed envelopes and payloads that are
+    // not market data are each answered with an error
+    let rejected = [
+        (json!({ "subject": "market.btc-usd.*", "data": {} }), "invalid_subject"),
+        (json!({ "subject": "market.eth-usd.trades", "data": {} }), "forbidden"),
+        (json!({ "data": {} }), "invalid_message"),
+        (json!({ "subject": "market.btc-usd.trades", "data": {} }), "invalid_message"),
+    ];
+    for (message, code) in rejected {
+        socket.send(Message::Text(message.to_string())).await.unwrap();
+        let reply = loop {
+            match socket.next().await.expect("the proxy replies").expect("the frame is valid") {
+                Message::Text(text) => break serde_json::from_str::<Value>(&text).unwrap(),
+                _ => continue,
+            }
+        };
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], code, "reply to {}", message);
+    }
+
+    drop(socket);
+    server.stop().await;
+    let _ = std::fs::remove_file(tokens);
+    let _ = std::fs::remove_file(subscriber_tokens);
+}
@@ -0,0 +1,104 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::validate::{non_empty, non_negative, positive, Validate, ValidationError};
+use crate::Side;
+
+// Total size resting at one price, sent as `[price, size]`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PriceLevel(pub f64, pub f64);
+
+// New total size at one price, sent as `[side, price, size]`; size 0 means
+// the level is gone
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BookChange(pub Side, pub f64, pub f64);
+
+// Aggregated book messages, tagged by `type`. Sequence numbers go up by one
+// with every update; a snapshot holds the book as of its sequence and
+// updates with higher sequences apply on top of it in order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Level2 {
+    #[serde(rename = "snapshot")]
+    Snapshot(BookSnapshot),
+    #[serde(rename = "l2update")]
+    Update(BookUpdate),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookSnapshot {
+    pub pair: String,
+    pub sequence: u64,
+    // Best first on each side
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+    // Unix milliseconds
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookUpdate {
+    pub pair: String,
+    pub sequence: u64,
+    pub changes: Vec<BookChange>,
+    // Unix milliseconds
+    pub timestamp: u64,
+}
+
+impl Level2 {
+    pub fn pair(&self) -> &str {
+        match self {
+            Level2::Snapshot(snapshot) => &snapshot.pair,
+            Level2::Update(update) => &update.pair,
+        }
+    }
+
+    pub fn sequence(&self) -> u64 {
+        match self {
+            Level2::Snapshot(snapshot) => snapshot.sequence,
+            Level2::Update(update) => update.sequence,
+        }
+    }
+}
+
+impl Validate for Level2 {
+    fn validate(&self) -> Result<(), ValidationError> {
+        match self {
+            Level2::Snapshot(snapshot) => snapshot.validate(),
+            Level2::Update(update) => update.validate(),
+        }
+    }
+}
+
+impl Validate for BookSnapshot {
+    fn validate(&self) -> Result<(), ValidationError> {
+        non_empty("pair", &self.pair)?;
+        for PriceLevel(price, size) in self.bids.iter().chain(&self.asks) {
+            positive("level price", *price)?;
+            positive("level size", *size)?;
+        }
+        if self.bids.windows(2).any(|pair| pair[0].0 <= pair[1].0) {
+            return Err(ValidationError::new("bids must be in descending price order"));
+        }
+        if self.asks.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(ValidationError::new("asks must be in ascending price order"));
+        }
+        match (self.bids.first(), self.asks.first()) {
+            (Some(bid), Some(ask)) if bid.0 >= ask.0 => Err(ValidationError::new("book is crossed")),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Validate for BookUpdate {
+    fn validate(&self) -> Result<(), ValidationError> {
+        non_empty("pair", &self.pair)?;
+        for BookChange(_, price, size) in &self.changes {
+            positive("change price", *price)?;
+            non_negative("change size", *size)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use alloc::string::String;
+
+use serde::{Deserialize, Serialize};
+
+use crate::validate::{non_empty, non_negative, positive, Validate, ValidationError};
+use crate::Trade;
+
+// Open, high, low and close prices and traded volume over one interval
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candle {
+    pub pair: String,
+    // Start of the interval, Unix milliseconds
+    pub start: u64,
+    pub interval_ms: u64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    // Total size traded, in the base currency
+    pub volume: f64,
+}
+
+impl Candle {
+    // A candle holding just `trade`, for the interval starting at `start`
+    pub fn open(trade: &Trade, start: u64, interval_ms: u64) -> Self {
+        Candle {
+            pair: trade.pair.clone(),
+            start,
+            interval_ms,
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: trade.size,
+        }
+    }
+
+    pub fn add(&mut self, trade: &Trade) {
+        self.high = self.high.max(trade.price);
+        self.low = self.low.min(trade.price);
+        self.close = trade.price;
+        self.volume += trade.size;
+    }
+
+    // Whether `timestamp` falls in this candle's interval
+    pub fn covers(&self, timestamp: u64) -> bool {
+        timestamp >= self.start && timestamp - self.start < self.interval_ms
+    }
+}
+
+impl Validate for Candle {
+    fn validate(&self) -> Result<(), ValidationError> {
+        non_empty("pair", &self.pair)?;
+        if self.interval_ms == 0 {
+            return Err(ValidationError::new("interval_ms must be positive"));
+        }
+        positive("open", self.open)?;
+        positive("high", self.high)?;
+        positive("low", self.low)?;
+        positive("close", self.close)?;
+        non_negative("volume", self.volume)?;
+        if self.low > self.open.min(self.close) || self.high < self.open.max(self.close) {
+            return Err(ValidationError::new("open and close must lie between low and high"));
+        }
+        Ok(())
+    }
+}
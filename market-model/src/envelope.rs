@@ -0,0 +1,69 @@
+use alloc::format;
+use alloc::string::String;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::validate::{non_empty, Validate, ValidationError};
+use crate::MarketData;
+
+// Version of the message layout defined by this crate. Bumped whenever a
+// change would break existing readers; additions of optional fields keep it.
+pub const SCHEMA_VERSION: u32 = 1;
+
+// A message as delivered to subscribers: the payload and the subject it
+// was published on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub subject: String,
+    pub data: Value,
+    // Unix milliseconds at which the message was published
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    // Messages from before versioning count as version 1
+    #[serde(default = "first_version")]
+    pub version: u32,
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+impl Envelope {
+    pub fn new(subject: impl Into<String>, data: Value, timestamp: Option<u64>) -> Self {
+        Envelope {
+            subject: subject.into(),
+            data,
+            timestamp,
+            version: SCHEMA_VERSION,
+        }
+    }
+
+    pub fn wrap(subject: impl Into<String>, data: &MarketData, timestamp: Option<u64>) -> Self {
+        Envelope::new(subject, data.to_value(), timestamp)
+    }
+
+    // The payload as market data, for subjects that carry it
+    pub fn market_data(&self) -> Result<MarketData, serde_json::Error> {
+        MarketData::deserialize(&self.data)
+    }
+
+    // The payload as any type, e.g. `Trade` on a trades subject
+    pub fn payload<'a, T: Deserialize<'a>>(&'a self) -> Result<T, serde_json::Error> {
+        T::deserialize(&self.data)
+    }
+}
+
+impl Validate for Envelope {
+    fn validate(&self) -> Result<(), ValidationError> {
+        non_empty("subject", &self.subject)?;
+        if self.version == 0 || self.version > SCHEMA_VERSION {
+            return Err(ValidationError::new(format!(
+                "version {} is not supported, expected 1 to {}",
+                self.version, SCHEMA_VERSION
+            )));
+        }
+        Ok(())
+    }
+}
+
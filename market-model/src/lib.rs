@@ -0,0 +1,108 @@
+// Market data as it travels between the publishers, the proxy and the web
+// client: trades, tickers, candles and level 2 book messages, and the
+// envelope naming the subject each one was published on. Builds without std
+// (disable default features) so the same types compile for wasm32 and
+// embedded consumers. The `wire` feature adds MessagePack and CBOR
+// encodings and needs std.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod book;
+pub mod candle;
+pub mod envelope;
+pub mod ticker;
+pub mod trade;
+mod validate;
+#[cfg(feature = "wire")]
+pub mod wire;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub use book::{BookChange, BookSnapshot, BookUpdate, Level2, PriceLevel};
+pub use candle::Candle;
+pub use envelope::{Envelope, SCHEMA_VERSION};
+pub use ticker::Ticker;
+pub use trade::Trade;
+pub use validate::{Validate, ValidationError};
+#[cfg(feature = "wire")]
+pub use wire::{Encoding, WireError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn opposite(self) -> Side {
+        match self {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        }
+    }
+}
+
+// Any payload published on a market data subject. Serialized without a tag;
+// the shapes are told apart by their fields, level 2 messages by `type`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MarketData {
+    Level2(Level2),
+    Trade(Trade),
+    Candle(Candle),
+    Ticker(Ticker),
+}
+
+impl MarketData {
+    pub fn pair(&self) -> &str {
+        match self {
+            MarketData::Level2(level2) => level2.pair(),
+            MarketData::Trade(trade) => &trade.pair,
+            MarketData::Candle(candle) => &candle.pair,
+            MarketData::Ticker(ticker) => &ticker.pair,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        // Plain structs with string keys always serialize
+        serde_json::to_value(self).expect("market data serializes to JSON")
+    }
+}
+
+impl Validate for MarketData {
+    fn validate(&self) -> Result<(), ValidationError> {
+        match self {
+            MarketData::Level2(level2) => level2.validate(),
+            MarketData::Trade(trade) => trade.validate(),
+            MarketData::Candle(candle) => candle.validate(),
+            MarketData::Ticker(ticker) => ticker.validate(),
+        }
+    }
+}
+
+impl From<Trade> for MarketData {
+    fn from(trade: Trade) -> Self {
+        MarketData::Trade(trade)
+    }
+}
+
+impl From<Ticker> for MarketData {
+    fn from(ticker: Ticker) -> Self {
+        MarketData::Ticker(ticker)
+    }
+}
+
+impl From<Candle> for MarketData {
+    fn from(candle: Candle) -> Self {
+        MarketData::Candle(candle)
+    }
+}
+
+impl From<Level2> for MarketData {
+    fn from(level2: Level2) -> Self {
+        MarketData::Level2(level2)
+    }
+}
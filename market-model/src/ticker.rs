@@ -0,0 +1,61 @@
+use alloc::string::String;
+
+use serde::{Deserialize, Serialize};
+
+use crate::validate::{non_empty, positive, Validate, ValidationError};
+
+// Top of the book and the last traded price. Each side is missing while
+// that side of the book is empty, and the price until the first trade.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ticker {
+    pub pair: String,
+    // Sequence of the book update this ticker reflects, if the source has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub best_bid: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub best_bid_size: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub best_ask: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub best_ask_size: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price: Option<f64>,
+    // Unix milliseconds
+    pub timestamp: u64,
+}
+
+impl Ticker {
+    // Halfway between the best bid and ask, when both sides have orders
+    pub fn mid(&self) -> Option<f64> {
+        Some((self.best_bid? + self.best_ask?) / 2.0)
+    }
+}
+
+impl Validate for Ticker {
+    fn validate(&self) -> Result<(), ValidationError> {
+        non_empty("pair", &self.pair)?;
+        let fields = [
+            ("best_bid", self.best_bid),
+            ("best_bid_size", self.best_bid_size),
+            ("best_ask", self.best_ask),
+            ("best_ask_size", self.best_ask_size),
+            ("price", self.price),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                positive(field, value)?;
+            }
+        }
+        if self.best_bid.is_some() != self.best_bid_size.is_some()
+            || self.best_ask.is_some() != self.best_ask_size.is_some()
+        {
+            return Err(ValidationError::new("best bid and ask need both a price and a size"));
+        }
+        match (self.best_bid, self.best_ask) {
+            (Some(bid), Some(ask)) if bid >= ask => Err(ValidationError::new("best_bid must be below best_ask")),
+            _ => Ok(()),
+        }
+    }
+}
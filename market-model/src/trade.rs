@@ -0,0 +1,32 @@
+use alloc::string::String;
+
+use serde::{Deserialize, Serialize};
+
+use crate::validate::{non_empty, positive, Validate, ValidationError};
+use crate::Side;
+
+// One execution on an exchange
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trade {
+    pub price: f64,
+    pub size: f64,
+    // Side of the order that took liquidity
+    pub side: Side,
+    pub exchange: String,
+    // Trading pair, e.g. `BTC-USD`
+    pub pair: String,
+    // Unique per pair and exchange, when the exchange numbers its trades
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trade_id: Option<u64>,
+    // Unix milliseconds
+    pub timestamp: u64,
+}
+
+impl Validate for Trade {
+    fn validate(&self) -> Result<(), ValidationError> {
+        positive("price", self.price)?;
+        positive("size", self.size)?;
+        non_empty("exchange", &self.exchange)?;
+        non_empty("pair", &self.pair)
+    }
+}
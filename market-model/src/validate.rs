@@ -0,0 +1,53 @@
+use alloc::string::{String, ToString};
+use core::fmt;
+
+// Checks a message makes sense beyond having the right shape
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationError>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError(String);
+
+impl ValidationError {
+    pub fn new(message: impl Into<String>) -> Self {
+        ValidationError(message.into())
+    }
+
+    pub fn message(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ValidationError {}
+
+pub(crate) fn positive(field: &str, value: f64) -> Result<(), ValidationError> {
+    if value.is_finite() && value > 0.0 {
+        Ok(())
+    } else {
+        Err(ValidationError(field.to_string() + " must be a positive number"))
+    }
+}
+
+pub(crate) fn non_negative(field: &str, value: f64) -> Result<(), ValidationError> {
+    if value.is_finite() && value >= 0.0 {
+        Ok(())
+    } else {
+        Err(ValidationError(field.to_string() + " must be a non-negative number"))
+    }
+}
+
+pub(crate) fn non_empty(field: &str, value: &str) -> Result<(), ValidationError> {
+    if value.is_empty() {
+        Err(ValidationError(field.to_string() + " may not be empty"))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+// Encodings the proxy can send messages to subscribers in. JSON goes out as
+// text frames; MessagePack and CBOR as binary frames, which are smaller and
+// quicker to decode. Snapshots, batches and the end of a replay come in the
+// subscription's encoding too; other control replies are always JSON.
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+impl Encoding {
+    pub fn is_binary(self) -> bool {
+        self != Encoding::Json
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, WireError> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(WireError::new),
+            // Field names are kept so readers need not know the field order
+            Encoding::Msgpack => rmp_serde::to_vec_named(value).map_err(WireError::new),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(WireError::new)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, WireError> {
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(WireError::new),
+            Encoding::Msgpack => rmp_serde::from_slice(bytes).map_err(WireError::new),
+            Encoding::Cbor => ciborium::from_reader(bytes).map_err(WireError::new),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireError(String);
+
+impl WireError {
+    fn new(error: impl fmt::Display) -> Self {
+        WireError(error.to_string())
+    }
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for WireError {}
@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+pub use market_model::Side;
+
+// Prices are kept in ticks and sizes in lots, so matching never suffers
+// from floating point rounding
+pub type Ticks = i64;
+pub type Lots = u64;
+
+// One execution between an incoming order and a resting one, at the
+// resting order's price
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fill {
+    pub trade_id: u64,
+    pub maker_order_id: u64,
+    // Side of the incoming order
+    pub taker_side: Side,
+    pub price: Ticks,
+    pub size: Lots,
+}
+
+// New total size at a price level; zero means the level is gone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelChange {
+    pub side: Side,
+    pub price: Ticks,
+    pub size: Lots,
+}
+
+#[derive(Debug, Default)]
+struct Level {
+    // Resting orders oldest first, as (order id, remaining size)
+    orders: VecDeque<(u64, Lots)>,
+    total: Lots,
+}
+
+// A price-time priority limit order book
+#[derive(Debug, Default)]
+pub struct OrderBook {
+    bids: BTreeMap<Ticks, Level>,
+    asks: BTreeMap<Ticks, Level>,
+    // Side and price of every resting order
+    orders: HashMap<u64, (Side, Ticks)>,
+    // Levels touched since the last `take_changes`
+    changed: BTreeSet<(Side, Ticks)>,
+    next_trade_id: u64,
+    sequence: u64,
+}
+
+impl OrderBook {
+    pub fn new() -> Self {
+        OrderBook::default()
+    }
+
+    pub fn best_bid(&self) -> Option<(Ticks, Lots)> {
+        self.bids.iter().next_back().map(|(price, level)| (*price, level.total))
+    }
+
+    pub fn best_ask(&self) -> Option<(Ticks, Lots)> {
+        self.asks.iter().next().map(|(price, level)| (*price, level.total))
+    }
+
+    pub fn order_count(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn contains(&self, order_id: u64) -> bool {
+        self.orders.contains_key(&order_id)
+    }
+
+    // Number of change batches taken so far; a snapshot taken right after
+    // `take_changes` is current as of this sequence
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    // Up to `depth` levels on one side, best first
+    pub fn levels(&self, side: Side, depth: usize) -> Vec<(Ticks, Lots)> {
+        let level = |(price, level): (&Ticks, &Level)| (*price, level.total);
+        match side {
+            Side::Buy => self.bids.iter().rev().take(depth).map(level).collect(),
+            Side::Sell => self.asks.iter().take(depth).map(level).collect(),
+        }
+    }
+
+    // Match an incoming order against the other side, best price first and
+    // oldest order first within a price. A limit order stops at its limit
+    // and rests what is left; a market order (no limit) drops the rest.
+    pub fn submit(&mut self, order_id: u64, side: Side, limit: Option<Ticks>, size: Lots) -> Vec<Fill> {
+        let mut fills = Vec::new();
+        let mut remaining = size;
+        let opposite = match side {
+            Side::Buy => &mut self.asks,
+            Side::Sell => &mut self.bids,
+        };
+
+        while remaining > 0 {
+            let best = match side {
+                Side::Buy => opposite.first_entry(),
+                Side::Sell => opposite.last_entry(),
+            };
+            let Some(mut best) = best else {
+                break;
+            };
+            let price = *best.key();
+            let crosses = match (side, limit) {
+                (_, None) => true,
+                (Side::Buy, Some(limit)) => price <= limit,
+                (Side::Sell, Some(limit)) => price >= limit,
+            };
+            if !crosses {
+                break;
+            }
+
+            let level = best.get_mut();
+            while remaining > 0 {
+                let Some(resting) = level.orders.front_mut() else {
+                    break;
+                };
+                let traded = resting.1.min(remaining);
+                resting.1 -= traded;
+                level.total -= traded;
+                remaining -= traded;
+                self.next_trade_id += 1;
+                fills.push(Fill {
+                    trade_id: self.next_trade_id,
+                    maker_order_id: resting.0,
+                    taker_side: side,
+                    price,
+                    size: traded,
+                });
+                if resting.1 == 0 {
+                    let (filled, _) = level.orders.pop_front().unwrap();
+                    self.orders.remove(&filled);
+                }
+            }
+            self.changed.insert((side.opposite(), price));
+            if level.orders.is_empty() {
+                best.remove();
+            }
+        }
+
+        if let (Some(limit), true) = (limit, remaining > 0) {
+            let book = match side {
+                Side::Buy => &mut self.bids,
+                Side::Sell => &mut self.asks,
+            };
+            let level = book.entry(limit).or_default();
+            level.orders.push_back((order_id, remaining));
+            level.total += remaining;
+            self.orders.insert(order_id, (side, limit));
+            self.changed.insert((side, limit));
+        }
+        fills
+    }
+
+    // Remove a resting order; false if it has already been filled or cancelled
+    pub fn cancel(&mut self, order_id: u64) -> bool {
+        let Some((side, price)) = self.orders.remove(&order_id) else {
+            return false;
+        };
+        let book = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        if let Some(level) = book.get_mut(&price) {
+            if let Some(index) = level.orders.iter().position(|(id, _)| *id == order_id) {
+                let (_, size) = level.orders.remove(index).unwrap();
+                level.total -= size;
+            }
+            if level.orders.is_empty() {
+                book.remove(&price);
+            }
+        }
+        self.changed.insert((side, price));
+        true
+    }
+
+    // Current size of every level touched since the last call. Each
+    // non-empty batch advances the sequence by one.
+    pub fn take_changes(&mut self) -> Vec<LevelChange> {
+        if self.changed.is_empty() {
+            return Vec::new();
+        }
+        self.sequence += 1;
+        let changed = std::mem::take(&mut self.changed);
+        changed
+            .into_iter()
+            .map(|(side, price)| {
+                let book = match side {
+                    Side::Buy => &self.bids,
+                    Side::Sell => &self.asks,
+                };
+                let size = book.get(&price).map_or(0, |level| level.total);
+                LevelChange { side, price, size }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sizes per price level on each side, as updates would leave them
+    type Levels = (BTreeMap<Ticks, Lots>, BTreeMap<Ticks, Lots>);
+
+    fn levels(book: &OrderBook) -> Levels {
+        (
+            book.levels(Side::Buy, usize::MAX).into_iter().collect(),
+            book.levels(Side::Sell, usize::MAX).into_iter().collect(),
+        )
+    }
+
+    fn apply(levels: &mut Levels, changes: &[LevelChange]) {
+        for change in changes {
+            let side = match change.side {
+                Side::Buy => &mut levels.0,
+                Side::Sell => &mut levels.1,
+            };
+            if change.size == 0 {
+                side.remove(&change.price);
+            } else {
+                side.insert(change.price, change.size);
+            }
+        }
+    }
+
+    #[test]
+    fn sweeps_levels_and_rests_the_remainder() {
+        let mut book = OrderBook::new();
+        book.submit(1, Side::Sell, Some(101), 5);
+        book.submit(2, Side::Sell, Some(102), 5);
+        book.submit(3, Side::Sell, Some(104), 5);
+
+        // Fills at each resting price, best first, up to the limit
+        let fills = book.submit(4, Side::Buy, Some(103), 12);
+        let fills: Vec<_> = fills.iter().map(|f| (f.maker_order_id, f.price, f.size, f.taker_side)).collect();
+        assert_eq!(fills, vec![(1, 101, 5, Side::Buy), (2, 102, 5, Side::Buy)]);
+        assert_eq!(book.best_bid(), Some((103, 2)));
+        assert_eq!(book.best_ask(), Some((104, 5)));
+        assert!(book.contains(4) && !book.contains(1) && !book.contains(2));
+
+        // A market order can take part of a level, and drops what it cannot fill
+        let fills = book.submit(5, Side::Sell, None, 1);
+        assert_eq!((fills[0].maker_order_id, fills[0].price, fills[0].size), (4, 103, 1));
+        assert_eq!(book.best_bid(), Some((103, 1)));
+        let fills = book.submit(6, Side::Buy, None, 100);
+        assert_eq!(fills.iter().map(|f| f.size).sum::<Lots>(), 5);
+        assert_eq!(book.best_ask(), None);
+        assert!(!book.contains(6), "market orders never rest");
+
+        // Trade ids run on across orders
+        let ids: Vec<u64> = fills.iter().map(|f| f.trade_id).collect();
+        assert_eq!(ids, vec![4]);
+    }
+
+    #[test]
+    fn fills_oldest_order_first_within_a_level() {
+        let mut book = OrderBook::new();
+        book.submit(1, Side::Buy, Some(100), 3);
+        book.submit(2, Side::Buy, Some(100), 4);
+        book.submit(3, Side::Buy, Some(100), 5);
+        assert_eq!(book.best_bid(), Some((100, 12)));
+
+        let fills = book.submit(4, Side::Sell, Some(100), 5);
+        let makers: Vec<_> = fills.iter().map(|f| (f.maker_order_id, f.size)).collect();
+        assert_eq!(makers, vec![(1, 3), (2, 2)]);
+
+        // The partly filled order keeps its place ahead of later ones
+        book.submit(5, Side::Buy, Some(100), 1);
+        let fills = book.submit(6, Side::Sell, None, 3);
+        let makers: Vec<_> = fills.iter().map(|f| (f.maker_order_id, f.size)).collect();
+        assert_eq!(makers, vec![(2, 2), (3, 1)]);
+        assert_eq!(book.best_bid(), Some((100, 5)));
+    }
+
+    #[test]
+    fn cancels_remove_resting_orders_only() {
+        let mut book = OrderBook::new();
+        book.submit(1, Side::Sell, Some(105), 2);
+        book.submit(2, Side::Sell, Some(105), 3);
+        book.submit(3, Side::Sell, Some(106), 4);
+        book.take_changes();
+
+        assert!(book.cancel(1));
+        assert!(!book.cancel(1), "an order is cancelled once");
+        assert_eq!(book.best_ask(), Some((105, 3)));
+        assert!(book.cancel(2));
+        assert_eq!(book.best_ask(), Some((106, 4)));
+        assert_eq!(book.order_count(), 1);
+        assert_eq!(book.take_changes(), vec![LevelChange { side: Side::Sell, price: 105, size: 0 }]);
+
+        // Filled orders cannot be cancelled, and cancelled ones are not matched
+        book.submit(4, Side::Buy, None, 4);
+        assert!(!book.cancel(3));
+        assert!(book.submit(5, Side::Buy, None, 1).is_empty());
+        assert!(!book.cancel(99));
+    }
+
+    #[test]
+    fn updates_carry_on_from_the_snapshot_sequence() {
+        let mut book = OrderBook::new();
+        assert!(book.take_changes().is_empty());
+        assert_eq!(book.sequence(), 0, "an empty batch does not advance the sequence");
+
+        book.submit(1, Side::Buy, Some(99), 5);
+        book.submit(2, Side::Sell, Some(101), 5);
+        book.take_changes();
+        let mut snapshot = levels(&book);
+        let mut sequence = book.sequence();
+
+        let steps: [fn(&mut OrderBook); 3] = [
+            |b| {
+                b.submit(3, Side::Buy, Some(100), 2);
+                b.submit(4, Side::Buy, Some(99), 1);
+            },
+            |b| {
+                b.submit(5, Side::Sell, None, 4);
+            },
+            |b| {
+                b.cancel(2);
+                b.submit(6, Side::Sell, Some(99), 10);
+            },
+        ];
+        for step in steps {
+            step(&mut book);
+            let changes = book.take_changes();
+            assert_eq!(book.sequence(), sequence + 1, "each batch follows the one before");
+            sequence = book.sequence();
+            apply(&mut snapshot, &changes);
+            assert_eq!(snapshot, levels(&book), "snapshot plus updates is the book");
+        }
+    }
+}
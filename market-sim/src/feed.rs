@@ -0,0 +1,253 @@
+use std::time::Duration;
+
+use market_model::{BookChange, BookSnapshot, BookUpdate, Level2, MarketData, PriceLevel, Ticker, Trade};
+
+use crate::book::{Lots, Side, Ticks};
+use crate::market::Market;
+
+// Kinds of message a feed publishes, each on its own subject
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    // One message per trade
+    Trades,
+    // `snapshot` and `l2update` messages for the aggregated book
+    Level2,
+    // Best bid and ask, whenever they or the last price change
+    Ticker,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 3] = [Channel::Trades, Channel::Level2, Channel::Ticker];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Trades => "trades",
+            Channel::Level2 => "level2",
+            Channel::Ticker => "ticker",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedMessage {
+    pub channel: Channel,
+    pub data: MarketData,
+}
+
+// Publishes one market as trade, level 2 and ticker messages. All three
+// are derived from the same book after each `advance`, so they always agree:
+// an `l2update` with sequence N applies on top of the snapshot or update
+// before it with sequence N - 1, and a snapshot holds the book as of its
+// sequence.
+pub struct Feed {
+    market: Market,
+    pair: String,
+    exchange: String,
+    // Levels per side in snapshots
+    depth: usize,
+    snapshot_interval: Duration,
+    // Time since the last snapshot; None until the first
+    since_snapshot: Option<Duration>,
+    last_top: Option<TopOfBook>,
+}
+
+// Best bid and ask as (price, size)
+type TopOfBook = (Option<(Ticks, Lots)>, Option<(Ticks, Lots)>);
+
+impl Feed {
+    pub fn new(market: Market, pair: impl Into<String>, exchange: impl Into<String>) -> Self {
+        Feed {
+            market,
+            pair: pair.into(),
+            exchange: exchange.into(),
+            depth: 50,
+            snapshot_interval: Duration::from_secs(10),
+            since_snapshot: None,
+            last_top: None,
+        }
+    }
+
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    // How often to repeat the full book for subscribers joining late
+    pub fn with_snapshot_interval(mut self, interval: Duration) -> Self {
+        self.snapshot_interval = interval;
+        self
+    }
+
+    pub fn pair(&self) -> &str {
+        &self.pair
+    }
+
+    pub fn market(&self) -> &Market {
+        &self.market
+    }
+
+    pub fn market_mut(&mut self) -> &mut Market {
+        &mut self.market
+    }
+
+    // Run the market for `elapsed` and describe what happened, stamping
+    // every message with `timestamp` (Unix milliseconds)
+    pub fn advance(&mut self, elapsed: Duration, timestamp: u64) -> Vec<FeedMessage> {
+        let mut messages = Vec::new();
+        let trades = self.market.advance(elapsed);
+        let traded = !trades.is_empty();
+        for trade in trades {
+            messages.push(FeedMessage {
+                channel: Channel::Trades,
+                data: MarketData::Trade(Trade {
+                    price: trade.price,
+                    size: trade.size,
+                    side: trade.side,
+                    exchange: self.exchange.clone(),
+                    pair: self.pair.clone(),
+                    trade_id: Some(trade.trade_id),
+                    timestamp,
+                }),
+            });
+        }
+
+        let changes = self.market.book_mut().take_changes();
+        if !changes.is_empty() && self.since_snapshot.is_some() {
+            let changes = changes
+                .iter()
+                .map(|change| BookChange(change.side, self.market.to_price(change.price), self.market.to_size(change.size)))
+                .collect();
+            messages.push(FeedMessage {
+                channel: Channel::Level2,
+                data: MarketData::Level2(Level2::Update(BookUpdate {
+                    pair: self.pair.clone(),
+                    sequence: self.market.book().sequence(),
+                    changes,
+                    timestamp,
+                })),
+            });
+        }
+
+        let since = self.since_snapshot.map_or(self.snapshot_interval, |since| since + elapsed);
+        if since >= self.snapshot_interval {
+            messages.push(FeedMessage {
+                channel: Channel::Level2,
+                data: MarketData::Level2(Level2::Snapshot(self.snapshot(timestamp))),
+            });
+            self.since_snapshot = Some(Duration::ZERO);
+        } else {
+            self.since_snapshot = Some(since);
+        }
+
+        let book = self.market.book();
+        let top = (book.best_bid(), book.best_ask());
+        if traded || self.last_top != Some(top) {
+            messages.push(FeedMessage {
+                channel: Channel::Ticker,
+                data: MarketData::Ticker(self.ticker(timestamp)),
+            });
+            self.last_top = Some(top);
+        }
+        messages
+    }
+
+    // The book's top `depth` levels per side as of its current sequence
+    pub fn snapshot(&self, timestamp: u64) -> BookSnapshot {
+        let side = |side: Side| -> Vec<PriceLevel> {
+            self.market
+                .book()
+                .levels(side, self.depth)
+                .into_iter()
+                .map(|(price, size)| PriceLevel(self.market.to_price(price), self.market.to_size(size)))
+                .collect()
+        };
+        BookSnapshot {
+            pair: self.pair.clone(),
+            sequence: self.market.book().sequence(),
+            bids: side(Side::Buy),
+            asks: side(Side::Sell),
+            timestamp,
+        }
+    }
+
+    pub fn ticker(&self, timestamp: u64) -> Ticker {
+        let market = &self.market;
+        let book = market.book();
+        let price = |level: Option<(Ticks, Lots)>| level.map(|(price, _)| market.to_price(price));
+        let size = |level: Option<(Ticks, Lots)>| level.map(|(_, size)| market.to_size(size));
+        Ticker {
+            pair: self.pair.clone(),
+            sequence: Some(book.sequence()),
+            best_bid: price(book.best_bid()),
+            best_bid_size: size(book.best_bid()),
+            best_ask: price(book.best_ask()),
+            best_ask_size: size(book.best_ask()),
+            price: market.last_price(),
+            timestamp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::market::MarketConfig;
+
+    // Size per (is bid, price key) level
+    type Levels = BTreeMap<(bool, i64), f64>;
+
+    // Prices in the book as whole ticks of 0.01 so levels compare exactly
+    fn key(price: f64) -> i64 {
+        (price * 100.0).round() as i64
+    }
+
+    #[test]
+    fn updates_apply_on_top_of_the_last_snapshot() {
+        let market = Market::seeded(MarketConfig::for_price(30000.0, 0.5), 30000.0, 7);
+        let mut feed = Feed::new(market, "BTC-USD", "test")
+            .with_depth(usize::MAX)
+            .with_snapshot_interval(Duration::from_secs(2));
+
+        let mut book: Option<(u64, Levels)> = None;
+        let (mut updates, mut snapshots) = (0, 0);
+        for step in 0..200 {
+            feed.market_mut().walk(0.1);
+            for message in feed.advance(Duration::from_millis(100), step) {
+                match message.data {
+                    MarketData::Level2(Level2::Snapshot(snapshot)) => {
+                        let levels = snapshot
+                            .bids
+                            .iter()
+                            .map(|level| ((true, key(level.0)), level.1))
+                            .chain(snapshot.asks.iter().map(|level| ((false, key(level.0)), level.1)))
+                            .collect();
+                        if let Some((sequence, rebuilt)) = &book {
+                            assert_eq!(*sequence, snapshot.sequence, "a snapshot follows the last update");
+                            assert_eq!(rebuilt, &levels, "updates rebuild the next snapshot");
+                        }
+                        book = Some((snapshot.sequence, levels));
+                        snapshots += 1;
+                    }
+                    MarketData::Level2(Level2::Update(update)) => {
+                        let (sequence, levels) = book.as_mut().expect("the first book message is a snapshot");
+                        assert_eq!(update.sequence, *sequence + 1, "no update is skipped");
+                        *sequence = update.sequence;
+                        for BookChange(side, price, size) in update.changes {
+                            let level = (side == Side::Buy, key(price));
+                            if size == 0.0 {
+                                levels.remove(&level);
+                            } else {
+                                levels.insert(level, size);
+                            }
+                        }
+                        updates += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        assert!(snapshots > 5 && updates > 50, "{} snapshots, {} updates", snapshots, updates);
+    }
+}
@@ -0,0 +1,13 @@
+// Simulated exchange shared by the proxy's simulator and the publishers: a
+// limit order book with price-time priority matching, fed by Poisson order
+// flow around a fair value the caller moves, and published as consistent
+// trade, level 2 book and ticker messages
+pub mod book;
+pub mod feed;
+pub mod market;
+pub mod publish;
+
+pub use book::{OrderBook, Side};
+pub use feed::{Channel, Feed, FeedMessage};
+pub use market::{Market, MarketConfig, Trade};
+pub use publish::{channel_subject, check_subject, envelopes};
@@ -0,0 +1,289 @@
+use std::f64::consts::TAU;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::book::{Fill, Lots, OrderBook, Side, Ticks};
+
+// Most order events processed by one `advance`, so a long pause in the
+// caller cannot stall it for good
+const MAX_EVENTS_PER_ADVANCE: usize = 100_000;
+
+// How orders arrive in a simulated market
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarketConfig {
+    pub tick_size: f64,
+    pub lot_size: f64,
+    // Limit orders arriving per second
+    pub order_rate: f64,
+    // Market orders arriving per second
+    pub market_order_rate: f64,
+    // Chance per second of each resting order being cancelled
+    pub cancel_rate: f64,
+    // Mean distance of new limit orders from the fair value, in ticks
+    pub mean_depth: f64,
+    // Mean order size in the base currency
+    pub mean_size: f64,
+}
+
+impl MarketConfig {
+    // Settings for an instrument trading around `price` whose fair value
+    // moves by `volatility` percent per second: ticks of about 0.001% of the
+    // price, orders worth about 5000 in the quote currency, and limit orders
+    // spread over roughly a second's worth of fair value movement so the
+    // book keeps up with it
+    pub fn for_price(price: f64, volatility: f64) -> Self {
+        let tick_size = power_of_ten_below(price * 1e-5);
+        let mean_size = 5000.0 / price;
+        let movement = price * volatility / 100.0 / tick_size;
+        MarketConfig {
+            tick_size,
+            lot_size: power_of_ten_below(mean_size / 1000.0),
+            order_rate: 50.0,
+            market_order_rate: 5.0,
+            cancel_rate: 0.5,
+            mean_depth: movement.max(5.0),
+            mean_size,
+        }
+    }
+}
+
+fn power_of_ten_below(value: f64) -> f64 {
+    10f64.powi(value.log10().floor() as i32)
+}
+
+// One trade in market units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trade {
+    pub trade_id: u64,
+    pub price: f64,
+    pub size: f64,
+    // Side of the order that took liquidity
+    pub side: Side,
+}
+
+// An order book fed by Poisson arrivals of limit orders, market orders and
+// cancellations. Limit orders are placed around a fair value set by the
+// caller and market orders lean towards it, so traded prices follow the
+// fair value while spread, depth and trade sizes come out of the order flow.
+pub struct Market {
+    config: MarketConfig,
+    book: OrderBook,
+    fair_value: f64,
+    rng: StdRng,
+    next_order_id: u64,
+    // Resting order ids to pick cancellations from; filled ones are only
+    // pruned now and then
+    resting: Vec<u64>,
+    // Seconds until the next order event
+    until_next: f64,
+    last_price: Option<f64>,
+    // Decimal places of the tick and lot sizes, for rounding reported values
+    price_decimals: i32,
+    size_decimals: i32,
+}
+
+impl Market {
+    pub fn new(config: MarketConfig, fair_value: f64) -> Self {
+        Market::with_rng(config, fair_value, StdRng::from_entropy())
+    }
+
+    // A market that replays the same order flow for the same seed
+    pub fn seeded(config: MarketConfig, fair_value: f64, seed: u64) -> Self {
+        Market::with_rng(config, fair_value, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(config: MarketConfig, fair_value: f64, rng: StdRng) -> Self {
+        let mut market = Market {
+            price_decimals: decimals(config.tick_size),
+            size_decimals: decimals(config.lot_size),
+            config,
+            book: OrderBook::new(),
+            fair_value,
+            rng,
+            next_order_id: 0,
+            resting: Vec::new(),
+            until_next: 0.0,
+            last_price: None,
+        };
+
+        // Start from the depth the order flow settles at, with nothing crossed
+        let resting = (market.config.order_rate / market.config.cancel_rate.max(1e-3)).min(10_000.0) as usize;
+        for _ in 0..resting {
+            let side = if market.rng.gen_bool(0.5) { Side::Buy } else { Side::Sell };
+            let distance = 1 + market.exponential(market.config.mean_depth) as Ticks;
+            market.place_limit(side, distance);
+        }
+        market.book.take_changes();
+        market.until_next = market.exponential(1.0 / market.event_rate());
+        market
+    }
+
+    pub fn config(&self) -> &MarketConfig {
+        &self.config
+    }
+
+    pub fn book(&self) -> &OrderBook {
+        &self.book
+    }
+
+    pub fn book_mut(&mut self) -> &mut OrderBook {
+        &mut self.book
+    }
+
+    pub fn fair_value(&self) -> f64 {
+        self.fair_value
+    }
+
+    pub fn set_fair_value(&mut self, fair_value: f64) {
+        self.fair_value = fair_value;
+    }
+
+    // Move the fair value by a normally distributed step with a standard
+    // deviation of `volatility` percent
+    pub fn walk(&mut self, volatility: f64) {
+        let shock = standard_normal(&mut self.rng);
+        self.fair_value *= (volatility / 100.0 * shock).exp();
+    }
+
+    pub fn last_price(&self) -> Option<f64> {
+        self.last_price
+    }
+
+    // Run the order flow for `elapsed` and return the trades it produced
+    pub fn advance(&mut self, elapsed: Duration) -> Vec<Trade> {
+        let mut trades = Vec::new();
+        let mut left = elapsed.as_secs_f64();
+        for _ in 0..MAX_EVENTS_PER_ADVANCE {
+            if self.until_next > left {
+                self.until_next -= left;
+                break;
+            }
+            left -= self.until_next;
+            self.next_event(&mut trades);
+            self.until_next = self.exponential(1.0 / self.event_rate());
+        }
+        trades
+    }
+
+    fn event_rate(&self) -> f64 {
+        let cancels = self.config.cancel_rate * self.book.order_count() as f64;
+        (self.config.order_rate + self.config.market_order_rate + cancels).max(1e-6)
+    }
+
+    // One arrival, chosen in proportion to the rates
+    fn next_event(&mut self, trades: &mut Vec<Trade>) {
+        let cancels = self.config.cancel_rate * self.book.order_count() as f64;
+        let pick = self.rng.gen::<f64>() * self.event_rate();
+        if pick < self.config.order_rate {
+            // Mostly passive, now and then a tick through the fair value
+            let side = if self.rng.gen_bool(0.5) { Side::Buy } else { Side::Sell };
+            let distance = self.exponential(self.config.mean_depth) as Ticks - 1;
+            let fills = self.place_limit(side, distance);
+            self.record(fills, trades);
+        } else if pick < self.config.order_rate + self.config.market_order_rate {
+            let side = if self.rng.gen_bool(self.buy_probability()) { Side::Buy } else { Side::Sell };
+            let size = self.order_size();
+            self.next_order_id += 1;
+            let fills = self.book.submit(self.next_order_id, side, None, size);
+            self.record(fills, trades);
+        } else if cancels > 0.0 {
+            self.cancel_random();
+        }
+    }
+
+    // Place a limit order `distance` ticks on the passive side of the fair value
+    fn place_limit(&mut self, side: Side, distance: Ticks) -> Vec<Fill> {
+        let fair = self.to_ticks(self.fair_value);
+        let price = match side {
+            Side::Buy => fair - distance,
+            Side::Sell => fair + distance,
+        }
+        .max(1);
+        let size = self.order_size();
+        self.next_order_id += 1;
+        let order_id = self.next_order_id;
+        let fills = self.book.submit(order_id, side, Some(price), size);
+        if self.book.contains(order_id) {
+            self.resting.push(order_id);
+        }
+        fills
+    }
+
+    // Market orders buy more often while the book's mid is below the fair
+    // value and sell more often while it is above
+    fn buy_probability(&self) -> f64 {
+        let (Some((bid, _)), Some((ask, _))) = (self.book.best_bid(), self.book.best_ask()) else {
+            return 0.5;
+        };
+        let mid = (bid + ask) as f64 / 2.0;
+        let gap = (self.fair_value / self.config.tick_size - mid) / self.config.mean_depth;
+        0.5 + 0.4 * gap.tanh()
+    }
+
+    fn cancel_random(&mut self) {
+        if self.resting.len() > 2 * self.book.order_count() + 16 {
+            let book = &self.book;
+            self.resting.retain(|id| book.contains(*id));
+        }
+        while !self.resting.is_empty() {
+            let index = self.rng.gen_range(0..self.resting.len());
+            let order_id = self.resting.swap_remove(index);
+            if self.book.cancel(order_id) {
+                return;
+            }
+        }
+    }
+
+    fn record(&mut self, fills: Vec<Fill>, trades: &mut Vec<Trade>) {
+        for fill in fills {
+            let price = self.to_price(fill.price);
+            self.last_price = Some(price);
+            trades.push(Trade {
+                trade_id: fill.trade_id,
+                price,
+                size: self.to_size(fill.size),
+                side: fill.taker_side,
+            });
+        }
+    }
+
+    fn order_size(&mut self) -> Lots {
+        let size = self.exponential(self.config.mean_size);
+        ((size / self.config.lot_size).round() as Lots).max(1)
+    }
+
+    fn exponential(&mut self, mean: f64) -> f64 {
+        -mean * (1.0 - self.rng.gen::<f64>()).ln()
+    }
+
+    pub fn to_ticks(&self, price: f64) -> Ticks {
+        (price / self.config.tick_size).round() as Ticks
+    }
+
+    pub fn to_price(&self, ticks: Ticks) -> f64 {
+        round(ticks as f64 * self.config.tick_size, self.price_decimals)
+    }
+
+    pub fn to_size(&self, lots: Lots) -> f64 {
+        round(lots as f64 * self.config.lot_size, self.size_decimals)
+    }
+}
+
+// Decimal places needed to show multiples of `step` exactly
+fn decimals(step: f64) -> i32 {
+    (-step.log10()).ceil().max(0.0) as i32
+}
+
+fn round(value: f64, decimals: i32) -> f64 {
+    let scale = 10f64.powi(decimals);
+    (value * scale).round() / scale
+}
+
+// A standard normal sample by the Box-Muller transform
+pub fn standard_normal(rng: &mut impl Rng) -> f64 {
+    let u: f64 = 1.0 - rng.gen::<f64>();
+    let v: f64 = rng.gen();
+    (-2.0 * u.ln()).sqrt() * (TAU * v).cos()
+}
@@ -0,0 +1,86 @@
+// Subjects and envelopes for the publishers, which both send a feed's
+// messages on a `--subject` with `{channel}` standing in for the channel
+use market_model::{Envelope, Validate};
+
+use crate::feed::{Channel, FeedMessage};
+
+// Check a `--subject` before connecting: a NATS subject without wildcards,
+// with `{channel}` standing in for a token or not at all
+pub fn check_subject(subject: &str) -> Result<(), String> {
+    let literal = subject.replace("{channel}", "trades");
+    let valid = !literal.is_empty()
+        && !literal.chars().any(char::is_whitespace)
+        && literal.split('.').all(|token| !token.is_empty() && !token.contains(['*', '>', '{', '}']));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid subject '{}': expected dot-separated tokens without wildcards", subject))
+    }
+}
+
+// Where one channel's messages go. A subject without `{channel}` carries
+// trades only, as it did before the book and ticker channels existed.
+pub fn channel_subject(subject: &str, channel: Channel) -> Option<String> {
+    if subject.contains("{channel}") {
+        Some(subject.replace("{channel}", channel.name()))
+    } else {
+        (channel == Channel::Trades).then(|| subject.to_string())
+    }
+}
+
+// Wrap the messages from one `Feed::advance` for `subject`, leaving out
+// channels it has no subject for. A message that fails validation comes
+// back as an error for the caller to log instead of being published.
+pub fn envelopes<'a>(
+    subject: &'a str,
+    messages: &'a [FeedMessage],
+    timestamp: u64,
+) -> impl Iterator<Item = Result<Envelope, String>> + 'a {
+    messages.iter().filter_map(move |message| {
+        let channel_subject = channel_subject(subject, message.channel)?;
+        Some(match message.data.validate() {
+            Ok(()) => Ok(Envelope::wrap(channel_subject, &message.data, Some(timestamp))),
+            Err(e) => Err(format!("Not publishing invalid {} message: {}", message.channel.name(), e)),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use market_model::{MarketData, Side, Trade};
+
+    use super::*;
+
+    #[test]
+    fn literal_subjects_carry_trades_only() {
+        assert_eq!(channel_subject("market.btc-usd.{channel}", Channel::Level2).as_deref(), Some("market.btc-usd.level2"));
+        assert_eq!(channel_subject("market.btc-usd.trades", Channel::Trades).as_deref(), Some("market.btc-usd.trades"));
+        assert_eq!(channel_subject("market.btc-usd.trades", Channel::Ticker), None);
+
+        assert!(check_subject("market.btc-usd.{channel}").is_ok());
+        assert!(check_subject("market.btc-usd.trades").is_ok());
+        assert!(check_subject("market.*.{channel}").is_err());
+        assert!(check_subject("market..{channel}").is_err());
+    }
+
+    #[test]
+    fn invalid_messages_are_not_wrapped() {
+        let trade = |price| FeedMessage {
+            channel: Channel::Trades,
+            data: MarketData::Trade(Trade {
+                price,
+                size: 0.5,
+                side: Side::Buy,
+                exchange: "test".to_string(),
+                pair: "BTC-USD".to_string(),
+                trade_id: None,
+                timestamp: 1,
+            }),
+        };
+        let messages = [trade(30000.0), trade(-1.0)];
+        let results: Vec<_> = envelopes("market.btc-usd.{channel}", &messages, 1).collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap().subject, "market.btc-usd.trades");
+        assert!(results[1].is_err());
+    }
+}
@@ -0,0 +1,114 @@
+// NATS connection options shared by the proxy and the publishers, so both
+// can reach a cluster secured with TLS, NKeys, credentials files or tokens
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use async_nats::ConnectOptions;
+use clap::Args;
+use url::Url;
+
+// Command line options for the NATS connection, flattened into each
+// binary's own arguments. At most one way of authenticating may be given;
+// username and password embedded in the server URL count as one.
+#[derive(Args, Debug, Clone, Default, PartialEq)]
+pub struct NatsArgs {
+    /// CA certificate (PEM) used to verify the NATS server
+    #[arg(long)]
+    pub nats_ca_file: Option<PathBuf>,
+
+    /// Client certificate (PEM) for mutual TLS, used with --nats-key-file
+    #[arg(long, requires = "nats_key_file")]
+    pub nats_cert_file: Option<PathBuf>,
+
+    /// Client private key (PEM) for mutual TLS, used with --nats-cert-file
+    #[arg(long, requires = "nats_cert_file")]
+    pub nats_key_file: Option<PathBuf>,
+
+    /// Refuse to connect to the NATS server without TLS; `=false` overrides
+    /// a configured `true`
+    #[arg(long, num_args = 0..=1, require_equals = true, default_value_t = false, default_missing_value = "true")]
+    pub nats_require_tls: bool,
+
+    /// File holding an NKey seed (starting with `SU`) to authenticate with
+    #[arg(long)]
+    pub nats_nkey_file: Option<PathBuf>,
+
+    /// `.creds` file holding a user JWT and NKey seed to authenticate with
+    #[arg(long)]
+    pub nats_creds_file: Option<PathBuf>,
+
+    /// File holding a token to authenticate with
+    #[arg(long)]
+    pub nats_token_file: Option<PathBuf>,
+}
+
+impl NatsArgs {
+    // Build fresh options for connecting to `url`. Files are read on every
+    // call, so rotated credentials are picked up when reconnecting.
+    pub async fn connect_options(&self, url: &Url) -> Result<ConnectOptions, Box<dyn Error>> {
+        let username = url.username();
+        let password = url.password().unwrap_or("");
+
+        let mut methods = Vec::new();
+        if !username.is_empty() {
+            methods.push("credentials in the URL");
+        }
+        if self.nats_nkey_file.is_some() {
+            methods.push("--nats-nkey-file");
+        }
+        if self.nats_creds_file.is_some() {
+            methods.push("--nats-creds-file");
+        }
+        if self.nats_token_file.is_some() {
+            methods.push("--nats-token-file");
+        }
+        if methods.len() > 1 {
+            return Err(format!("Choose one NATS authentication method, not {}", methods.join(" and ")).into());
+        }
+
+        let mut options = if let Some(path) = &self.nats_creds_file {
+            ConnectOptions::with_credentials_file(path.clone())
+                .await
+                .map_err(|e| format!("Failed to read credentials file {}: {}", path.display(), e))?
+        } else if let Some(path) = &self.nats_nkey_file {
+            let seed = read_secret(path)?;
+            if !seed.starts_with('S') {
+                return Err(format!("{} does not hold an NKey seed", path.display()).into());
+            }
+            ConnectOptions::with_nkey(seed)
+        } else if let Some(path) = &self.nats_token_file {
+            ConnectOptions::with_token(read_secret(path)?)
+        } else if !username.is_empty() {
+            ConnectOptions::with_user_and_password(username.to_string(), password.to_string())
+        } else {
+            ConnectOptions::new()
+        };
+
+        if let Some(path) = &self.nats_ca_file {
+            options = options.add_root_certificates(readable(path)?);
+        }
+        if let (Some(cert), Some(key)) = (&self.nats_cert_file, &self.nats_key_file) {
+            options = options.add_client_certificate(readable(cert)?, readable(key)?);
+        }
+        // `tls://` URLs require TLS on their own
+        Ok(options.require_tls(self.nats_require_tls))
+    }
+}
+
+// First line of a secret file, without surrounding whitespace
+fn read_secret(path: &Path) -> Result<String, Box<dyn Error>> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let secret = contents.lines().next().unwrap_or_default().trim();
+    if secret.is_empty() {
+        return Err(format!("{} is empty", path.display()).into());
+    }
+    Ok(secret.to_string())
+}
+
+// async-nats only opens certificate files once it connects; check them up
+// front so a typo is reported as such rather than as a TLS failure
+fn readable(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    std::fs::metadata(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(path.to_path_buf())
+}
@@ -0,0 +1,65 @@
+// The simulated market the NEX publisher sends to NATS, kept apart from
+// its command line so tests can run it against a server of their own
+use std::error::Error;
+use std::time::Duration;
+
+use async_nats::Client;
+use chrono::Utc;
+use log::{info, error};
+use market_sim::{envelopes, Feed, Market, MarketConfig};
+use tokio::time::{sleep, Instant};
+
+// Publish simulated data to NATS
+pub async fn publish_simulated_data(
+    client: Client,
+    subject: String,
+    pair: String,
+    interval: u64,
+    initial_price: f64,
+    volatility: f64,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!("Starting to publish simulated data to {}", subject);
+    info!("Initial price: ${:.2}, Volatility: {:.2}%, Interval: {}ms", 
+          initial_price, volatility, interval);
+    
+    // Order flow sized to how far the fair value moves in a second
+    let interval = Duration::from_millis(interval.max(1));
+    let per_second = volatility / interval.as_secs_f64().sqrt();
+    let market = Market::new(MarketConfig::for_price(initial_price, per_second), initial_price);
+    let mut feed = Feed::new(market, pair, "nex");
+    let mut last = Instant::now();
+    
+    // Publish loop
+    loop {
+        // Move the fair value and run the order book up to now
+        feed.market_mut().walk(volatility);
+        let now = Instant::now();
+        let timestamp = Utc::now().timestamp_millis() as u64;
+        let messages = feed.advance(now - last, timestamp);
+        last = now;
+        
+        for envelope in envelopes(&subject, &messages, timestamp) {
+            let envelope = match envelope {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    error!("{}", e);
+                    continue;
+                }
+            };
+            let json_data = serde_json::to_string(&envelope.data)?;
+            if let Err(e) = client.publish(envelope.subject, json_data.into()).await {
+                error!("Failed to publish message: {}", e);
+            }
+        }
+        
+        let market = feed.market();
+        let (bid, ask) = (market.book().best_bid(), market.book().best_ask());
+        info!("Published: {} ${:.2} | Bid: {:?} | Ask: {:?}", 
+              feed.pair(), market.fair_value(),
+              bid.map(|(price, _)| market.to_price(price)),
+              ask.map(|(price, _)| market.to_price(price)));
+        
+        // Wait for the next interval
+        sleep(interval).await;
+    }
+}
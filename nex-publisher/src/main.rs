@@ -1,13 +1,10 @@
 use std::error::Error;
-use std::time::Duration;
 
-use async_nats::Client;
-use chrono::Utc;
 use clap::Parser;
 use log::{info, error, warn};
-use rand::{Rng, thread_rng};
-use serde::{Serialize, Deserialize};
-use serde_json::json;
+use nats_config::NatsArgs;
+use market_sim::check_subject;
+use nex_publisher::publish_simulated_data;
 use tokio::time::sleep;
 use url::Url;
 
@@ -19,32 +16,29 @@ struct Args {
     #[arg(short, long, default_value = "nats://localhost:4222")]
     nats_url: String,
 
-    /// Subject to publish to
-    #[arg(short, long, default_value = "market.btc-usd.trades")]
+    /// Subject to publish to; {channel} is replaced by trades, level2 or
+    /// ticker, and a subject without it gets trades only
+    #[arg(short, long, default_value = "market.btc-usd.{channel}")]
     subject: String,
 
-    /// Interval between messages in milliseconds
+    /// Trading pair reported in each message
+    #[arg(long, default_value = "BTC-USD")]
+    pair: String,
+
+    /// Interval between batches of messages in milliseconds
     #[arg(short, long, default_value_t = 1000)]
     interval: u64,
 
     /// Initial price
-    #[arg(short, long, default_value_t = 30000.0)]
+    #[arg(short = 'p', long, default_value_t = 30000.0)]
     initial_price: f64,
 
-    /// Volatility (percentage)
+    /// Volatility of the fair value per interval (percentage)
     #[arg(short, long, default_value_t = 0.5)]
     volatility: f64,
-}
 
-// Trade data structure
-#[derive(Serialize, Deserialize, Debug)]
-struct TradeData {
-    price: f64,
-    size: f64,
-    side: String,
-    exchange: String,
-    pair: String,
-    timestamp: u64,
+    #[command(flatten)]
+    nats: NatsArgs,
 }
 
 #[tokio::main]
@@ -54,6 +48,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     
     // Parse command line arguments
     let args = Args::parse();
+    check_subject(&args.subject)?;
     
     // Connect to NATS server
     info!("Connecting to NATS server at {}", args.nats_url);
@@ -61,25 +56,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Parse the URL to extract credentials if present
     let url = Url::parse(&args.nats_url)?;
     
-    // Extract username and password if present in the URL
-    let username = url.username();
-    let password = url.password().unwrap_or("");
-    
-    // Build connection options
-    let mut options = async_nats::ConnectOptions::new();
-    
-    // Add authentication if credentials are provided
-    if !username.is_empty() {
-        options = options.with_username_and_password(username.to_string(), password.to_string());
-    }
-    
     // Connect to NATS server with retry
     let mut retry_count = 0;
     let max_retries = 5;
     let mut client = None;
     
     while retry_count < max_retries {
-        match options.connect(&args.nats_url).await {
+        // Options are consumed by connecting, so build them for every attempt
+        let options = args.nats.connect_options(&url).await?;
+        match options.connect(url.as_str()).await {
             Ok(c) => {
                 info!("Successfully connected to NATS server");
                 client = Some(c);
@@ -96,81 +81,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     sleep(backoff).await;
                 } else {
                     error!("Maximum retry attempts reached. Giving up.");
-                    return Err(Box::new(e));
+                    return Err(e.into());
                 }
             }
         }
     }
     
     let client = client.unwrap();
-    
+
     // Start publishing simulated data
     publish_simulated_data(
         client, 
         args.subject, 
+        args.pair,
         args.interval, 
         args.initial_price, 
         args.volatility
-    ).await?;
+    ).await.map_err(|e| e as Box<dyn Error>)?;
     
     Ok(())
 }
-
-// Publish simulated data to NATS
-async fn publish_simulated_data(
-    client: Client,
-    subject: String,
-    interval: u64,
-    initial_price: f64,
-    volatility: f64,
-) -> Result<(), Box<dyn Error>> {
-    info!("Starting to publish simulated data to {}", subject);
-    info!("Initial price: ${:.2}, Volatility: {:.2}%, Interval: {}ms", 
-          initial_price, volatility, interval);
-    
-    // Current price, starting with the initial price
-    let mut price = initial_price;
-    
-    // Publish loop
-    loop {
-        // Update price with random movement
-        let change_pct = thread_rng().gen_range(-volatility..volatility);
-        price = price * (1.0 + change_pct / 100.0);
-        
-        // Generate random trade size
-        let size = thread_rng().gen_range(0.001..1.0);
-        
-        // Randomly choose buy or sell
-        let side = if thread_rng().gen_bool(0.5) { "buy" } else { "sell" };
-        
-        // Current timestamp
-        let timestamp = Utc::now().timestamp_millis() as u64;
-        
-        // Create trade data
-        let trade = TradeData {
-            price,
-            size,
-            side: side.to_string(),
-            exchange: "nex".to_string(),
-            pair: "BTC-USD".to_string(),
-            timestamp,
-        };
-        
-        // Serialize to JSON
-        let json_data = serde_json::to_string(&trade)?;
-        
-        // Publish to NATS
-        match client.publish(subject.clone(), json_data.into()).await {
-            Ok(_) => {
-                info!("Published: {} ${:.2} | Size: {:.4} | Side: {}", 
-                      trade.pair, trade.price, trade.size, trade.side);
-            },
-            Err(e) => {
-                error!("Failed to publish message: {}", e);
-            }
-        }
-        
-        // Wait for the next interval
-        sleep(Duration::from_millis(interval)).await;
-    }
-}
\ No newline at end of file
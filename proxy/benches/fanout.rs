@@ -0,0 +1,209 @@
+// Fan-out benchmark: many clients on many subjects fed by several
+// publisher threads, measuring delivered messages/sec and the latency from
+// broadcast to the client's writer picking the message up.
+//
+//     cargo bench --bench fanout -- --clients 5000 --subjects 200 --publishers 8
+//
+// `--rate 0` publishes flat out to find the throughput ceiling; latency is
+// then dominated by full queues rather than by the fan-out itself.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+
+use rt_duckdb_coinbase_server::auth::Permissions;
+use rt_duckdb_coinbase_server::history::History;
+use rt_duckdb_coinbase_server::protocol::NexStreamMessage;
+use rt_duckdb_coinbase_server::queue::{ClientQueue, Outgoing, QueueConfig, SlowConsumerPolicy};
+use rt_duckdb_coinbase_server::registry::Registry;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Number of simulated WebSocket clients
+    #[arg(long, default_value_t = 2000)]
+    clients: usize,
+
+    /// Number of distinct subjects published to
+    #[arg(long, default_value_t = 100)]
+    subjects: usize,
+
+    /// Literal subjects each client subscribes to
+    #[arg(long, default_value_t = 5)]
+    subscriptions: usize,
+
+    /// Fraction of clients that also subscribe to every subject by wildcard
+    #[arg(long, default_value_t = 0.01)]
+    wildcard_ratio: f64,
+
+    /// Publisher threads calling broadcast concurrently
+    #[arg(long, default_value_t = 4)]
+    publishers: usize,
+
+    /// Messages per second per publisher (0 publishes as fast as possible)
+    #[arg(long, default_value_t = 1000)]
+    rate: u64,
+
+    /// How long to publish for, in seconds
+    #[arg(long, default_value_t = 5)]
+    seconds: u64,
+
+    /// Messages queued per client before the slow-consumer policy applies
+    #[arg(long, default_value_t = 1024)]
+    queue_size: usize,
+
+    /// Recent messages kept per subject (0 disables history)
+    #[arg(long, default_value_t = 1000)]
+    history_size: usize,
+
+    // `cargo bench` passes this to every bench target
+    #[arg(long, hide = true)]
+    bench: bool,
+}
+
+#[derive(Deserialize)]
+struct Probe {
+    data: ProbeData,
+}
+
+#[derive(Deserialize)]
+struct ProbeData {
+    sent_us: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let registry = Arc::new(Registry::new(History::new(args.history_size, Duration::from_secs(3600))));
+    let subjects: Vec<String> = (0..args.subjects).map(|i| format!("market.pair-{}.trades", i)).collect();
+    let queue_config = QueueConfig {
+        capacity: args.queue_size.max(1),
+        policy: SlowConsumerPolicy::DropOldest,
+    };
+    let start = Instant::now();
+
+    // Subscribe the clients through the same control messages browsers send,
+    // and drain each queue the way a connection's writer task does
+    let mut queues = Vec::with_capacity(args.clients);
+    let mut drainers = Vec::with_capacity(args.clients);
+    for i in 0..args.clients {
+        let client_id = format!("client-{}", i);
+        let queue = Arc::new(ClientQueue::new(queue_config));
+        registry.add_client(&client_id, queue.clone(), Permissions::all());
+
+        let mut rng = thread_rng();
+        for subject in subjects.choose_multiple(&mut rng, args.subscriptions) {
+            registry.handle_message(&client_id, &subscribe(subject));
+        }
+        if rng.gen_bool(args.wildcard_ratio.clamp(0.0, 1.0)) {
+            registry.handle_message(&client_id, &subscribe("market.*.trades"));
+        }
+
+        queues.push(queue.clone());
+        drainers.push(tokio::spawn(drain(queue, start)));
+    }
+
+    // Publish from plain threads, like the NATS task and simulator do
+    println!(
+        "Publishing to {} clients on {} subjects from {} publishers for {}s",
+        args.clients, args.subjects, args.publishers, args.seconds
+    );
+    let stop = Arc::new(AtomicBool::new(false));
+    let published = Arc::new(AtomicU64::new(0));
+    let publishers: Vec<_> = (0..args.publishers)
+        .map(|p| {
+            let registry = registry.clone();
+            let subjects = subjects.clone();
+            let stop = stop.clone();
+            let published = published.clone();
+            let pause = (args.rate > 0).then(|| Duration::from_secs_f64(1.0 / args.rate as f64));
+            thread::spawn(move || {
+                let mut seq = 0u64;
+                let mut next = Instant::now();
+                while !stop.load(Ordering::Relaxed) {
+                    let message = NexStreamMessage::new(
+                        subjects[(seq as usize * args.publishers + p) % subjects.len()].clone(),
+                        serde_json::json!({
+                            "price": 30000.0,
+                            "seq": seq,
+                            "sent_us": start.elapsed().as_micros() as u64,
+                        }),
+                        None,
+                    );
+                    let message_json = serde_json::to_string(&message).unwrap();
+                    registry.broadcast(&message, &message_json, None);
+                    published.fetch_add(1, Ordering::Relaxed);
+                    seq += 1;
+
+                    if let Some(pause) = pause {
+                        next += pause;
+                        if let Some(wait) = next.checked_duration_since(Instant::now()) {
+                            thread::sleep(wait);
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    tokio::time::sleep(Duration::from_secs(args.seconds)).await;
+    stop.store(true, Ordering::Relaxed);
+    for publisher in publishers {
+        publisher.join().unwrap();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    // Give the drainers a moment to empty their queues, then stop them
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let dropped: u64 = queues.iter().map(|queue| queue.dropped()).sum();
+    for queue in &queues {
+        queue.close(None);
+    }
+    let mut latencies = Vec::new();
+    for drainer in drainers {
+        latencies.extend(drainer.await.unwrap());
+    }
+    latencies.sort_unstable();
+
+    let published = published.load(Ordering::Relaxed);
+    let delivered = latencies.len() as u64;
+    println!("published:   {} msgs ({:.0} msgs/sec)", published, published as f64 / elapsed);
+    println!("delivered:   {} msgs ({:.0} msgs/sec)", delivered, delivered as f64 / elapsed);
+    println!("dropped:     {} msgs", dropped);
+    println!("latency p50: {} us", percentile(&latencies, 0.50));
+    println!("latency p99: {} us", percentile(&latencies, 0.99));
+    println!("latency max: {} us", latencies.last().copied().unwrap_or(0));
+}
+
+fn subscribe(subject: &str) -> String {
+    serde_json::json!({ "action": "subscribe", "subject": subject }).to_string()
+}
+
+// Pop messages until the queue is closed, recording each data message's
+// latency in microseconds; control replies and lagged notices are skipped
+async fn drain(queue: Arc<ClientQueue>, start: Instant) -> Vec<u64> {
+    let mut latencies = Vec::new();
+    while let Some(Outgoing::Message(msg)) = queue.pop().await {
+        let Ok(text) = msg.to_str() else {
+            continue;
+        };
+        if let Ok(probe) = serde_json::from_str::<Probe>(text) {
+            let now = start.elapsed().as_micros() as u64;
+            latencies.push(now.saturating_sub(probe.data.sent_us));
+        }
+    }
+    latencies
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
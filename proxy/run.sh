@@ -4,4 +4,4 @@
 cargo build --release
 
 # Run the proxy with simulation mode enabled
-RUST_LOG=info ./target/release/nex-stream-proxy --simulate --port 3030
\ No newline at end of file
+RUST_LOG=info ../target/release/rt-duckdb-coinbase-server --simulate --proxy-port 3030
\ No newline at end of file
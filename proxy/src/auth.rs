@@ -0,0 +1,295 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error as StdError;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use url::Url;
+
+use crate::subject;
+
+// Why a WebSocket client was turned away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken(String),
+    Expired,
+    OriginNotAllowed(String),
+    // Publishing without a token from somewhere other than this host
+    NotLocal(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "missing token"),
+            AuthError::InvalidToken(reason) => write!(f, "invalid token: {}", reason),
+            AuthError::Expired => write!(f, "token expired"),
+            AuthError::OriginNotAllowed(origin) => write!(f, "origin '{}' is not allowed", origin),
+            AuthError::NotLocal(addr) => write!(f, "publishing from {} needs a token", addr),
+        }
+    }
+}
+
+impl StdError for AuthError {}
+
+impl warp::reject::Reject for AuthError {}
+
+// Subjects a client may subscribe to; `None` allows everything
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Permissions {
+    subjects: Option<Vec<String>>,
+}
+
+impl Permissions {
+    pub fn all() -> Self {
+        Permissions { subjects: None }
+    }
+
+    pub fn only(subjects: Vec<String>) -> Self {
+        Permissions { subjects: Some(subjects) }
+    }
+
+    // A pattern is allowed if everything it matches is covered by one of
+    // the permitted patterns
+    pub fn allows(&self, pattern: &str) -> bool {
+        match &self.subjects {
+            Some(subjects) => subjects.iter().any(|allowed| subject::is_subset(pattern, allowed)),
+            None => true,
+        }
+    }
+}
+
+// Who a connection belongs to, what it may subscribe to, and when its
+// credentials stop being valid
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    pub permissions: Permissions,
+    pub expires_at: Option<SystemTime>,
+}
+
+impl Identity {
+    pub fn anonymous() -> Self {
+        Identity {
+            name: "anonymous".to_string(),
+            permissions: Permissions::all(),
+            expires_at: None,
+        }
+    }
+}
+
+// One way of checking a bearer token
+pub trait TokenVerifier: Send + Sync {
+    fn verify(&self, token: &str) -> Result<Identity, AuthError>;
+}
+
+// Tokens listed in a file, one per line, optionally followed by a
+// comma-separated list of the subject patterns it may subscribe to:
+//
+//     s3cr3t-token
+//     btc-only-token market.btc-usd.*,market.btc-usd.>
+pub struct StaticTokens {
+    tokens: HashMap<String, (String, Permissions)>,
+}
+
+impl StaticTokens {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn StdError>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read token file {}: {}", path.display(), e))?;
+
+        let mut tokens = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let token = fields.next().unwrap_or_default().to_string();
+            let permissions = match fields.next() {
+                Some(list) => Permissions::only(parse_subjects(list, path, number + 1)?),
+                None => Permissions::all(),
+            };
+            let name = format!("{}:{}", path.display(), number + 1);
+            tokens.insert(token, (name, permissions));
+        }
+        Ok(StaticTokens { tokens })
+    }
+}
+
+impl TokenVerifier for StaticTokens {
+    fn verify(&self, token: &str) -> Result<Identity, AuthError> {
+        match self.tokens.get(token) {
+            Some((name, permissions)) => Ok(Identity {
+                name: name.clone(),
+                permissions: permissions.clone(),
+                expires_at: None,
+            }),
+            None => Err(AuthError::InvalidToken("unknown token".to_string())),
+        }
+    }
+}
+
+fn parse_subjects(list: &str, path: &Path, line: usize) -> Result<Vec<String>, Box<dyn StdError>> {
+    list.split(',')
+        .map(|pattern| {
+            subject::validate_pattern(pattern)
+                .map(|_| pattern.to_string())
+                .map_err(|e| format!("{}:{}: invalid subject '{}': {}", path.display(), line, pattern, e).into())
+        })
+        .collect()
+}
+
+// Claims read from an HS256 JWT. `exp` is required; `subjects` limits what
+// the holder may subscribe to and allows everything when absent.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    exp: u64,
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    subjects: Option<Vec<String>>,
+}
+
+// HMAC-SHA256 signed JWTs sharing a secret with whoever issues them
+pub struct JwtVerifier {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtVerifier {
+    pub fn new(secret: &[u8]) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        validation.set_required_spec_claims(&["exp"]);
+        JwtVerifier {
+            key: DecodingKey::from_secret(secret),
+            validation,
+        }
+    }
+}
+
+impl TokenVerifier for JwtVerifier {
+    fn verify(&self, token: &str) -> Result<Identity, AuthError> {
+        let claims = decode::<Claims>(token, &self.key, &self.validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => AuthError::Expired,
+                ErrorKind::InvalidToken => AuthError::InvalidToken("unknown or malformed token".to_string()),
+                _ => AuthError::InvalidToken(e.to_string()),
+            })?
+            .claims;
+
+        if let Some(subjects) = &claims.subjects {
+            if let Some(bad) = subjects.iter().find(|p| subject::validate_pattern(p).is_err()) {
+                return Err(AuthError::InvalidToken(format!("invalid subject claim '{}'", bad)));
+            }
+        }
+
+        Ok(Identity {
+            name: claims.sub.unwrap_or_else(|| "jwt".to_string()),
+            permissions: claims.subjects.map(Permissions::only).unwrap_or_default(),
+            expires_at: Some(UNIX_EPOCH + Duration::from_secs(claims.exp)),
+        })
+    }
+}
+
+// Gatekeeper for `/ws`: checks the Origin header against an allow-list and
+// the bearer token against each configured verifier in turn. With no
+// verifiers configured every client is let in as anonymous.
+#[derive(Default)]
+pub struct Authenticator {
+    verifiers: Vec<Box<dyn TokenVerifier>>,
+    origins: Option<HashSet<String>>,
+}
+
+impl Authenticator {
+    pub fn with_verifier(mut self, verifier: impl TokenVerifier + 'static) -> Self {
+        self.verifiers.push(Box::new(verifier));
+        self
+    }
+
+    // Only accept browsers on these origins, e.g. `https://example.com`
+    pub fn with_origins(mut self, origins: &[String]) -> Result<Self, Box<dyn StdError>> {
+        if origins.is_empty() {
+            return Ok(self);
+        }
+
+        let mut allowed = HashSet::new();
+        for origin in origins {
+            let parsed = Url::parse(origin).map_err(|e| format!("Invalid origin '{}': {}", origin, e))?;
+            let serialized = parsed.origin().ascii_serialization();
+            if serialized != origin.trim_end_matches('/') {
+                return Err(format!("Invalid origin '{}': expected something like '{}'", origin, serialized).into());
+            }
+            allowed.insert(serialized);
+        }
+        self.origins = Some(allowed);
+        Ok(self)
+    }
+
+    // Whether any verifier is configured, so clients need a token at all
+    pub fn requires_token(&self) -> bool {
+        !self.verifiers.is_empty()
+    }
+
+    pub fn origins(&self) -> Option<&HashSet<String>> {
+        self.origins.as_ref()
+    }
+
+    // Requests without an Origin header do not come from a browser and are
+    // left to the token check
+    pub fn check_origin(&self, origin: Option<&str>) -> Result<(), AuthError> {
+        match (&self.origins, origin) {
+            (Some(allowed), Some(origin)) if !allowed.contains(origin) => {
+                Err(AuthError::OriginNotAllowed(origin.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn authenticate(&self, token: Option<&str>) -> Result<Identity, AuthError> {
+        if self.verifiers.is_empty() {
+            return Ok(Identity::anonymous());
+        }
+        let token = token.ok_or(AuthError::MissingToken)?;
+
+        let mut error = AuthError::MissingToken;
+        for verifier in &self.verifiers {
+            match verifier.verify(token) {
+                Ok(identity) => return Ok(identity),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+}
+
+// The authenticator in force, replaced as a whole when the configuration is
+// reloaded. Connections already accepted keep the identity they were given.
+#[derive(Clone, Default)]
+pub struct SharedAuthenticator(Arc<RwLock<Arc<Authenticator>>>);
+
+impl SharedAuthenticator {
+    pub fn new(auth: Authenticator) -> Self {
+        SharedAuthenticator(Arc::new(RwLock::new(Arc::new(auth))))
+    }
+
+    pub fn current(&self) -> Arc<Authenticator> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, auth: Authenticator) {
+        *self.0.write().unwrap() = Arc::new(auth);
+    }
+}
+
+// Pull a bearer token out of an `Authorization` header value
+pub fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
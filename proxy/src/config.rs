@@ -0,0 +1,347 @@
+// Proxy settings read from a TOML or YAML file, chosen by extension, and
+// from `NEX_PROXY_<SECTION>_<KEY>` environment variables. Both stand in for
+// the defaults of the matching command line flags, so a flag always wins,
+// then the environment, then the file:
+//
+//     [limits]
+//     max_subscriptions = 50    # or NEX_PROXY_LIMITS_MAX_SUBSCRIPTIONS=50
+//
+// Simulated instruments have no flag and only come from the file.
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+
+use clap::Command;
+use log::warn;
+use market_sim::Channel;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::simulator::Instrument;
+use crate::subject;
+
+pub const ENV_PREFIX: &str = "NEX_PROXY_";
+
+// Environment variable naming the configuration file, like `--config`
+pub const CONFIG_ENV: &str = "NEX_PROXY_CONFIG";
+
+// Every setting as (section, key, id of the flag it stands in for)
+const SETTINGS: &[(&str, &str, &str)] = &[
+    ("listeners", "proxy_port", "proxy_port"),
+    ("listeners", "http_port", "http_port"),
+    ("listeners", "static_dir", "static_dir"),
+    ("listeners", "tls_cert", "tls_cert"),
+    ("listeners", "tls_key", "tls_key"),
+    ("listeners", "tls_reload_interval", "tls_reload_interval"),
+    ("upstream", "url", "nex_url"),
+    ("upstream", "replay_limit", "replay_limit"),
+    ("upstream", "ca_file", "nats_ca_file"),
+    ("upstream", "cert_file", "nats_cert_file"),
+    ("upstream", "key_file", "nats_key_file"),
+    ("upstream", "require_tls", "nats_require_tls"),
+    ("upstream", "nkey_file", "nats_nkey_file"),
+    ("upstream", "creds_file", "nats_creds_file"),
+    ("upstream", "token_file", "nats_token_file"),
+    ("simulation", "enabled", "simulate"),
+    ("auth", "token_file", "token_file"),
+    ("auth", "jwt_secret_file", "jwt_secret_file"),
+    ("auth", "publish_token_file", "publish_token_file"),
+    ("auth", "publish_jwt_secret_file", "publish_jwt_secret_file"),
+    ("cors", "allowed_origins", "allowed_origins"),
+    ("limits", "max_control_rate", "max_control_rate"),
+    ("limits", "control_burst", "control_burst"),
+    ("limits", "max_subscriptions", "max_subscriptions"),
+    ("limits", "max_connections_per_ip", "max_connections_per_ip"),
+    ("clients", "queue_size", "client_queue_size"),
+    ("clients", "slow_consumer_policy", "slow_consumer_policy"),
+    ("clients", "ping_interval", "ping_interval"),
+    ("clients", "max_missed_pongs", "max_missed_pongs"),
+    ("history", "size", "history_size"),
+    ("history", "max_age", "history_max_age"),
+    ("history", "max_subjects", "history_max_subjects"),
+    ("history", "snapshot_size", "history_snapshot_size"),
+    ("health", "ready_max_message_age", "ready_max_message_age"),
+    ("shutdown", "timeout", "shutdown_timeout"),
+    ("shutdown", "reconnect_url", "reconnect_url"),
+];
+
+// One instrument in `[[simulation.instruments]]`; left out values come from
+// `Instrument::default()` and the `[simulation]` section
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InstrumentSettings {
+    pair: String,
+    // Overrides `subject_template`
+    subject: Option<String>,
+    initial_price: Option<f64>,
+    volatility: Option<f64>,
+    interval_ms: Option<u64>,
+    // Overrides the section's `correlation`
+    correlation: Option<f64>,
+    // Order flow; see `MarketConfig`
+    tick_size: Option<f64>,
+    order_rate: Option<f64>,
+    market_order_rate: Option<f64>,
+    cancel_rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    // Flag id and the value replacing its default; lists are comma-separated
+    defaults: Vec<(&'static str, String)>,
+    simulation: Vec<Instrument>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SimulationSection {
+    #[serde(default)]
+    enabled: Option<Value>,
+    // Subjects of each instrument, with `{pair}`, `{base}` and `{quote}`
+    // replaced by the lowercased pair and its halves and `{channel}` by
+    // `trades`, `level2` or `ticker`
+    #[serde(default = "default_subject_template")]
+    subject_template: String,
+    // Default loading of every instrument on the common market factor
+    #[serde(default = "default_correlation")]
+    correlation: f64,
+    #[serde(default)]
+    instruments: Vec<InstrumentSettings>,
+}
+
+fn default_subject_template() -> String {
+    "market.{pair}.{channel}".to_string()
+}
+
+fn default_correlation() -> f64 {
+    Instrument::default().correlation
+}
+
+impl Config {
+    // Read `path`, if given, then the environment over it
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let mut config = match path {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply_env(std::env::vars());
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read configuration file {}: {}", path.display(), e))?;
+        let value: Value = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?,
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?,
+            _ => return Err(format!("{}: expected a .toml, .yaml or .yml file", path.display()).into()),
+        };
+        Config::from_value(value).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    fn from_value(value: Value) -> Result<Self, String> {
+        let sections = match value {
+            Value::Object(sections) => sections,
+            // An empty YAML document
+            Value::Null => return Ok(Config::default()),
+            _ => return Err("expected a table of sections".to_string()),
+        };
+
+        let mut config = Config::default();
+        for (section, settings) in sections {
+            match (section.as_str(), settings) {
+                (_, Value::Null) => {},
+                ("simulation", settings) => {
+                    let simulation: SimulationSection =
+                        serde_json::from_value(settings).map_err(|e| format!("[simulation]: {}", e))?;
+                    if let Some(enabled) = &simulation.enabled {
+                        config.set("simulation", "enabled", enabled)?;
+                    }
+                    config.simulation = simulation.instruments()?;
+                },
+                (_, Value::Object(settings)) => {
+                    for (key, value) in &settings {
+                        config.set(&section, key, value)?;
+                    }
+                },
+                (section, _) if is_section(section) => return Err(format!("[{}] must be a table", section)),
+                (section, _) => return Err(format!("unknown section [{}]", section)),
+            }
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: &Value) -> Result<(), String> {
+        let id = flag_id(section, key).ok_or_else(|| match is_section(section) {
+            true => format!("unknown setting '{}.{}'", section, key),
+            false => format!("unknown section [{}]", section),
+        })?;
+        let value = match value {
+            // Leave the default in place
+            Value::Null => return Ok(()),
+            Value::Array(items) if items.is_empty() => return Ok(()),
+            Value::Array(items) => items.iter().map(scalar).collect::<Option<Vec<_>>>().map(|items| items.join(",")),
+            value => scalar(value),
+        }
+        .ok_or_else(|| format!("'{}.{}' must be a string, number, boolean or list of those", section, key))?;
+        self.override_default(id, value);
+        Ok(())
+    }
+
+    // Variables naming no known setting, perhaps left over from another
+    // version, are skipped with a warning rather than stopping the proxy;
+    // bad values of known settings still fail when the flags are parsed
+    fn apply_env(&mut self, vars: impl Iterator<Item = (String, String)>) {
+        for (name, value) in vars {
+            let Some(setting) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if name == CONFIG_ENV {
+                continue;
+            }
+            let setting = setting.to_ascii_lowercase();
+            let id = SETTINGS
+                .iter()
+                .find(|(section, key, _)| setting == format!("{}_{}", section, key))
+                .map(|(_, _, id)| *id);
+            match id {
+                Some(id) => self.override_default(id, value),
+                None => warn!("Ignoring environment variable {}, which names no setting", name),
+            }
+        }
+    }
+
+    fn override_default(&mut self, id: &'static str, value: String) {
+        self.defaults.retain(|(existing, _)| *existing != id);
+        self.defaults.push((id, value));
+    }
+
+    // Use the configured values as the defaults of `command`'s flags, for
+    // clap to parse and check like any other
+    pub fn apply_to(&self, command: Command) -> Command {
+        self.defaults
+            .iter()
+            .fold(command, |command, (id, value)| command.mut_arg(*id, |arg| arg.default_value(value.clone())))
+    }
+
+    // Markets the simulator runs, each an order book publishing trades,
+    // level 2 and ticker messages on its own subjects. Without a
+    // `[simulation]` section that is one BTC-USD market on
+    // `market.btc-usd.{channel}`.
+    pub fn simulated_instruments(&self) -> Vec<Instrument> {
+        if self.simulation.is_empty() {
+            vec![Instrument::default()]
+        } else {
+            self.simulation.clone()
+        }
+    }
+}
+
+fn is_section(section: &str) -> bool {
+    SETTINGS.iter().any(|(known, _, _)| *known == section)
+}
+
+fn flag_id(section: &str, key: &str) -> Option<&'static str> {
+    SETTINGS
+        .iter()
+        .find(|(known_section, known_key, _)| *known_section == section && *known_key == key)
+        .map(|(_, _, id)| *id)
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+impl SimulationSection {
+    fn instruments(&self) -> Result<Vec<Instrument>, String> {
+        let defaults = Instrument::default();
+        let mut subjects = HashSet::new();
+        let mut instruments = Vec::new();
+        for settings in &self.instruments {
+            let pair = &settings.pair;
+            let subject = match &settings.subject {
+                Some(subject) => subject.clone(),
+                None => expand_template(&self.subject_template, pair),
+            };
+            let initial_price = settings.initial_price.unwrap_or(defaults.initial_price);
+            let volatility = settings.volatility.unwrap_or(defaults.volatility);
+            let interval = settings.interval_ms.map(Duration::from_millis).unwrap_or(defaults.interval);
+            let mut market = Instrument::market_for(initial_price, volatility, interval);
+            if let Some(tick_size) = settings.tick_size {
+                // Keep orders spread over the same price range
+                market.mean_depth = (market.mean_depth * market.tick_size / tick_size).max(5.0);
+                market.tick_size = tick_size;
+            }
+            market.order_rate = settings.order_rate.unwrap_or(market.order_rate);
+            market.market_order_rate = settings.market_order_rate.unwrap_or(market.market_order_rate);
+            market.cancel_rate = settings.cancel_rate.unwrap_or(market.cancel_rate);
+            let instrument = Instrument {
+                subject,
+                pair: pair.clone(),
+                initial_price,
+                volatility,
+                interval,
+                correlation: settings.correlation.unwrap_or(self.correlation),
+                market,
+            };
+            validate_instrument(&instrument).map_err(|e| format!("simulated instrument {}: {}", pair, e))?;
+            for channel in Channel::ALL {
+                let subject = instrument.subject(channel);
+                if !subjects.insert(subject.clone()) {
+                    return Err(format!("simulated subject '{}' is used twice", subject));
+                }
+            }
+            instruments.push(instrument);
+        }
+        Ok(instruments)
+    }
+}
+
+fn expand_template(template: &str, pair: &str) -> String {
+    let pair = pair.to_ascii_lowercase();
+    let (base, quote) = pair.split_once('-').unwrap_or((&pair, ""));
+    template
+        .replace("{pair}", &pair)
+        .replace("{base}", base)
+        .replace("{quote}", quote)
+}
+
+fn validate_instrument(instrument: &Instrument) -> Result<(), String> {
+    if !instrument.subject.contains("{channel}") {
+        return Err(format!("subject '{}' needs a {{channel}} placeholder", instrument.subject));
+    }
+    for channel in Channel::ALL {
+        let subject = instrument.subject(channel);
+        subject::validate_pattern(&subject).map_err(|e| format!("invalid subject '{}': {}", subject, e))?;
+        if subject.split('.').any(|token| token == "*" || token == ">") {
+            return Err(format!("subject '{}' may not contain wildcards", subject));
+        }
+    }
+    if !instrument.initial_price.is_finite() || instrument.initial_price <= 0.0 {
+        return Err("initial_price must be positive".to_string());
+    }
+    if !(0.0..100.0).contains(&instrument.volatility) {
+        return Err("volatility must be from 0 up to 100 percent".to_string());
+    }
+    if instrument.interval.is_zero() {
+        return Err("interval_ms must be positive".to_string());
+    }
+    if !(-1.0..=1.0).contains(&instrument.correlation) {
+        return Err("correlation must be from -1 to 1".to_string());
+    }
+    let market = &instrument.market;
+    if !(market.tick_size > 0.0 && market.tick_size < instrument.initial_price) {
+        return Err("tick_size must be positive and below initial_price".to_string());
+    }
+    let rates = [market.order_rate, market.market_order_rate, market.cancel_rate];
+    if rates.iter().any(|rate| !rate.is_finite() || *rate < 0.0) {
+        return Err("order_rate, market_order_rate and cancel_rate may not be negative".to_string());
+    }
+    Ok(())
+}
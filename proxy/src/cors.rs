@@ -0,0 +1,47 @@
+// CORS checked against the origins of the current authenticator on every
+// request, so a reloaded allow-list applies straight away; warp's own
+// `cors()` is fixed once the routes are built
+use warp::http::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_MAX_AGE, VARY,
+};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::SharedAuthenticator;
+
+const ALLOWED_METHODS: &str = "GET, POST, OPTIONS";
+const ALLOWED_HEADERS: &str = "Content-Type, Authorization";
+
+// Answer preflight requests, turning away origins that are not allowed
+pub fn preflight(auth: SharedAuthenticator) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::options()
+        .and(warp::header::<String>("origin"))
+        .and(warp::header::<String>("access-control-request-method"))
+        .map(move |origin: String, _method: String| {
+            if auth.current().check_origin(Some(&origin)).is_err() {
+                return StatusCode::FORBIDDEN.into_response();
+            }
+            let mut response = allow_origin(&auth, Some(origin), StatusCode::OK);
+            let headers = response.headers_mut();
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static(ALLOWED_METHODS));
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static(ALLOWED_HEADERS));
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("600"));
+            response
+        })
+}
+
+// Let the browser hand `reply` to scripts on `origin` if it is allowed;
+// otherwise the reply goes out unchanged and the browser withholds it
+pub fn allow_origin(auth: &SharedAuthenticator, origin: Option<String>, reply: impl Reply) -> Response {
+    let mut response = reply.into_response();
+    let allowed = origin
+        .filter(|origin| auth.current().check_origin(Some(origin)).is_ok())
+        .and_then(|origin| HeaderValue::from_str(&origin).ok());
+    if let Some(origin) = allowed {
+        response.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+    response.headers_mut().append(VARY, HeaderValue::from_static("origin"));
+    response
+}
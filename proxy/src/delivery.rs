@@ -0,0 +1,283 @@
+// Batched and conflated delivery. A subscription that asks for either has
+// its messages held back by a `Batcher` and sent every interval, so a
+// client on a busy subject gets a few large frames instead of one frame
+// per message.
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use log::error;
+use market_model::WireError;
+use serde::Serialize;
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+use warp::ws::Message;
+
+use crate::protocol::{self, Delivery, Encoding, NexStreamMessage};
+use crate::queue::ClientQueue;
+
+// A message or reply as one WebSocket frame: text for JSON, binary otherwise
+pub fn frame<T: Serialize>(value: &T, encoding: Encoding) -> Result<Message, WireError> {
+    Ok(to_frame(encoding.encode(value)?, encoding))
+}
+
+// A message along with its encodings, each made the first time it is
+// needed and then shared by every client, snapshot and batch it goes into
+#[derive(Debug)]
+pub struct Encoded {
+    pub message: NexStreamMessage,
+    // JSON, MessagePack and CBOR in that order; None if encoding failed
+    bytes: [OnceLock<Option<Vec<u8>>>; 3],
+}
+
+impl Encoded {
+    pub fn new(message: NexStreamMessage) -> Self {
+        Encoded {
+            message,
+            bytes: Default::default(),
+        }
+    }
+
+    // A message already serialized to JSON
+    pub fn with_json(message: NexStreamMessage, json: &str) -> Self {
+        let encoded = Encoded::new(message);
+        let _ = encoded.bytes[0].set(Some(json.as_bytes().to_vec()));
+        encoded
+    }
+
+    // None if the message cannot be encoded that way
+    pub fn bytes(&self, encoding: Encoding) -> Option<&[u8]> {
+        let slot = match encoding {
+            Encoding::Json => &self.bytes[0],
+            Encoding::Msgpack => &self.bytes[1],
+            Encoding::Cbor => &self.bytes[2],
+        };
+        slot.get_or_init(|| match encoding.encode(&self.message) {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                error!("Failed to encode message on {} as {:?}: {}", self.message.subject, encoding, e);
+                None
+            }
+        })
+        .as_deref()
+    }
+
+    pub fn frame(&self, encoding: Encoding) -> Option<Message> {
+        self.bytes(encoding).map(|bytes| to_frame(bytes.to_vec(), encoding))
+    }
+}
+
+// A `snapshot` or `batch` reply as one frame, put together from the
+// messages' shared encodings rather than encoding them again per client.
+// The envelope matches what serializing the reply would give.
+pub fn envelope(kind: &str, subject: &str, messages: &[Arc<Encoded>], encoding: Encoding) -> Result<Message, WireError> {
+    let items: Vec<&[u8]> = messages.iter().filter_map(|message| message.bytes(encoding)).collect();
+    let json = !encoding.is_binary();
+    let mut bytes = Vec::new();
+    let field = |bytes: &mut Vec<u8>, name: &str| -> Result<(), WireError> {
+        if json && bytes.len() > 1 {
+            bytes.push(b',');
+        }
+        bytes.extend(encoding.encode(&name)?);
+        if json {
+            bytes.push(b':');
+        }
+        Ok(())
+    };
+
+    // A map of four fields
+    bytes.push(match encoding {
+        Encoding::Json => b'{',
+        Encoding::Msgpack => 0x84,
+        Encoding::Cbor => 0xa4,
+    });
+    field(&mut bytes, "type")?;
+    bytes.extend(encoding.encode(&kind)?);
+    field(&mut bytes, "subject")?;
+    bytes.extend(encoding.encode(&subject)?);
+    field(&mut bytes, "messages")?;
+    array_header(&mut bytes, encoding, items.len());
+    for (i, item) in items.iter().enumerate() {
+        if json && i > 0 {
+            bytes.push(b',');
+        }
+        bytes.extend_from_slice(item);
+    }
+    if json {
+        bytes.push(b']');
+    }
+    field(&mut bytes, "time")?;
+    bytes.extend(encoding.encode(&protocol::now())?);
+    if json {
+        bytes.push(b'}');
+    }
+    Ok(to_frame(bytes, encoding))
+}
+
+// The start of an array of `len` items
+fn array_header(bytes: &mut Vec<u8>, encoding: Encoding, len: usize) {
+    match encoding {
+        Encoding::Json => bytes.push(b'['),
+        Encoding::Msgpack => match len {
+            0..=15 => bytes.push(0x90 | len as u8),
+            16..=0xffff => {
+                bytes.push(0xdc);
+                bytes.extend((len as u16).to_be_bytes());
+            }
+            _ => {
+                bytes.push(0xdd);
+                bytes.extend((len as u32).to_be_bytes());
+            }
+        },
+        Encoding::Cbor => match len {
+            0..=23 => bytes.push(0x80 | len as u8),
+            24..=0xff => bytes.extend([0x98, len as u8]),
+            0x100..=0xffff => {
+                bytes.push(0x99);
+                bytes.extend((len as u16).to_be_bytes());
+            }
+            _ => {
+                bytes.push(0x9a);
+                bytes.extend((len as u32).to_be_bytes());
+            }
+        },
+    }
+}
+
+fn to_frame(bytes: Vec<u8>, encoding: Encoding) -> Message {
+    if encoding.is_binary() {
+        Message::binary(bytes)
+    } else {
+        Message::text(String::from_utf8(bytes).expect("JSON is UTF-8"))
+    }
+}
+
+// Messages held back on one subscription until the next flush
+#[derive(Debug)]
+pub struct Batcher {
+    pattern: String,
+    // Keep only the latest message on each subject rather than all of them
+    conflate: bool,
+    interval: Duration,
+    encoding: Encoding,
+    // Most messages held back before flushing early, so a long interval
+    // on a busy subject cannot grow without bound
+    max_pending: usize,
+    pending: Mutex<Vec<Arc<Encoded>>>,
+}
+
+impl Batcher {
+    // None for a subscription whose messages are sent as they arrive
+    pub fn new(pattern: &str, delivery: Delivery, encoding: Encoding, max_pending: usize) -> Option<Self> {
+        Some(Batcher {
+            pattern: pattern.to_string(),
+            conflate: matches!(delivery, Delivery::Conflate { .. }),
+            interval: delivery.interval()?,
+            encoding,
+            max_pending: max_pending.max(1),
+            pending: Mutex::new(Vec::new()),
+        })
+    }
+
+    // Flush into `queue` every interval until the batcher is dropped, which
+    // happens when the subscription ends or changes
+    pub fn start(self: &Arc<Self>, queue: Arc<ClientQueue>) {
+        let batcher = Arc::downgrade(self);
+        let period = self.interval;
+        tokio::spawn(async move {
+            let mut ticks = interval_at(Instant::now() + period, period);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                let Some(batcher) = batcher.upgrade() else {
+                    break;
+                };
+                batcher.flush(&queue);
+            }
+        });
+    }
+
+    // Hold a message back, flushing into `queue` straight away once
+    // `max_pending` are waiting
+    pub fn add(&self, message: &Arc<Encoded>, queue: &ClientQueue) {
+        let mut pending = self.pending.lock().unwrap();
+        if self.conflate {
+            if let Some(latest) = pending.iter_mut().find(|m| m.message.subject == message.message.subject) {
+                *latest = message.clone();
+                return;
+            }
+        }
+        pending.push(message.clone());
+        let full = pending.len() >= self.max_pending;
+        drop(pending);
+        if full {
+            self.flush(queue);
+        }
+    }
+
+    // Queue what is held back: one `batch` reply, or when conflating the
+    // latest message on each subject as it would have been sent anyway
+    pub fn flush(&self, queue: &ClientQueue) {
+        let messages = std::mem::take(&mut *self.pending.lock().unwrap());
+        if messages.is_empty() {
+            return;
+        }
+
+        if self.conflate {
+            for message in &messages {
+                if let Some(frame) = message.frame(self.encoding) {
+                    queue.push(&message.message.subject, frame);
+                }
+            }
+            return;
+        }
+
+        let count = messages.len();
+        match envelope("batch", &self.pattern, &messages, self.encoding) {
+            Ok(frame) => queue.push(&self.pattern, frame),
+            Err(e) => error!("Failed to encode a batch of {} messages on {} as {:?}: {}", count, self.pattern, self.encoding, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::protocol::ServerReply;
+    use crate::queue::{Outgoing, QueueConfig, SlowConsumerPolicy};
+
+    #[test]
+    fn envelopes_decode_like_serialized_replies() {
+        let messages: Vec<NexStreamMessage> = (0..20)
+            .map(|price| NexStreamMessage::new("market.btc-usd.trades", json!({ "price": price }), Some(1)))
+            .collect();
+        let encoded: Vec<_> = messages.iter().cloned().map(|message| Arc::new(Encoded::new(message))).collect();
+        for encoding in [Encoding::Json, Encoding::Msgpack, Encoding::Cbor] {
+            for count in [0, 1, messages.len()] {
+                let frame = envelope("snapshot", "market.>", &encoded[..count], encoding).unwrap();
+                let mut spliced: Value = encoding.decode(frame.as_bytes()).unwrap();
+                let reply = ServerReply::snapshot("market.>", messages[..count].to_vec());
+                let mut serialized: Value = encoding.decode(&encoding.encode(&reply).unwrap()).unwrap();
+                spliced["time"].take();
+                serialized["time"].take();
+                assert_eq!(spliced, serialized, "{:?} with {} messages", encoding, count);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn full_batches_flush_without_waiting_for_the_interval() {
+        let queue = ClientQueue::new(QueueConfig { capacity: 3, policy: SlowConsumerPolicy::DropOldest });
+        let batcher = Batcher::new("market.>", Delivery::Batch { interval_ms: 60_000 }, Encoding::Json, 3).unwrap();
+        for price in 0..3 {
+            let message = NexStreamMessage::new("market.btc-usd.trades", json!({ "price": price }), None);
+            batcher.add(&Arc::new(Encoded::new(message)), &queue);
+        }
+        assert!(batcher.pending.lock().unwrap().is_empty());
+        let Some(Outgoing::Message(frame)) = queue.pop().await else {
+            panic!("a batch is queued");
+        };
+        let batch: Value = serde_json::from_str(frame.to_str().unwrap()).unwrap();
+        assert_eq!(batch["messages"].as_array().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+// Where the proxy's messages come from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Source {
+    Simulator,
+    Upstream,
+    #[default]
+    None,
+}
+
+// State of the upstream NATS connection
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamState {
+    // Not connected yet since startup
+    #[default]
+    Connecting,
+    Connected,
+    // Lost or not yet re-established; the supervisor keeps retrying
+    Disconnected,
+    // Given up for good, e.g. on an unusable URL
+    Failed,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Unavailable,
+}
+
+// One dependency's part of a health report
+#[derive(Serialize, Debug, Clone)]
+pub struct Component {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<UpstreamState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_message_age_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl Component {
+    fn new(ok: bool) -> Self {
+        Component {
+            status: if ok { Status::Ok } else { Status::Unavailable },
+            state: None,
+            last_message_age_ms: None,
+            message: None,
+        }
+    }
+}
+
+// Body of `/healthz` and `/readyz`
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    pub status: Status,
+    pub uptime_secs: u64,
+    pub components: BTreeMap<&'static str, Component>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.status == Status::Ok
+    }
+}
+
+// Liveness and readiness state, updated by the message sources
+#[derive(Debug)]
+pub struct Health {
+    source: Source,
+    started: Instant,
+    upstream: Mutex<UpstreamState>,
+    // Milliseconds after `started` of the last message plus one; zero
+    // until the first message arrives
+    last_message: AtomicU64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health::new(Source::None)
+    }
+}
+
+impl Health {
+    pub fn new(source: Source) -> Self {
+        Health {
+            source,
+            started: Instant::now(),
+            upstream: Mutex::new(UpstreamState::default()),
+            last_message: AtomicU64::new(0),
+        }
+    }
+
+    pub fn message_received(&self) {
+        let millis = self.started.elapsed().as_millis() as u64 + 1;
+        self.last_message.store(millis, Ordering::Relaxed);
+    }
+
+    // Record the upstream state, returning the previous one
+    pub fn set_upstream(&self, state: UpstreamState) -> UpstreamState {
+        std::mem::replace(&mut *self.upstream.lock().unwrap(), state)
+    }
+
+    fn last_message_age(&self) -> Option<Duration> {
+        match self.last_message.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(self.started.elapsed().saturating_sub(Duration::from_millis(millis - 1))),
+        }
+    }
+
+    // The process is up and answering
+    pub fn liveness(&self) -> Report {
+        self.report(BTreeMap::new())
+    }
+
+    // Ready when the message source is working. Upstream subscriptions
+    // follow client demand, so without subscriptions no messages are
+    // expected and their age is not held against the proxy.
+    pub fn readiness(&self, has_subscriptions: bool, max_message_age: Duration) -> Report {
+        let mut components = BTreeMap::new();
+        let age = self.last_message_age();
+        let fresh = age.is_some_and(|age| age <= max_message_age);
+
+        match self.source {
+            Source::Simulator => {
+                let mut simulator = Component::new(fresh);
+                simulator.last_message_age_ms = age.map(|age| age.as_millis() as u64);
+                if !fresh {
+                    simulator.message = Some("simulator has not produced a message recently".to_string());
+                }
+                components.insert("simulator", simulator);
+            }
+            Source::Upstream => {
+                let state = *self.upstream.lock().unwrap();
+                let mut upstream = Component::new(state == UpstreamState::Connected);
+                upstream.state = Some(state);
+                components.insert("upstream", upstream);
+
+                let mut messages = Component::new(fresh || !has_subscriptions);
+                messages.last_message_age_ms = age.map(|age| age.as_millis() as u64);
+                if !has_subscriptions {
+                    messages.message = Some("no subscriptions".to_string());
+                } else if !fresh {
+                    messages.message = Some(format!("no message within {}s", max_message_age.as_secs()));
+                }
+                components.insert("messages", messages);
+            }
+            Source::None => {}
+        }
+
+        self.report(components)
+    }
+
+    fn report(&self, components: BTreeMap<&'static str, Component>) -> Report {
+        let ok = components.values().all(|component| component.status == Status::Ok);
+        Report {
+            status: if ok { Status::Ok } else { Status::Unavailable },
+            uptime_secs: self.started.elapsed().as_secs(),
+            components,
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::delivery::Encoded;
+use crate::subject;
+
+// Subjects are spread over this many independently locked shards, so
+// publishers on different subjects record without contending
+const SHARDS: usize = 16;
+
+type Entries = VecDeque<(Instant, u64, Arc<Encoded>)>;
+
+// One shard's subjects, and the sequence number given to its latest
+// message. Numbering across the shard rather than per subject keeps it
+// increasing on a subject that was evicted and came back.
+#[derive(Debug, Default)]
+struct Shard {
+    subjects: HashMap<String, Entries>,
+    sequence: u64,
+}
+
+// Recent messages on the subjects matching a pattern, plus the sequence
+// number of the latest message recorded on each of those subjects when it
+// was taken
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub messages: Vec<Arc<Encoded>>,
+    latest: HashMap<String, u64>,
+}
+
+impl Snapshot {
+    // Whether a message had been recorded by the time the snapshot was
+    // taken, so it is either in the snapshot or older than all of it
+    pub fn covers(&self, subject: &str, sequence: u64) -> bool {
+        self.latest.get(subject).is_some_and(|latest| sequence <= *latest)
+    }
+}
+
+// Recent messages per subject, bounded by count and age, used to give new
+// subscribers a snapshot instead of an empty chart. Publishers can make up
+// any number of subjects, so the number kept is capped too, and the subject
+// that has gone longest without a message makes way for a new one.
+#[derive(Debug)]
+pub struct History {
+    shards: Vec<Mutex<Shard>>,
+    max_messages: usize,
+    max_age: Duration,
+    // Subjects kept per shard (0 means no cap)
+    max_subjects_per_shard: usize,
+    // Most messages in one snapshot (0 means no cap)
+    max_snapshot: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(0, Duration::ZERO)
+    }
+}
+
+impl History {
+    // A `max_messages` of zero disables history
+    pub fn new(max_messages: usize, max_age: Duration) -> Self {
+        History {
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard::default())).collect(),
+            max_messages,
+            max_age,
+            max_subjects_per_shard: 0,
+            max_snapshot: 0,
+        }
+    }
+
+    // Keep about `max_subjects` subjects in all, spread over the shards
+    pub fn with_max_subjects(mut self, max_subjects: usize) -> Self {
+        self.max_subjects_per_shard = max_subjects.div_ceil(SHARDS);
+        self
+    }
+
+    // Send at most the newest `max_snapshot` messages in one snapshot
+    pub fn with_max_snapshot(mut self, max_snapshot: usize) -> Self {
+        self.max_snapshot = max_snapshot;
+        self
+    }
+
+    // Keep a message, returning its sequence number, which only ever
+    // increases on a subject; 0 when history is disabled
+    pub fn record(&self, encoded: &Arc<Encoded>) -> u64 {
+        if self.max_messages == 0 {
+            return 0;
+        }
+        let message = &encoded.message;
+
+        let now = Instant::now();
+        let mut shard = self.shard(&message.subject).lock().unwrap();
+        let shard = &mut *shard;
+        if self.max_subjects_per_shard > 0
+            && shard.subjects.len() >= self.max_subjects_per_shard
+            && !shard.subjects.contains_key(&message.subject)
+        {
+            Self::evict_stalest(&mut shard.subjects);
+        }
+        shard.sequence += 1;
+        let entries = match shard.subjects.get_mut(&message.subject) {
+            Some(entries) => entries,
+            None => shard.subjects.entry(message.subject.clone()).or_default(),
+        };
+        entries.push_back((now, shard.sequence, encoded.clone()));
+        while entries.len() > self.max_messages {
+            entries.pop_front();
+        }
+        Self::expire(entries, now, self.max_age);
+        shard.sequence
+    }
+
+    // Messages on every subject matching `pattern`, oldest first, keeping
+    // the newest when there are more than a snapshot may hold
+    pub fn snapshot(&self, pattern: &str) -> Snapshot {
+        if self.max_messages == 0 {
+            return Snapshot::default();
+        }
+
+        let now = Instant::now();
+        let mut messages: Vec<(Instant, Arc<Encoded>)> = Vec::new();
+        let mut latest = HashMap::new();
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.subjects.retain(|_, entries| {
+                Self::expire(entries, now, self.max_age);
+                !entries.is_empty()
+            });
+            for (subject, entries) in shard.subjects.iter().filter(|(subject, _)| subject::matches(pattern, subject)) {
+                if let Some((_, sequence, _)) = entries.back() {
+                    latest.insert(subject.clone(), *sequence);
+                }
+                messages.extend(entries.iter().map(|(received, _, message)| (*received, message.clone())));
+            }
+        }
+        messages.sort_by_key(|(received, _)| *received);
+        let skip = match self.max_snapshot {
+            0 => 0,
+            max => messages.len().saturating_sub(max),
+        };
+        Snapshot {
+            messages: messages.into_iter().skip(skip).map(|(_, message)| message).collect(),
+            latest,
+        }
+    }
+
+    fn shard(&self, subject: &str) -> &Mutex<Shard> {
+        &self.shards[subject::shard_index(subject, self.shards.len())]
+    }
+
+    // Drop the subject whose latest message is oldest
+    fn evict_stalest(shard: &mut HashMap<String, Entries>) {
+        let stalest = shard
+            .iter()
+            .min_by_key(|(_, entries)| entries.back().map(|(received, _, _)| *received))
+            .map(|(subject, _)| subject.clone());
+        if let Some(subject) = stalest {
+            shard.remove(&subject);
+        }
+    }
+
+    fn expire(entries: &mut Entries, now: Instant, max_age: Duration) {
+        while let Some((received, _, _)) = entries.front() {
+            if now.duration_since(*received) <= max_age {
+                break;
+            }
+            entries.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::protocol::NexStreamMessage;
+
+    fn message(subject: &str, price: u64) -> Arc<Encoded> {
+        Arc::new(Encoded::new(NexStreamMessage::new(subject, json!({ "price": price }), None)))
+    }
+
+    #[test]
+    fn snapshots_keep_the_newest_messages() {
+        let history = History::new(10, Duration::from_secs(60)).with_max_snapshot(3);
+        for price in 0..5 {
+            history.record(&message("market.btc-usd.trades", price));
+            history.record(&message("market.eth-usd.trades", price));
+        }
+        let mut prices: Vec<u64> = history.snapshot("market.*.trades").messages.iter().map(|m| m.message.data["price"].as_u64().unwrap()).collect();
+        prices.sort();
+        assert_eq!(prices, vec![3, 4, 4]);
+        assert_eq!(history.snapshot("market.btc-usd.trades").messages.len(), 3);
+    }
+
+    #[test]
+    fn snapshots_cover_what_was_recorded_before_them() {
+        let history = History::new(10, Duration::from_secs(60));
+        let first = history.record(&message("market.btc-usd.trades", 1));
+        let snapshot = history.snapshot("market.>");
+        let second = history.record(&message("market.btc-usd.trades", 2));
+        assert!(second > first);
+        assert!(snapshot.covers("market.btc-usd.trades", first));
+        assert!(!snapshot.covers("market.btc-usd.trades", second));
+        assert!(!snapshot.covers("market.eth-usd.trades", first));
+    }
+
+    #[test]
+    fn the_stalest_subject_makes_way_for_a_new_one() {
+        let history = History::new(10, Duration::from_secs(60)).with_max_subjects(SHARDS);
+        let subjects: Vec<String> = (0..SHARDS * 4).map(|i| format!("market.pair-{}.trades", i)).collect();
+        for subject in &subjects {
+            history.record(&message(subject, 1));
+        }
+        let kept = subjects.iter().filter(|subject| !history.snapshot(subject).messages.is_empty()).count();
+        assert!(kept <= SHARDS, "{} subjects kept", kept);
+
+        // The latest subject always has room
+        history.record(&message("market.new.trades", 1));
+        assert_eq!(history.snapshot("market.new.trades").messages.len(), 1);
+        let total: usize = history.shards.iter().map(|shard| shard.lock().unwrap().subjects.len()).sum();
+        assert!(total <= SHARDS, "{} subjects kept", total);
+    }
+}
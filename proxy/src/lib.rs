@@ -0,0 +1,20 @@
+// NEX Stream proxy internals, shared by the server binary, the benchmarks
+// and the integration tests
+pub mod auth;
+pub mod config;
+pub mod cors;
+pub mod delivery;
+pub mod health;
+pub mod history;
+pub mod limits;
+pub mod metrics;
+pub mod protocol;
+pub mod publish;
+pub mod queue;
+pub mod registry;
+pub mod server;
+pub mod shutdown;
+pub mod simulator;
+pub mod subject;
+pub mod tls;
+pub mod upstream;
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Token bucket allowing `rate` events per second with bursts of `burst`
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    // Take a token if one is available
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// Open connections per remote address, capped at `max_per_ip` (0 means
+// no cap). Lowering the cap turns away new connections only.
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    max_per_ip: AtomicUsize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: usize) -> Self {
+        ConnectionLimiter {
+            max_per_ip: AtomicUsize::new(max_per_ip),
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn max_per_ip(&self) -> usize {
+        self.max_per_ip.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_per_ip(&self, max_per_ip: usize) {
+        self.max_per_ip.store(max_per_ip, Ordering::Relaxed);
+    }
+
+    // Count a new connection from `ip`; the slot is freed when the guard
+    // is dropped. Returns None if the address is already at its cap.
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_insert(0);
+        let max_per_ip = self.max_per_ip();
+        if max_per_ip > 0 && *count >= max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+}
+
+// One connection's slot in a `ConnectionLimiter`
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
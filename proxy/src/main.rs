@@ -1,468 +1,92 @@
-use std::sync::{Arc, Mutex};
+use std::ffi::OsString;
 use std::time::Duration;
-use std::collections::HashMap;
-use std::error::Error as StdError;
-use std::path::Path;
 
-use tokio::sync::mpsc;
-use tokio::time::interval;
-use warp::{Filter, ws::{Message, WebSocket}};
-use futures::{StreamExt, SinkExt};
-use serde::{Serialize, Deserialize};
-use rand::{Rng, thread_rng};
-use chrono::Utc;
-use log::{info, error, warn, debug};
-use clap::Parser;
-use url::Url;
-use async_nats::jetstream;
+use log::{info, error, warn};
 
-// Command line arguments
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Port to listen on for WebSocket proxy
-    #[arg(short, long, default_value_t = 3030)]
-    proxy_port: u16,
-
-    /// Port to listen on for HTTP server
-    #[arg(short = 'P', long, default_value_t = 54572)]
-    http_port: u16,
-
-    /// Enable simulated data mode
-    #[arg(short, long)]
-    simulate: bool,
-
-    /// Real NEX Stream URL (if not simulating)
-    #[arg(short, long, default_value = "")]
-    nex_url: String,
-    
-    /// Directory to serve static files from
-    #[arg(short, long, default_value = "../")]
-    static_dir: String,
-}
-
-// NEX Stream message structure
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct NexStreamMessage {
-    subject: String,
-    data: serde_json::Value,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    timestamp: Option<u64>,
-}
-
-// Client subscription information
-#[derive(Debug, Clone)]
-struct Client {
-    id: String,
-    subscriptions: Vec<String>,
-    tx: mpsc::UnboundedSender<Result<Message, warp::Error>>,
-}
-
-// Shared state
-type Clients = Arc<Mutex<HashMap<String, Client>>>;
+use rt_duckdb_coinbase_server::server::{load_args, Server};
+#[cfg(unix)]
+use rt_duckdb_coinbase_server::server::Reloader;
 
 #[tokio::main]
 async fn main() {
     // Initialize logging
     env_logger::init();
-    
-    // Parse command line arguments
-    let args = Args::parse();
-    
-    // Shared state
-    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
-    
-    // Clone for data generator
-    let clients_for_generator = clients.clone();
-    
-    // Start data generator if in simulation mode
-    if args.simulate {
-        info!("Starting in simulation mode");
-        tokio::spawn(generate_simulated_data(clients_for_generator));
-    } else if !args.nex_url.is_empty() {
-        info!("Connecting to real NEX Stream at: {}", args.nex_url);
-        let nex_url = args.nex_url.clone();
-        tokio::spawn(async move {
-            if let Err(e) = connect_to_nex_stream(nex_url, clients_for_generator).await {
-                error!("NEX Stream connection error: {}", e);
-            }
-        });
-    } else {
-        warn!("No NEX Stream URL provided and simulation disabled. Proxy will only relay WebSocket connections.");
+
+    // Parse command line arguments over the configuration file and environment
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let (args, config) = load_args(&argv).unwrap_or_else(|e| e.exit());
+    if let Some(path) = &args.config {
+        info!("Loaded configuration from {}", path);
     }
-    
-    // WebSocket route
-    let ws_route = warp::path("ws")
-        .and(warp::ws())
-        .and(with_clients(clients.clone()))
-        .map(|ws: warp::ws::Ws, clients| {
-            ws.on_upgrade(move |socket| handle_connection(socket, clients))
-        });
-    
-    // CORS configuration
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_methods(vec!["GET", "POST", "OPTIONS"])
-        .allow_headers(vec!["Content-Type", "Authorization"]);
-    
-    // Health check route
-    let health_route = warp::path("health")
-        .map(|| "NEX Stream Proxy is running");
-    
-    // Combine routes for proxy server
-    let proxy_routes = ws_route
-        .or(health_route)
-        .with(cors.clone())
-        .with(warp::log("nex_proxy"));
-    
-    // Start the proxy server
-    info!("Starting NEX Stream proxy server on port {}", args.proxy_port);
-    let proxy_server = warp::serve(proxy_routes)
-        .run(([0, 0, 0, 0], args.proxy_port));
-    
-    // Create static file server
-    let static_dir = args.static_dir.clone();
-    info!("Serving static files from directory: {}", static_dir);
-    
-    // Favicon handler - simplified approach
-    let static_dir_clone = static_dir.clone();
-    let favicon_path = Path::new(&static_dir_clone).join("favicon.ico");
-    let favicon_path_str = favicon_path.to_string_lossy().to_string();
-    
-    info!("Favicon path: {}", favicon_path_str);
-    
-    // Create a simple route that serves the favicon file directly
-    let favicon_route = warp::path("favicon.ico")
-        .and(warp::get())
-        .map(move || {
-            if favicon_path.exists() {
-                info!("Serving favicon from: {}", favicon_path_str);
-                warp::reply::with_header(
-                    warp::reply::html("<link rel='icon' href='data:;base64,iVBORw0KGgo='>"),
-                    "Content-Type", 
-                    "text/html"
-                )
-            } else {
-                warn!("Favicon not found at: {}", favicon_path_str);
-                warp::reply::with_header(
-                    warp::reply::html("<link rel='icon' href='data:;base64,iVBORw0KGgo='>"),
-                    "Content-Type",
-                    "text/html"
-                )
-            }
-        });
-    
-    // JavaScript files handler
-    let static_dir_for_js = static_dir.clone();
-    let js_files = warp::path::tail()
-        .and_then(move |tail: warp::path::Tail| {
-            let static_dir = static_dir_for_js.clone();
-            async move {
-                let path_str = tail.as_str();
-                let file_path = Path::new(&static_dir).join(path_str);
-                
-                if path_str.ends_with(".js") {
-                    match tokio::fs::read(&file_path).await {
-                        Ok(content) => {
-                            Ok(warp::reply::with_header(
-                                content,
-                                "Content-Type",
-                                "application/javascript",
-                            ))
-                        },
-                        Err(e) => {
-                            error!("Failed to read JS file: {}", e);
-                            Err(warp::reject::not_found())
-                        }
-                    }
-                } else if path_str.ends_with(".wasm") {
-                    match tokio::fs::read(&file_path).await {
-                        Ok(content) => {
-                            Ok(warp::reply::with_header(
-                                content,
-                                "Content-Type",
-                                "application/wasm",
-                            ))
-                        },
-                        Err(e) => {
-                            error!("Failed to read WASM file: {}", e);
-                            Err(warp::reject::not_found())
-                        }
-                    }
-                } else {
-                    Err(warp::reject::not_found())
-                }
-            }
-        });
-    
-    // Static file server with custom MIME type handling
-    let static_routes = warp::fs::dir(static_dir)
-        .or(favicon_route)
-        .or(js_files)
-        .with(cors)
-        .with(warp::log("static_server"));
-    
-    // Start the static file server
-    info!("Starting static file server on port {}", args.http_port);
-    let static_server = warp::serve(static_routes)
-        .run(([0, 0, 0, 0], args.http_port));
-    
-    // Run both servers concurrently
+    let deadline = Duration::from_secs(args.shutdown_timeout);
+
+    let server = match Server::start(args, config).await {
+        Ok(server) => server,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Apply configuration changes on SIGHUP
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(argv, server.reloader()));
+
+    // Run both servers until told to stop
     info!("Both servers are running. Press Ctrl+C to stop.");
-    futures::join!(proxy_server, static_server);
-}
+    wait_for_signal().await;
 
-// Helper function to pass clients to route handlers
-fn with_clients(clients: Clients) -> impl Filter<Extract = (Clients,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || clients.clone())
+    // Wind down within the deadline; a second Ctrl+C gives up on it
+    let clients = server.clients().clone();
+    tokio::select! {
+        result = tokio::time::timeout(deadline, server.stop()) => match result {
+            Ok(()) => info!("Shutdown complete"),
+            Err(_) => warn!("Shutdown deadline passed with {} clients still connected", clients.client_count()),
+        },
+        _ = tokio::signal::ctrl_c() => warn!("Shutdown interrupted with {} clients still connected", clients.client_count()),
+    }
 }
 
-// Handle WebSocket connection
-async fn handle_connection(ws: WebSocket, clients: Clients) {
-    // Split the socket into sender and receiver
-    let (ws_tx, mut ws_rx) = ws.split();
-    
-    // Use an unbounded channel to handle buffering and flushing of messages
-    let (tx, mut rx) = mpsc::unbounded_channel();
-    
-    // Generate a client ID
-    let client_id = format!("client-{}", rand::thread_rng().gen::<u32>());
-    info!("New client connected: {}", client_id);
-    
-    // Create a new client
-    let client = Client {
-        id: client_id.clone(),
-        subscriptions: vec![],
-        tx,
-    };
-    
-    // Add the client to our map
-    clients.lock().unwrap().insert(client_id.clone(), client);
-    
-    // Forward messages from rx to the WebSocket
-    tokio::task::spawn(async move {
-        let mut ws_tx = ws_tx;
-        while let Some(message) = rx.recv().await {
-            match message {
-                Ok(msg) => {
-                    if let Err(e) = ws_tx.send(msg).await {
-                        error!("WebSocket send error: {}", e);
-                        break;
-                    }
-                },
-                Err(e) => {
-                    error!("Message error: {}", e);
-                    break;
-                }
-            }
-        }
-    });
-    
-    // Handle incoming messages
-    while let Some(result) = ws_rx.next().await {
-        match result {
-            Ok(msg) => {
-                // Process the message
-                if let Err(e) = process_message(msg, &client_id, &clients).await {
-                    error!("Error processing message: {}", e);
-                    break;
-                }
-            }
+// Wait for SIGINT (Ctrl+C) or, on Unix, SIGTERM
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            },
             Err(e) => {
-                error!("WebSocket error: {}", e);
-                break;
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
             }
         }
-    }
-    
-    // Client disconnected
-    info!("Client disconnected: {}", client_id);
-    clients.lock().unwrap().remove(&client_id);
-}
-
-// Process incoming WebSocket messages
-async fn process_message(msg: Message, client_id: &str, clients: &Clients) -> Result<(), Box<dyn StdError>> {
-    // Skip processing non-text messages
-    if !msg.is_text() {
-        return Ok(());
-    }
-    
-    let text = match msg.to_str() {
-        Ok(text) => text,
-        Err(_) => return Err("Message is not valid UTF-8".into()),
     };
-    info!("Received message from {}: {}", client_id, text);
-    
-    // Try to parse as a subscription message
-    if let Ok(subscription) = serde_json::from_str::<serde_json::Value>(text) {
-        if let Some(action) = subscription.get("action").and_then(|a| a.as_str()) {
-            if action == "subscribe" {
-                if let Some(subject) = subscription.get("subject").and_then(|s| s.as_str()) {
-                    // Add subscription
-                    let mut clients_lock = clients.lock().unwrap();
-                    if let Some(client) = clients_lock.get_mut(client_id) {
-                        client.subscriptions.push(subject.to_string());
-                        info!("Client {} subscribed to {}", client_id, subject);
-                        
-                        // Send confirmation
-                        let confirmation = serde_json::json!({
-                            "type": "subscription_confirmed",
-                            "subject": subject,
-                            "time": Utc::now().to_rfc3339()
-                        });
-                        
-                        client.tx.send(Ok(Message::text(confirmation.to_string())))?;
-                    }
-                }
-            }
-        }
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        _ = terminate => info!("Received SIGTERM, shutting down"),
     }
-    
-    Ok(())
 }
 
-// Connect to a real NEX Stream and forward messages to clients
-async fn connect_to_nex_stream(nex_url: String, clients: Clients) -> Result<(), Box<dyn StdError>> {
-    info!("Connecting to NEX Stream at {}", nex_url);
-    
-    // Parse the URL to extract credentials if present
-    let url = Url::parse(&nex_url)?;
-    
-    // Extract username and password if present in the URL
-    let username = url.username();
-    let password = url.password().unwrap_or("");
-    
-    // Build connection options
-    let mut options = async_nats::ConnectOptions::new();
-    
-    // Add authentication if credentials are provided
-    if !username.is_empty() {
-        options = async_nats::ConnectOptions::with_user_and_password(username.to_string(), password.to_string());
-    }
-    
-    // Connect to NATS server
-    info!("Establishing connection to NATS server...");
-    let client = match options.connect(&nex_url).await {
-        Ok(client) => {
-            info!("Successfully connected to NATS server");
-            client
-        },
+// Reread the configuration on every SIGHUP, keeping the current one if the
+// new one does not load
+#[cfg(unix)]
+async fn reload_on_hangup(argv: Vec<OsString>, reloader: Reloader) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => signal,
         Err(e) => {
-            error!("Failed to connect to NATS server: {}", e);
-            return Err(Box::new(e));
+            error!("Failed to listen for SIGHUP: {}", e);
+            return;
         }
     };
-    
-    // Create JetStream context if available
-    let jetstream = jetstream::new(client.clone());
-    
-    // Subscribe to market data
-    let mut subscriber = match client.subscribe("market.btc-usd.trades".to_string()).await {
-        Ok(sub) => {
-            info!("Successfully subscribed to market.btc-usd.trades");
-            sub
-        },
-        Err(e) => {
-            error!("Failed to subscribe to market data: {}", e);
-            return Err(Box::new(e));
-        }
-    };
-    
-    // Process incoming messages
-    info!("Listening for messages from NEX Stream...");
-    while let Some(msg) = subscriber.next().await {
-        let payload = String::from_utf8_lossy(&msg.payload);
-        debug!("Received message: {}", payload);
-        
-        // Try to parse the message
-        match serde_json::from_str::<serde_json::Value>(&payload) {
-            Ok(data) => {
-                // Create a NEX Stream message
-                let now = Utc::now();
-                let timestamp = now.timestamp_millis() as u64;
-                
-                let message = NexStreamMessage {
-                    subject: msg.subject.to_string(),
-                    data: data,
-                    timestamp: Some(timestamp),
-                };
-                
-                // Serialize to JSON
-                match serde_json::to_string(&message) {
-                    Ok(message_json) => {
-                        // Send to all subscribed clients
-                        let clients_lock = clients.lock().unwrap();
-                        for (_, client) in clients_lock.iter() {
-                            if client.subscriptions.contains(&message.subject) || 
-                               client.subscriptions.contains(&"*".to_string()) {
-                                if let Err(e) = client.tx.send(Ok(Message::text(&message_json))) {
-                                    error!("Error sending message to client: {}", e);
-                                }
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        error!("Failed to serialize message: {}", e);
-                    }
-                }
-            },
-            Err(e) => {
-                error!("Failed to parse message: {}", e);
-            }
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading configuration");
+        if let Err(e) = reloader.reload(&argv) {
+            // Only the first line; the rest of a clap error is a hint about --help
+            let e = e.to_string();
+            error!("Keeping the current configuration: {}", e.lines().next().unwrap_or_default());
         }
     }
-    
-    info!("NEX Stream connection closed");
-    Ok(())
 }
-
-// Generate simulated NEX Stream data
-async fn generate_simulated_data(clients: Clients) {
-    let mut interval = interval(Duration::from_millis(1000));
-    
-    // Initial price (using thread_rng in a way that doesn't hold it across awaits)
-    let mut price = 30000.0 + thread_rng().gen_range(0.0..2000.0);
-    
-    loop {
-        interval.tick().await;
-        
-        // Update price with some random movement (create a new rng each time)
-        let change_pct = thread_rng().gen_range(-0.5..0.5);
-        price = price * (1.0 + change_pct / 100.0);
-        
-        // Create NEX Stream message
-        let now = Utc::now();
-        let timestamp = now.timestamp_millis() as u64;
-        
-        // Create a new rng for each use
-        let mut rng = thread_rng();
-        let size = rng.gen_range(0.001..1.0);
-        let side = if rng.gen_bool(0.5) { "buy" } else { "sell" };
-        
-        let message = NexStreamMessage {
-            subject: "market.btc-usd.trades".to_string(),
-            data: serde_json::json!({
-                "price": price,
-                "size": size,
-                "side": side,
-                "exchange": "nex",
-                "pair": "BTC-USD"
-            }),
-            timestamp: Some(timestamp),
-        };
-        
-        // Serialize to JSON
-        let message_json = serde_json::to_string(&message).unwrap();
-        
-        // Send to all subscribed clients
-        let clients_lock = clients.lock().unwrap();
-        for (_, client) in clients_lock.iter() {
-            if client.subscriptions.contains(&message.subject) || 
-               client.subscriptions.contains(&"*".to_string()) {
-                if let Err(e) = client.tx.send(Ok(Message::text(&message_json))) {
-                    error!("Error sending message to client: {}", e);
-                }
-            }
-        }
-    }
-}
\ No newline at end of file
@@ -0,0 +1,159 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+use prometheus::core::Collector;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+// Subjects that get series of their own. Publishers can make up any number
+// of subjects, so past this many the rest share the `other` label.
+const MAX_SUBJECT_SERIES: usize = 1000;
+const OTHER_SUBJECTS: &str = "other";
+
+// Prometheus metrics for the proxy, rendered by the `/metrics` route.
+// Message series are labelled with the subject, up to a cap, and
+// subscription series with the subscribed pattern, which only lives as
+// long as a client holds it.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    // Subjects labelled so far
+    subjects: Arc<RwLock<HashSet<String>>>,
+    pub clients: IntGauge,
+    // Clients subscribed to each pattern
+    pub subscriptions: IntGaugeVec,
+    // Messages received from upstream or the simulator, per subject
+    pub messages_in: IntCounterVec,
+    // Messages queued for clients, per subject
+    pub messages_out: IntCounterVec,
+    // Messages dropped by the slow-consumer policy, per subject
+    pub dropped: IntCounterVec,
+    pub upstream_connected: IntGauge,
+    pub upstream_reconnects: IntCounter,
+    // Time taken to route one message to every matching client
+    pub fanout_duration: Histogram,
+    // Requests refused by a limit, labelled `rate`, `subscriptions` or `connections`
+    pub limit_violations: IntCounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let subject = &["subject"];
+
+        Metrics {
+            clients: register(
+                &registry,
+                IntGauge::new("nex_proxy_clients", "Connected WebSocket clients"),
+            ),
+            subscriptions: register(
+                &registry,
+                IntGaugeVec::new(Opts::new("nex_proxy_subscriptions", "Clients subscribed to each pattern"), subject),
+            ),
+            messages_in: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("nex_proxy_messages_in_total", "Messages received for fan-out"),
+                    subject,
+                ),
+            ),
+            messages_out: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("nex_proxy_messages_out_total", "Messages queued for clients"),
+                    subject,
+                ),
+            ),
+            dropped: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("nex_proxy_messages_dropped_total", "Messages dropped for slow clients"),
+                    subject,
+                ),
+            ),
+            upstream_connected: register(
+                &registry,
+                IntGauge::new("nex_proxy_upstream_connected", "Whether the upstream NATS connection is up"),
+            ),
+            upstream_reconnects: register(
+                &registry,
+                IntCounter::new("nex_proxy_upstream_reconnects_total", "Upstream NATS reconnections"),
+            ),
+            fanout_duration: register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new("nex_proxy_fanout_duration_seconds", "Time to route a message to all clients")
+                        .buckets(exponential_buckets(0.000_001, 4.0, 10).expect("valid buckets")),
+                ),
+            ),
+            subjects: Arc::new(RwLock::new(HashSet::new())),
+            limit_violations: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("nex_proxy_limit_violations_total", "Client requests refused by a limit"),
+                    &["limit"],
+                ),
+            ),
+            registry,
+        }
+    }
+
+    // The label to count a message on `subject` under: the subject itself
+    // while fewer than `MAX_SUBJECT_SERIES` have been seen, `other` after
+    pub fn subject<'a>(&self, subject: &'a str) -> &'a str {
+        if self.subjects.read().unwrap().contains(subject) {
+            return subject;
+        }
+        let mut subjects = self.subjects.write().unwrap();
+        if subjects.contains(subject) {
+            return subject;
+        }
+        if subjects.len() < MAX_SUBJECT_SERIES {
+            subjects.insert(subject.to_string());
+            return subject;
+        }
+        OTHER_SUBJECTS
+    }
+
+    // Current values in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics encode");
+        String::from_utf8(buffer).expect("metrics are UTF-8")
+    }
+}
+
+fn register<T: Collector + Clone + 'static>(registry: &Registry, metric: prometheus::Result<T>) -> T {
+    let metric = metric.expect("valid metric");
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric registered once");
+    metric
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subjects_past_the_cap_share_one_series() {
+        let metrics = Metrics::new();
+        for i in 0..MAX_SUBJECT_SERIES + 10 {
+            let subject = format!("market.pair-{}.trades", i);
+            metrics.messages_in.with_label_values(&[metrics.subject(&subject)]).inc();
+        }
+        assert_eq!(metrics.subject("market.pair-0.trades"), "market.pair-0.trades");
+        assert_eq!(metrics.subject("market.new.trades"), OTHER_SUBJECTS);
+        assert_eq!(metrics.messages_in.with_label_values(&[OTHER_SUBJECTS]).get(), 10);
+    }
+}
@@ -0,0 +1,309 @@
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::health::UpstreamState;
+
+// NEX Stream message structure, shared with the publishers and web client
+pub use market_model::Envelope as NexStreamMessage;
+
+// Encodings a subscription may ask for its messages in
+pub use market_model::Encoding;
+
+// Control messages sent by WebSocket clients, tagged by `action`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ClientRequest {
+    Subscribe {
+        subject: String,
+        #[serde(default)]
+        replay: Option<ReplaySpec>,
+        // Ask for `heartbeat` messages on this subscription, for clients
+        // behind proxies that strip WebSocket pings
+        #[serde(default)]
+        heartbeat: bool,
+        // How messages on this subscription are encoded: `json` in text
+        // frames, or `msgpack` or `cbor` in binary frames
+        #[serde(default)]
+        encoding: Encoding,
+        // Whether messages are sent as they arrive, in batches, or
+        // conflated to the latest on each subject
+        #[serde(default)]
+        delivery: Delivery,
+    },
+    Unsubscribe { subject: String },
+    UnsubscribeAll,
+    List,
+    // Sent by clients to show they are alive; needs no reply
+    Heartbeat,
+}
+
+// History a client wants before live delivery starts:
+// `{"since": <RFC 3339 time or epoch millis>}` or `{"last": N}`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplaySpec {
+    Since(ReplayStart),
+    Last(u64),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ReplayStart {
+    EpochMillis(i64),
+    Time(String),
+}
+
+impl ReplayStart {
+    pub fn to_datetime(&self) -> Result<DateTime<Utc>, String> {
+        match self {
+            ReplayStart::EpochMillis(ms) => Utc
+                .timestamp_millis_opt(*ms)
+                .single()
+                .ok_or_else(|| format!("Invalid replay start {}", ms)),
+            ReplayStart::Time(time) => DateTime::parse_from_rfc3339(time)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(|e| format!("Invalid replay start '{}': {}", time, e)),
+        }
+    }
+}
+
+// How a subscription's messages are sent: each as it arrives,
+// `{"mode": "batch", "interval_ms": N}` for everything from the last N ms
+// together in one `batch` reply, or `{"mode": "conflate", "interval_ms": N}`
+// for only the latest message on each subject every N ms
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Delivery {
+    #[default]
+    Immediate,
+    Batch { interval_ms: u64 },
+    Conflate { interval_ms: u64 },
+}
+
+// Bounds on a batched or conflated subscription's interval
+pub const MIN_DELIVERY_INTERVAL_MS: u64 = 10;
+pub const MAX_DELIVERY_INTERVAL_MS: u64 = 60_000;
+
+impl Delivery {
+    // How often held-back messages are sent, or None when none are held back
+    pub fn interval(&self) -> Option<Duration> {
+        match *self {
+            Delivery::Immediate => None,
+            Delivery::Batch { interval_ms } | Delivery::Conflate { interval_ms } => {
+                Some(Duration::from_millis(interval_ms))
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.interval() {
+            Some(interval)
+                if interval < Duration::from_millis(MIN_DELIVERY_INTERVAL_MS)
+                    || interval > Duration::from_millis(MAX_DELIVERY_INTERVAL_MS) =>
+            {
+                Err(format!(
+                    "'interval_ms' must be between {} and {}",
+                    MIN_DELIVERY_INTERVAL_MS, MAX_DELIVERY_INTERVAL_MS
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Actions understood by `ClientRequest`, used to tell unknown actions
+// apart from known actions with bad fields
+const ACTIONS: &[&str] = &["subscribe", "unsubscribe", "unsubscribe_all", "list", "heartbeat"];
+
+// Machine-readable codes carried by error replies
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidJson,
+    MissingAction,
+    UnknownAction,
+    InvalidRequest,
+    InvalidSubject,
+    InvalidMessage,
+    NotSubscribed,
+    ReplayUnavailable,
+    ReplayFailed,
+    Unauthorized,
+    Forbidden,
+    RateLimited,
+    SubscriptionLimit,
+    ConnectionLimit,
+}
+
+// Error reply for a control message that could not be handled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl RequestError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        RequestError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// Replies sent back to WebSocket clients, tagged by `type`
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerReply {
+    SubscriptionConfirmed { subject: String, time: String },
+    Unsubscribed { subject: String, time: String },
+    UnsubscribedAll { subjects: Vec<String>, time: String },
+    Subscriptions { subjects: Vec<String>, dropped: u64, time: String },
+    ReplayComplete { subject: String, count: usize, time: String },
+    Snapshot { subject: String, messages: Vec<NexStreamMessage>, time: String },
+    Batch { subject: String, messages: Vec<NexStreamMessage>, time: String },
+    Published { subject: String, count: usize, time: String },
+    Lagged { dropped: u64, total_dropped: u64, time: String },
+    Heartbeat { subject: String, time: String },
+    UpstreamStatus { status: UpstreamState, time: String },
+    Shutdown {
+        reason: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reconnect_url: Option<String>,
+        time: String,
+    },
+    Error { code: ErrorCode, message: String, time: String },
+}
+
+impl ServerReply {
+    pub fn subscription_confirmed(subject: &str) -> Self {
+        ServerReply::SubscriptionConfirmed {
+            subject: subject.to_string(),
+            time: now(),
+        }
+    }
+
+    pub fn unsubscribed(subject: &str) -> Self {
+        ServerReply::Unsubscribed {
+            subject: subject.to_string(),
+            time: now(),
+        }
+    }
+
+    pub fn unsubscribed_all(subjects: Vec<String>) -> Self {
+        ServerReply::UnsubscribedAll { subjects, time: now() }
+    }
+
+    pub fn subscriptions(subjects: Vec<String>, dropped: u64) -> Self {
+        ServerReply::Subscriptions {
+            subjects,
+            dropped,
+            time: now(),
+        }
+    }
+
+    pub fn replay_complete(subject: &str, count: usize) -> Self {
+        ServerReply::ReplayComplete {
+            subject: subject.to_string(),
+            count,
+            time: now(),
+        }
+    }
+
+    pub fn snapshot(subject: &str, messages: Vec<NexStreamMessage>) -> Self {
+        ServerReply::Snapshot {
+            subject: subject.to_string(),
+            messages,
+            time: now(),
+        }
+    }
+
+    pub fn batch(subject: &str, messages: Vec<NexStreamMessage>) -> Self {
+        ServerReply::Batch {
+            subject: subject.to_string(),
+            messages,
+            time: now(),
+        }
+    }
+
+    pub fn published(subject: &str, count: usize) -> Self {
+        ServerReply::Published {
+            subject: subject.to_string(),
+            count,
+            time: now(),
+        }
+    }
+
+    pub fn lagged(dropped: u64, total_dropped: u64) -> Self {
+        ServerReply::Lagged {
+            dropped,
+            total_dropped,
+            time: now(),
+        }
+    }
+
+    pub fn heartbeat(subject: &str) -> Self {
+        ServerReply::Heartbeat {
+            subject: subject.to_string(),
+            time: now(),
+        }
+    }
+
+    pub fn upstream_status(status: UpstreamState) -> Self {
+        ServerReply::UpstreamStatus { status, time: now() }
+    }
+
+    pub fn shutdown(reason: &str, reconnect_url: Option<&str>) -> Self {
+        ServerReply::Shutdown {
+            reason: reason.to_string(),
+            reconnect_url: reconnect_url.map(str::to_string),
+            time: now(),
+        }
+    }
+
+    pub fn error(err: RequestError) -> Self {
+        ServerReply::Error {
+            code: err.code,
+            message: err.message,
+            time: now(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        // Serializing these plain enums cannot fail
+        serde_json::to_string(self).expect("server reply serializes")
+    }
+}
+
+pub fn now() -> String {
+    Utc::now().to_rfc3339()
+}
+
+// Parse a text frame into a control message, classifying failures so the
+// client gets a precise error code back
+pub fn parse_request(text: &str) -> Result<ClientRequest, RequestError> {
+    let value: serde_json::Value = serde_json::from_str(text)
+        .map_err(|e| RequestError::new(ErrorCode::InvalidJson, format!("Malformed JSON: {}", e)))?;
+
+    let action = match value.get("action") {
+        Some(serde_json::Value::String(action)) => action.clone(),
+        Some(_) => return Err(RequestError::new(ErrorCode::MissingAction, "'action' must be a string")),
+        None => return Err(RequestError::new(ErrorCode::MissingAction, "Message has no 'action' field")),
+    };
+
+    if !ACTIONS.contains(&action.as_str()) {
+        return Err(RequestError::new(
+            ErrorCode::UnknownAction,
+            format!("Unknown action '{}'", action),
+        ));
+    }
+
+    serde_json::from_value(value).map_err(|e| {
+        RequestError::new(
+            ErrorCode::InvalidRequest,
+            format!("Invalid '{}' request: {}", action, e),
+        )
+    })
+}
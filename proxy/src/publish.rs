@@ -0,0 +1,199 @@
+// Ingest for producers that are not on NATS. Publishers reach the proxy in
+// three ways:
+//
+// - a WebSocket to `/publish`, sending one envelope per text frame
+// - a WebSocket to `/publish/<subject>`, sending one payload per text frame
+// - `POST /publish/<subject>` with one JSON payload, or an NDJSON batch
+//
+// Every message is checked against the shared market data model and then
+// fanned out to subscribers like a message from upstream or the simulator.
+use chrono::Utc;
+use futures::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use market_model::{MarketData, Validate};
+use serde::Deserialize;
+use serde_json::Value;
+use warp::http::StatusCode;
+use warp::ws::{Message, WebSocket};
+
+use crate::auth::Identity;
+use crate::protocol::{ErrorCode, NexStreamMessage, RequestError, ServerReply};
+use crate::registry::Clients;
+use crate::shutdown::Shutdown;
+use crate::subject;
+
+// Largest body accepted by `POST /publish/<subject>`
+pub const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+// A publish request refused before any of it was fanned out
+#[derive(Debug)]
+pub struct Rejected(pub RequestError);
+
+impl warp::reject::Reject for Rejected {}
+
+impl Rejected {
+    pub fn status(&self) -> StatusCode {
+        match self.0.code {
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+// Check that `subject` names one subject, not a pattern, and that
+// `identity` may publish to it
+pub fn check_subject(subject: &str, identity: &Identity) -> Result<(), RequestError> {
+    subject::validate_subject(subject)
+        .map_err(|e| RequestError::new(ErrorCode::InvalidSubject, format!("Invalid subject '{}': {}", subject, e)))?;
+    if !identity.permissions.allows(subject) {
+        return Err(RequestError::new(
+            ErrorCode::Forbidden,
+            format!("Not permitted to publish to '{}'", subject),
+        ));
+    }
+    Ok(())
+}
+
+// Check a payload is market data that makes sense, and of the kind its
+// subject's channel carries when the subject ends in a known channel name
+pub fn check_payload(subject: &str, data: &Value) -> Result<(), RequestError> {
+    let invalid = |message: String| RequestError::new(ErrorCode::InvalidMessage, message);
+    let market_data = MarketData::deserialize(data)
+        .map_err(|_| invalid("Payload is not a trade, ticker, candle or level 2 message".to_string()))?;
+
+    let channel = subject.rsplit('.').next().unwrap_or_default();
+    let expected = match (channel, &market_data) {
+        ("trades", MarketData::Trade(_))
+        | ("ticker", MarketData::Ticker(_))
+        | ("level2", MarketData::Level2(_))
+        | ("candles", MarketData::Candle(_)) => None,
+        ("trades", _) => Some("a trade"),
+        ("ticker", _) => Some("a ticker"),
+        ("level2", _) => Some("a level 2 message"),
+        ("candles", _) => Some("a candle"),
+        _ => None,
+    };
+    if let Some(expected) = expected {
+        return Err(invalid(format!("Payload on '{}' must be {}", subject, expected)));
+    }
+
+    market_data
+        .validate()
+        .map_err(|e| invalid(format!("Invalid payload: {}", e)))
+}
+
+// Parse one published envelope and check that `identity` may publish it
+pub fn parse_message(text: &str, identity: &Identity) -> Result<NexStreamMessage, RequestError> {
+    let message: NexStreamMessage = serde_json::from_str(text)
+        .map_err(|e| RequestError::new(ErrorCode::InvalidMessage, format!("Invalid message: {}", e)))?;
+
+    check_subject(&message.subject, identity)?;
+    message
+        .validate()
+        .map_err(|e| RequestError::new(ErrorCode::InvalidMessage, format!("Invalid message: {}", e)))?;
+    check_payload(&message.subject, &message.data)?;
+    Ok(message)
+}
+
+// Parse one bare payload for a subject already checked with `check_subject`,
+// wrapping it in an envelope stamped with the time it arrived
+pub fn parse_payload(subject: &str, text: &str) -> Result<NexStreamMessage, RequestError> {
+    let data: Value = serde_json::from_str(text)
+        .map_err(|e| RequestError::new(ErrorCode::InvalidJson, format!("Malformed JSON: {}", e)))?;
+    check_payload(subject, &data)?;
+    let timestamp = Utc::now().timestamp_millis() as u64;
+    Ok(NexStreamMessage::new(subject, data, Some(timestamp)))
+}
+
+// Parse a request body: one payload, or with `ndjson` one per non-empty
+// line. A bad line fails the whole batch, so it is published entirely or
+// not at all.
+pub fn parse_body(subject: &str, body: &str, ndjson: bool) -> Result<Vec<NexStreamMessage>, RequestError> {
+    if !ndjson {
+        return parse_payload(subject, body).map(|message| vec![message]);
+    }
+
+    let mut messages = Vec::new();
+    for (number, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = parse_payload(subject, line)
+            .map_err(|e| RequestError::new(e.code, format!("Line {}: {}", number + 1, e.message)))?;
+        messages.push(message);
+    }
+    if messages.is_empty() {
+        return Err(RequestError::new(ErrorCode::InvalidMessage, "Request body holds no messages"));
+    }
+    Ok(messages)
+}
+
+// Fan out a checked message to every matching subscriber
+pub fn publish(clients: &Clients, message: &NexStreamMessage) {
+    // Serialized again so subscribers always see the current layout
+    let message_json = serde_json::to_string(message).expect("envelope serializes");
+    clients.broadcast(message, &message_json, None);
+}
+
+// Fan out what a publisher sends until it disconnects or the proxy shuts
+// down. With a `subject` the connection is bound to it and each frame is a
+// bare payload; without one each frame is a whole envelope.
+pub async fn handle_publisher(
+    ws: WebSocket,
+    clients: Clients,
+    identity: Identity,
+    subject: Option<String>,
+    shutdown: Shutdown,
+) {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    info!("Publisher connected: {} ({})", identity.name, subject.as_deref().unwrap_or("any subject"));
+
+    let stop = shutdown.triggered();
+    tokio::pin!(stop);
+    let mut published = 0u64;
+
+    loop {
+        let msg = tokio::select! {
+            msg = ws_rx.next() => msg,
+            _ = &mut stop => {
+                let _ = ws_tx.send(Message::close_with(1001u16, "server shutting down")).await;
+                break;
+            }
+        };
+        let msg = match msg {
+            Some(Ok(msg)) => msg,
+            Some(Err(e)) => {
+                error!("Publisher WebSocket error: {}", e);
+                break;
+            }
+            None => break,
+        };
+        if msg.is_close() {
+            break;
+        }
+        if msg.is_ping() || msg.is_pong() {
+            continue;
+        }
+
+        let result = match (msg.to_str(), &subject) {
+            (Ok(text), Some(subject)) => parse_payload(subject, text),
+            (Ok(text), None) => parse_message(text, &identity),
+            (Err(_), _) => Err(RequestError::new(ErrorCode::InvalidMessage, "Messages must be sent as text frames")),
+        };
+        match result {
+            Ok(message) => {
+                publish(&clients, &message);
+                published += 1;
+            }
+            Err(e) => {
+                warn!("Rejected message from publisher {}: {}", identity.name, e.message);
+                if ws_tx.send(Message::text(ServerReply::error(e).to_json())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    info!("Publisher disconnected: {} ({} messages published)", identity.name, published);
+    let _ = ws_tx.close().await;
+}
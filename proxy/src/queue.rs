@@ -0,0 +1,341 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use clap::ValueEnum;
+use tokio::sync::Notify;
+use warp::ws::Message;
+
+use crate::metrics::Metrics;
+use crate::protocol::ServerReply;
+
+// Unread control replies a client may leave queued before it is disconnected
+const MAX_UNREAD_REPLIES: usize = 256;
+
+// What to do with a client's queue when it is full
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued message to make room
+    DropOldest,
+    /// Drop the incoming message
+    DropNewest,
+    /// Replace the queued message on the same subject, else drop the oldest
+    Coalesce,
+    /// Close the connection
+    Disconnect,
+}
+
+// Per-client queue limits chosen on the command line
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub policy: SlowConsumerPolicy,
+}
+
+// Close code and reason sent to a client the proxy disconnects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseReason {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseReason {
+    pub fn new(code: u16, reason: impl Into<String>) -> Self {
+        CloseReason {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+// Something for a client's writer task to do next
+#[derive(Debug)]
+pub enum Outgoing {
+    Message(Message),
+    Close(CloseReason),
+}
+
+#[derive(Debug)]
+struct Queued {
+    // Only data messages carry a subject; replies are never dropped
+    subject: Option<String>,
+    message: Message,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    items: VecDeque<Queued>,
+    // Replies among `items`
+    replies: usize,
+    dropped: u64,
+    unreported: u64,
+    closed: bool,
+    // Set by `close_when_flushed`: nothing more is queued, and the close
+    // frame follows the messages already waiting
+    draining: bool,
+    close_reason: Option<CloseReason>,
+}
+
+// Bounded outgoing queue for one client, drained by its writer task, so a
+// stalled browser costs at most `capacity` messages of memory. Replies
+// cannot be dropped, so a client with `MAX_UNREAD_REPLIES` of them unread is
+// disconnected instead.
+#[derive(Debug)]
+pub struct ClientQueue {
+    state: Mutex<State>,
+    notify: Notify,
+    shutdown: Notify,
+    config: QueueConfig,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl ClientQueue {
+    pub fn new(config: QueueConfig) -> Self {
+        ClientQueue {
+            state: Mutex::new(State::default()),
+            notify: Notify::new(),
+            shutdown: Notify::new(),
+            config,
+            metrics: None,
+        }
+    }
+
+    // Count dropped messages in the proxy's metrics
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.config.capacity
+    }
+
+    // Queue a control reply; these bypass the slow-consumer policy
+    pub fn push_reply(&self, message: Message) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.draining {
+            return;
+        }
+        if state.replies >= MAX_UNREAD_REPLIES {
+            drop(state);
+            self.close(Some(CloseReason::new(1008, "slow consumer")));
+            return;
+        }
+        state.replies += 1;
+        state.items.push_back(Queued { subject: None, message });
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    // Bound messages held back from this queue, such as live messages
+    // waiting for a replay, by the same capacity and policy. Returns
+    // whether one more on `subject` may be added to `held`; whatever is
+    // dropped is counted as if the queue had dropped it.
+    pub fn make_room<T>(&self, held: &mut Vec<T>, subject: &str, subject_of: impl Fn(&T) -> &str) -> bool {
+        if held.len() < self.config.capacity {
+            return true;
+        }
+        let mut state = self.state.lock().unwrap();
+        let index = match self.config.policy {
+            SlowConsumerPolicy::DropOldest => 0,
+            SlowConsumerPolicy::DropNewest => {
+                self.count_drop(&mut state, subject);
+                return false;
+            }
+            SlowConsumerPolicy::Coalesce => held.iter().rposition(|item| subject_of(item) == subject).unwrap_or(0),
+            SlowConsumerPolicy::Disconnect => {
+                self.count_drop(&mut state, subject);
+                drop(state);
+                self.close(Some(CloseReason::new(1008, "slow consumer")));
+                return false;
+            }
+        };
+        let removed = held.remove(index);
+        self.count_drop(&mut state, subject_of(&removed));
+        drop(state);
+        self.notify.notify_one();
+        true
+    }
+
+    // Queue a data message, applying the slow-consumer policy when full
+    pub fn push(&self, subject: &str, message: Message) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.draining {
+            return;
+        }
+
+        if state.items.len() >= self.config.capacity {
+            match self.config.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    self.drop_oldest(&mut state);
+                }
+                SlowConsumerPolicy::DropNewest => {
+                    self.count_drop(&mut state, subject);
+                    return;
+                }
+                SlowConsumerPolicy::Coalesce => {
+                    let same_subject = state
+                        .items
+                        .iter_mut()
+                        .rev()
+                        .find(|queued| queued.subject.as_deref() == Some(subject));
+                    if let Some(queued) = same_subject {
+                        queued.message = message;
+                        self.count_drop(&mut state, subject);
+                        return;
+                    }
+                    self.drop_oldest(&mut state);
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    self.count_drop(&mut state, subject);
+                    drop(state);
+                    self.close(Some(CloseReason::new(1008, "slow consumer")));
+                    return;
+                }
+            }
+        }
+
+        state.items.push_back(Queued {
+            subject: Some(subject.to_string()),
+            message,
+        });
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    fn drop_oldest(&self, state: &mut State) {
+        let Some(index) = state.items.iter().position(|queued| queued.subject.is_some()) else {
+            return;
+        };
+        if let Some(Queued { subject: Some(subject), .. }) = state.items.remove(index) {
+            self.count_drop(state, &subject);
+        }
+    }
+
+    fn count_drop(&self, state: &mut State, subject: &str) {
+        state.dropped += 1;
+        state.unreported += 1;
+        if let Some(metrics) = &self.metrics {
+            metrics.dropped.with_label_values(&[metrics.subject(subject)]).inc();
+        }
+    }
+
+    // Total data messages dropped for this client so far
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+
+    // Stop the writer task, optionally sending a close frame with a reason
+    pub fn close(&self, reason: Option<CloseReason>) {
+        let mut state = self.state.lock().unwrap();
+        if !state.closed {
+            state.closed = true;
+            state.close_reason = reason;
+            state.items.clear();
+            state.replies = 0;
+        }
+        drop(state);
+        self.notify.notify_one();
+        self.shutdown.notify_waiters();
+    }
+
+    // Send what is already queued, then a close frame with `reason`. The
+    // connection stays open for the client to answer the close.
+    pub fn close_when_flushed(&self, reason: CloseReason) {
+        let mut state = self.state.lock().unwrap();
+        if !state.closed && !state.draining {
+            state.draining = true;
+            state.close_reason = Some(reason);
+        }
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    // Wait for the next thing to send. A lagged notice goes out ahead of
+    // the next message whenever messages were dropped since the last one.
+    pub async fn pop(&self) -> Option<Outgoing> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return state.close_reason.take().map(Outgoing::Close);
+                }
+                if state.unreported > 0 {
+                    let notice = ServerReply::lagged(state.unreported, state.dropped);
+                    state.unreported = 0;
+                    return Some(Outgoing::Message(Message::text(notice.to_json())));
+                }
+                if let Some(queued) = state.items.pop_front() {
+                    if queued.subject.is_none() {
+                        state.replies -= 1;
+                    }
+                    return Some(Outgoing::Message(queued.message));
+                }
+                if state.draining {
+                    return state.close_reason.take().map(Outgoing::Close);
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    // Resolves once the queue has been closed, e.g. by the disconnect policy
+    pub async fn closed(&self) {
+        let notified = self.shutdown.notified();
+        tokio::pin!(notified);
+        // Register for the wakeup before checking, so a close in between is not missed
+        notified.as_mut().enable();
+        if self.state.lock().unwrap().closed {
+            return;
+        }
+        notified.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(policy: SlowConsumerPolicy) -> ClientQueue {
+        ClientQueue::new(QueueConfig { capacity: 2, policy })
+    }
+
+    #[tokio::test]
+    async fn unread_replies_disconnect_the_client() {
+        let queue = queue(SlowConsumerPolicy::DropOldest);
+        for _ in 0..MAX_UNREAD_REPLIES {
+            queue.push_reply(Message::text("reply"));
+        }
+        assert!(matches!(queue.pop().await, Some(Outgoing::Message(_))));
+        queue.push_reply(Message::text("reply"));
+        queue.push_reply(Message::text("reply"));
+        assert!(matches!(queue.pop().await, Some(Outgoing::Close(reason)) if reason.code == 1008));
+    }
+
+    #[test]
+    fn held_messages_follow_the_policy() {
+        fn subject_of<'a>(item: &'a (&'static str, u32)) -> &'a str {
+            item.0
+        }
+
+        let oldest = queue(SlowConsumerPolicy::DropOldest);
+        let mut held = vec![("a", 1), ("b", 2)];
+        assert!(oldest.make_room(&mut held, "a", subject_of));
+        assert_eq!(held, vec![("b", 2)]);
+
+        let newest = queue(SlowConsumerPolicy::DropNewest);
+        let mut held = vec![("a", 1), ("b", 2)];
+        assert!(!newest.make_room(&mut held, "a", subject_of));
+        assert_eq!(held.len(), 2);
+
+        let coalesce = queue(SlowConsumerPolicy::Coalesce);
+        let mut held = vec![("a", 1), ("b", 2)];
+        assert!(coalesce.make_room(&mut held, "b", subject_of));
+        assert_eq!(held, vec![("a", 1)]);
+
+        let disconnect = queue(SlowConsumerPolicy::Disconnect);
+        let mut held = vec![("a", 1), ("b", 2)];
+        assert!(!disconnect.make_room(&mut held, "a", subject_of));
+        assert!(disconnect.state.lock().unwrap().closed);
+        assert_eq!([oldest.dropped(), newest.dropped(), coalesce.dropped(), disconnect.dropped()], [1, 1, 1, 1]);
+    }
+}
@@ -0,0 +1,905 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use log::{error, info, warn};
+use tokio::sync::mpsc;
+use warp::ws::Message;
+
+use crate::auth::Permissions;
+use crate::delivery::{self, Batcher, Encoded};
+use crate::health::{Health, UpstreamState};
+use crate::history::{History, Snapshot};
+use crate::metrics::Metrics;
+use crate::queue::{ClientQueue, CloseReason};
+use crate::protocol::{
+    parse_request, ClientRequest, Delivery, Encoding, ErrorCode, NexStreamMessage, ReplaySpec, RequestError, ServerReply,
+};
+use crate::subject::{self, SubjectError, SubjectTrie};
+use crate::upstream::UpstreamCommand;
+
+// Shards the route table is split into
+const SHARDS: usize = 16;
+
+// A connected client. Its queue, replay and snapshot state carry their own
+// locks, so fan-out to different clients never waits on the same lock.
+#[derive(Debug)]
+pub struct Client {
+    pub queue: Arc<ClientQueue>,
+    // JetStream replays in progress, keyed by pattern
+    replays: Mutex<HashMap<String, ReplayBuffer>>,
+    // Live messages on new subscriptions, held back until the snapshot
+    // they follow is queued, keyed by pattern
+    held: Mutex<HashMap<String, Vec<Held>>>,
+}
+
+// A live message held back on a new subscription, with its history
+// sequence number
+#[derive(Debug)]
+enum Held {
+    Frame { subject: String, sequence: u64, frame: Message },
+    Batched { batcher: Arc<Batcher>, sequence: u64, message: Arc<Encoded> },
+}
+
+impl Held {
+    fn subject(&self) -> &str {
+        match self {
+            Held::Frame { subject, .. } => subject,
+            Held::Batched { message, .. } => &message.message.subject,
+        }
+    }
+}
+
+impl Client {
+    pub fn new(queue: Arc<ClientQueue>) -> Self {
+        Client {
+            queue,
+            replays: Mutex::new(HashMap::new()),
+            held: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Queue a data message, subject to the slow-consumer policy
+    fn send(&self, subject: &str, frame: Message) {
+        self.queue.push(subject, frame);
+    }
+
+    // Queue a control reply, which is never dropped
+    pub fn reply(&self, reply: &ServerReply) {
+        self.queue.push_reply(Message::text(reply.to_json()));
+    }
+
+    // Send a live message, or hold it back if a replay or snapshot covering
+    // it is still to come
+    fn deliver(&self, message: &NexStreamMessage, sequence: u64, frame: Message, id: Option<&str>, key: &mut Option<MessageKey>) {
+        let subject = message.subject.as_str();
+        let mut replays = self.replays.lock().unwrap();
+        let replaying = replays.iter_mut().find(|(p, _)| subject::matches(p, subject));
+        if let Some((_, buffer)) = replaying {
+            if self.queue.make_room(&mut buffer.live, subject, |(_, subject, _)| subject) {
+                let key = key.get_or_insert_with(|| MessageKey::new(message, id)).clone();
+                buffer.live.push((key, subject.to_string(), frame));
+            }
+            return;
+        }
+        drop(replays);
+
+        let mut held = self.held.lock().unwrap();
+        if let Some((_, pending)) = held.iter_mut().find(|(p, _)| subject::matches(p, subject)) {
+            if self.queue.make_room(pending, subject, Held::subject) {
+                pending.push(Held::Frame { subject: subject.to_string(), sequence, frame });
+            }
+            return;
+        }
+        drop(held);
+        self.send(subject, frame);
+    }
+
+    // Add a live message to a batch, or hold it back like `deliver` does
+    fn batch(&self, batcher: &Arc<Batcher>, message: &Arc<Encoded>, sequence: u64) {
+        let mut held = self.held.lock().unwrap();
+        if let Some((_, pending)) = held.iter_mut().find(|(p, _)| subject::matches(p, &message.message.subject)) {
+            if self.queue.make_room(pending, &message.message.subject, Held::subject) {
+                pending.push(Held::Batched { batcher: batcher.clone(), sequence, message: message.clone() });
+            }
+            return;
+        }
+        drop(held);
+        batcher.add(message, &self.queue);
+    }
+
+    // Queue a reply about one subscription in the encoding it asked for
+    fn reply_in(&self, reply: &ServerReply, encoding: Encoding) {
+        match delivery::frame(reply, encoding) {
+            Ok(frame) => self.queue.push_reply(frame),
+            Err(e) => error!("Failed to encode a reply as {:?}: {}", encoding, e),
+        }
+    }
+
+    // Queue a new subscription's snapshot in its encoding, then the live
+    // messages held back while it was read that it does not already hold.
+    // Nothing is sent if the subscription ended in the meantime.
+    fn release(&self, pattern: &str, encoding: Encoding, snapshot: Snapshot) {
+        let mut held = self.held.lock().unwrap();
+        let Some(pending) = held.remove(pattern) else {
+            return;
+        };
+        if !snapshot.messages.is_empty() {
+            match delivery::envelope("snapshot", pattern, &snapshot.messages, encoding) {
+                Ok(frame) => self.queue.push_reply(frame),
+                Err(e) => error!("Failed to encode a snapshot of {} as {:?}: {}", pattern, encoding, e),
+            }
+        }
+        for message in pending {
+            match message {
+                Held::Frame { subject, sequence, frame } if !snapshot.covers(&subject, sequence) => self.send(&subject, frame),
+                Held::Batched { batcher, sequence, message } if !snapshot.covers(&message.message.subject, sequence) => {
+                    batcher.add(&message, &self.queue)
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+// Live messages held back while a replay is running, and how many replayed
+// messages had each key, so the overlap between the two is sent only once
+#[derive(Debug, Clone, Default)]
+pub struct ReplayBuffer {
+    live: Vec<(MessageKey, String, Message)>,
+    replayed: HashMap<MessageKey, usize>,
+}
+
+// Identifies a message so a replayed copy and a live copy compare equal:
+// by the publisher's `Nats-Msg-Id` where it set one, which JetStream keeps,
+// and otherwise by subject and payload. Distinct messages with the same
+// payload share a key, so keys are counted rather than only remembered.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MessageKey {
+    Id(String),
+    Content(u64),
+}
+
+impl MessageKey {
+    pub fn new(message: &NexStreamMessage, id: Option<&str>) -> Self {
+        if let Some(id) = id {
+            return MessageKey::Id(id.to_string());
+        }
+        let mut hasher = DefaultHasher::new();
+        message.subject.hash(&mut hasher);
+        message.data.to_string().hash(&mut hasher);
+        MessageKey::Content(hasher.finish())
+    }
+}
+
+// A client, the patterns it is subscribed to, those it wants
+// application-level heartbeats on, those it wants in an encoding other
+// than JSON, those it wants batched or conflated, and what its
+// credentials allow
+#[derive(Debug)]
+struct Member {
+    client: Arc<Client>,
+    subscriptions: BTreeSet<String>,
+    heartbeats: BTreeSet<String>,
+    encodings: BTreeMap<String, Encoding>,
+    batchers: BTreeMap<String, Arc<Batcher>>,
+    permissions: Permissions,
+}
+
+impl Member {
+    fn encoding(&self, pattern: &str) -> Encoding {
+        self.encodings.get(pattern).copied().unwrap_or_default()
+    }
+}
+
+// A message as one WebSocket frame, or None if it cannot be encoded
+fn encode(message: &NexStreamMessage, encoding: Encoding) -> Option<Message> {
+    match delivery::frame(message, encoding) {
+        Ok(frame) => Some(frame),
+        Err(e) => {
+            error!("Failed to encode message on {} as {:?}: {}", message.subject, encoding, e);
+            None
+        }
+    }
+}
+
+// Connected clients and the number of clients demanding each pattern from
+// upstream
+#[derive(Debug, Default)]
+struct Routes {
+    clients: HashMap<String, Member>,
+    demand: HashMap<String, usize>,
+    upstream: Option<mpsc::UnboundedSender<UpstreamCommand>>,
+}
+
+// One client's subscription to one pattern
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RouteKey {
+    client_id: String,
+    pattern: String,
+}
+
+// Where and how messages on a subscription go
+#[derive(Debug, Clone)]
+struct Route {
+    client: Arc<Client>,
+    encoding: Encoding,
+    batcher: Option<Arc<Batcher>>,
+}
+
+#[derive(Debug, Default)]
+struct RouteShard {
+    trie: SubjectTrie<RouteKey>,
+    routes: HashMap<RouteKey, Route>,
+}
+
+// Subscriptions by subject, split into independently locked shards so
+// publishers on different subjects never share a lock. A literal pattern
+// lives in the shard of the subject it names; a wildcard can match
+// subjects in any shard, so it is kept in all of them.
+#[derive(Debug)]
+struct RouteTable {
+    shards: Vec<RwLock<RouteShard>>,
+}
+
+impl Default for RouteTable {
+    fn default() -> Self {
+        RouteTable {
+            shards: (0..SHARDS).map(|_| RwLock::new(RouteShard::default())).collect(),
+        }
+    }
+}
+
+impl RouteTable {
+    fn shard(&self, subject: &str) -> &RwLock<RouteShard> {
+        &self.shards[subject::shard_index(subject, self.shards.len())]
+    }
+
+    fn shards_for(&self, pattern: &str) -> &[RwLock<RouteShard>] {
+        if subject::is_literal(pattern) {
+            std::slice::from_ref(self.shard(pattern))
+        } else {
+            &self.shards
+        }
+    }
+
+    // Add a route, or replace it when a subscription changes
+    fn set(&self, key: RouteKey, route: Route) -> Result<(), SubjectError> {
+        for shard in self.shards_for(&key.pattern) {
+            let mut shard = shard.write().unwrap();
+            shard.trie.insert(&key.pattern, key.clone())?;
+            shard.routes.insert(key.clone(), route.clone());
+        }
+        Ok(())
+    }
+
+    fn remove(&self, key: &RouteKey) {
+        for shard in self.shards_for(&key.pattern) {
+            let mut shard = shard.write().unwrap();
+            shard.trie.remove(&key.pattern, key);
+            shard.routes.remove(key);
+        }
+    }
+}
+
+// Clients and their routes plus recent history per subject. Publishers
+// only take a read lock on the route shard of their subject, so any number
+// of them fan out in parallel, and subscription changes hold up only the
+// shards they touch. A new subscriber's live messages are held back until
+// its history snapshot is queued, and history sequence numbers tell which
+// of them the snapshot already has.
+#[derive(Debug, Default)]
+pub struct Registry {
+    routes: RwLock<Routes>,
+    table: RouteTable,
+    history: History,
+    health: Arc<Health>,
+    metrics: Arc<Metrics>,
+    // Most patterns one client may hold (0 means no cap); changed live when
+    // the configuration is reloaded
+    max_subscriptions: AtomicUsize,
+}
+
+// Shared state
+pub type Clients = Arc<Registry>;
+
+impl Registry {
+    pub fn new(history: History) -> Self {
+        Registry {
+            history,
+            ..Default::default()
+        }
+    }
+
+    // Forward changes in subscription demand to the upstream NATS task,
+    // which opens and closes subscriptions to match
+    pub fn with_upstream(self, upstream: mpsc::UnboundedSender<UpstreamCommand>) -> Self {
+        self.routes.write().unwrap().upstream = Some(upstream);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn with_health(mut self, health: Arc<Health>) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn health(&self) -> Arc<Health> {
+        self.health.clone()
+    }
+
+    // Record a change in the upstream connection for readiness and metrics,
+    // and tell every client when it goes down or comes back
+    pub fn set_upstream_state(&self, state: UpstreamState) {
+        let previous = self.health.set_upstream(state);
+        if previous == state {
+            return;
+        }
+
+        self.metrics.upstream_connected.set(i64::from(state == UpstreamState::Connected));
+        if state == UpstreamState::Connected && previous == UpstreamState::Disconnected {
+            info!("Reconnected to NATS server");
+            self.metrics.upstream_reconnects.inc();
+        }
+        let routes = self.routes.read().unwrap();
+        // The first connection is not news to anyone
+        if previous != UpstreamState::Connecting || state != UpstreamState::Connected {
+            let reply = ServerReply::upstream_status(state);
+            for member in routes.clients.values() {
+                member.client.reply(&reply);
+            }
+        }
+    }
+
+    // Tell every client the proxy is going away, then close each connection
+    // once the messages already queued for it have been sent
+    pub fn shutdown(&self, reconnect_url: Option<&str>) {
+        let reason = "server shutting down";
+        let reply = ServerReply::shutdown(reason, reconnect_url);
+        let routes = self.routes.read().unwrap();
+        for member in routes.clients.values() {
+            for batcher in member.batchers.values() {
+                batcher.flush(&member.client.queue);
+            }
+            member.client.reply(&reply);
+            member.client.queue.close_when_flushed(CloseReason::new(1001, reason));
+        }
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.routes.read().unwrap().clients.len()
+    }
+
+    // Patterns at least one client is subscribed to
+    pub fn demanded_patterns(&self) -> Vec<String> {
+        self.routes.read().unwrap().demand.keys().cloned().collect()
+    }
+
+    // Whether any client holds a subscription, and so wants messages
+    pub fn has_subscriptions(&self) -> bool {
+        !self.routes.read().unwrap().demand.is_empty()
+    }
+
+    pub fn with_max_subscriptions(self, max_subscriptions: usize) -> Self {
+        self.set_max_subscriptions(max_subscriptions);
+        self
+    }
+
+    // Applies to subscribe requests from now on; clients already over a
+    // lowered cap keep what they hold
+    pub fn set_max_subscriptions(&self, max_subscriptions: usize) {
+        self.max_subscriptions.store(max_subscriptions, Ordering::Relaxed);
+    }
+
+    pub fn add_client(&self, client_id: &str, queue: Arc<ClientQueue>, permissions: Permissions) {
+        let member = Member {
+            client: Arc::new(Client::new(queue)),
+            subscriptions: BTreeSet::new(),
+            heartbeats: BTreeSet::new(),
+            encodings: BTreeMap::new(),
+            batchers: BTreeMap::new(),
+            permissions,
+        };
+        let mut routes = self.routes.write().unwrap();
+        routes.clients.insert(client_id.to_string(), member);
+        self.metrics.clients.set(routes.clients.len() as i64);
+    }
+
+    // Remove a client along with all of its routes
+    pub fn remove_client(&self, client_id: &str) {
+        let mut routes = self.routes.write().unwrap();
+        self.remove_all_routes(&mut routes, client_id);
+        routes.clients.remove(client_id);
+        self.metrics.clients.set(routes.clients.len() as i64);
+    }
+
+    // Parse and apply a client's control message, queueing the replies or
+    // an error reply, then the snapshot a new subscription starts with.
+    // Live messages on the subscription are held back until then, so they
+    // come after everything announcing it.
+    pub fn handle_message(&self, client_id: &str, text: &str) {
+        let mut routes = self.routes.write().unwrap();
+        let outcome = match parse_request(text) {
+            Ok(request) => self.handle_request(&mut routes, client_id, request),
+            Err(e) => Err(e),
+        };
+
+        let (replies, snapshot) = outcome.unwrap_or_else(|e| {
+            warn!("Rejected message from {}: {}", client_id, e.message);
+            (vec![ServerReply::error(e)], None)
+        });
+
+        let Some(member) = routes.clients.get(client_id) else {
+            return;
+        };
+        for reply in &replies {
+            member.client.reply(reply);
+        }
+
+        // Reading history is left until the lock is released, so other
+        // clients' subscription changes do not wait for it
+        if let Some(pattern) = snapshot {
+            let client = member.client.clone();
+            let encoding = member.encoding(&pattern);
+            drop(routes);
+            client.release(&pattern, encoding, self.history.snapshot(&pattern));
+        }
+    }
+
+    // Apply a client's control message and build the replies to send back,
+    // plus the pattern of a new subscription still owed its snapshot
+    fn handle_request(
+        &self,
+        routes: &mut Routes,
+        client_id: &str,
+        request: ClientRequest,
+    ) -> Result<(Vec<ServerReply>, Option<String>), RequestError> {
+        if !routes.clients.contains_key(client_id) {
+            return Err(RequestError::new(ErrorCode::InvalidRequest, "Unknown client"));
+        }
+
+        match request {
+            ClientRequest::Subscribe { subject, replay, heartbeat, encoding, delivery } => {
+                subject::validate_pattern(&subject)
+                    .map_err(|e| RequestError::new(ErrorCode::InvalidSubject, format!("Invalid subject '{}': {}", subject, e)))?;
+                if !routes.clients[client_id].permissions.allows(&subject) {
+                    return Err(RequestError::new(
+                        ErrorCode::Forbidden,
+                        format!("Not permitted to subscribe to '{}'", subject),
+                    ));
+                }
+                let member = &routes.clients[client_id];
+                let max_subscriptions = self.max_subscriptions.load(Ordering::Relaxed);
+                if max_subscriptions > 0
+                    && member.subscriptions.len() >= max_subscriptions
+                    && !member.subscriptions.contains(&subject)
+                {
+                    self.metrics.limit_violations.with_label_values(&["subscriptions"]).inc();
+                    return Err(RequestError::new(
+                        ErrorCode::SubscriptionLimit,
+                        format!("Subscription limit of {} reached", max_subscriptions),
+                    ));
+                }
+                delivery
+                    .validate()
+                    .map_err(|e| RequestError::new(ErrorCode::InvalidRequest, e))?;
+                if let Some(replay) = &replay {
+                    if delivery != Delivery::Immediate {
+                        return Err(RequestError::new(
+                            ErrorCode::InvalidRequest,
+                            "Replay cannot be combined with batched or conflated delivery",
+                        ));
+                    }
+                    routes.check_replay(client_id, &subject, replay)?;
+                }
+
+                // Live messages are held back until the replay has caught
+                // up, or on a new subscription until its snapshot is queued
+                let client = routes.clients[client_id].client.clone();
+                let added = !routes.clients[client_id].subscriptions.contains(&subject);
+                if replay.is_some() {
+                    client.replays.lock().unwrap().insert(subject.clone(), ReplayBuffer::default());
+                } else if added {
+                    client.held.lock().unwrap().insert(subject.clone(), Vec::new());
+                }
+
+                // The latest subscribe decides how messages are encoded and
+                // delivered
+                let batcher = Batcher::new(&subject, delivery, encoding, client.queue.capacity()).map(Arc::new);
+                let key = RouteKey { client_id: client_id.to_string(), pattern: subject.clone() };
+                let route = Route { client: client.clone(), encoding, batcher: batcher.clone() };
+                if let Err(e) = self.table.set(key, route) {
+                    client.replays.lock().unwrap().remove(&subject);
+                    client.held.lock().unwrap().remove(&subject);
+                    return Err(RequestError::new(ErrorCode::InvalidSubject, format!("Invalid subject '{}': {}", subject, e)));
+                }
+
+                // Re-subscribing is confirmed again but never duplicated
+                if self.add_route(routes, client_id, &subject) {
+                    info!("Client {} subscribed to {}", client_id, subject);
+                }
+
+                // The latest subscribe also decides whether heartbeats are
+                // wanted
+                if let Some(member) = routes.clients.get_mut(client_id) {
+                    if heartbeat {
+                        member.heartbeats.insert(subject.clone());
+                    } else {
+                        member.heartbeats.remove(&subject);
+                    }
+                    if encoding.is_binary() {
+                        member.encodings.insert(subject.clone(), encoding);
+                    } else {
+                        member.encodings.remove(&subject);
+                    }
+                    // Messages held back under the old delivery go out first
+                    let previous = match batcher {
+                        Some(batcher) => {
+                            batcher.start(member.client.queue.clone());
+                            member.batchers.insert(subject.clone(), batcher)
+                        }
+                        None => member.batchers.remove(&subject),
+                    };
+                    if let Some(previous) = previous {
+                        previous.flush(&member.client.queue);
+                    }
+                }
+
+                let confirmation = ServerReply::subscription_confirmed(&subject);
+                if let Some(replay) = replay {
+                    info!("Client {} requested replay of {}: {:?}", client_id, subject, replay);
+                    routes.send_upstream(UpstreamCommand::Replay {
+                        client_id: client_id.to_string(),
+                        pattern: subject.clone(),
+                        replay,
+                    });
+                    return Ok((vec![confirmation], None));
+                }
+
+                // New subscriptions start from the recent history
+                Ok((vec![confirmation], added.then_some(subject)))
+            }
+            ClientRequest::Unsubscribe { subject } => {
+                if !self.remove_route(routes, client_id, &subject) {
+                    return Err(RequestError::new(
+                        ErrorCode::NotSubscribed,
+                        format!("Not subscribed to '{}'", subject),
+                    ));
+                }
+                info!("Client {} unsubscribed from {}", client_id, subject);
+                Ok((vec![ServerReply::unsubscribed(&subject)], None))
+            }
+            ClientRequest::UnsubscribeAll => {
+                let subjects = self.remove_all_routes(routes, client_id);
+                info!("Client {} unsubscribed from all {} subjects", client_id, subjects.len());
+                Ok((vec![ServerReply::unsubscribed_all(subjects)], None))
+            }
+            ClientRequest::List => {
+                let member = &routes.clients[client_id];
+                let subjects = member.subscriptions.iter().cloned().collect();
+                Ok((vec![ServerReply::subscriptions(subjects, member.client.queue.dropped())], None))
+            }
+            ClientRequest::Heartbeat => Ok((Vec::new(), None)),
+        }
+    }
+
+    // Send a heartbeat on each subscription the client asked to have them
+    // on. They go out as replies: queued as data, a heartbeat on a literal
+    // pattern would share its key with that subject's messages and could
+    // coalesce one away.
+    pub fn send_heartbeats(&self, client_id: &str) {
+        let routes = self.routes.read().unwrap();
+        let Some(member) = routes.clients.get(client_id) else {
+            return;
+        };
+        for pattern in &member.heartbeats {
+            member.client.reply(&ServerReply::heartbeat(pattern));
+        }
+    }
+
+    // Send one historical message to a replaying client; returns false once
+    // the replay has been cancelled by an unsubscribe or disconnect. `id` is
+    // the publisher's message ID, if it set one.
+    pub fn deliver_replayed(&self, client_id: &str, pattern: &str, message: &NexStreamMessage, id: Option<&str>) -> bool {
+        let routes = self.routes.read().unwrap();
+        let Some(member) = routes.clients.get(client_id) else {
+            return false;
+        };
+        let mut replays = member.client.replays.lock().unwrap();
+        let Some(buffer) = replays.get_mut(pattern) else {
+            return false;
+        };
+
+        *buffer.replayed.entry(MessageKey::new(message, id)).or_default() += 1;
+        if let Some(frame) = encode(message, member.encoding(pattern)) {
+            member.client.send(&message.subject, frame);
+            self.metrics.messages_out.with_label_values(&[self.metrics.subject(&message.subject)]).inc();
+        }
+        true
+    }
+
+    // End a replay: report how it went, then release the held-back live
+    // messages that the replay did not already deliver
+    pub fn finish_replay(&self, client_id: &str, pattern: &str, outcome: Result<usize, String>) {
+        let routes = self.routes.read().unwrap();
+        let Some(member) = routes.clients.get(client_id) else {
+            return;
+        };
+        let client = &member.client;
+
+        // Hold the replay lock while flushing so live fan-out to this client
+        // waits until the buffered messages are queued ahead of it
+        let mut replays = client.replays.lock().unwrap();
+        let Some(buffer) = replays.remove(pattern) else {
+            return;
+        };
+
+        let reply = match outcome {
+            Ok(count) => {
+                info!("Replayed {} messages of {} to {}", count, pattern, client_id);
+                ServerReply::replay_complete(pattern, count)
+            }
+            Err(e) => {
+                warn!("Replay of {} for {} failed: {}", pattern, client_id, e);
+                ServerReply::error(RequestError::new(ErrorCode::ReplayFailed, e))
+            }
+        };
+        client.reply_in(&reply, member.encoding(pattern));
+
+        let mut replayed = buffer.replayed;
+        for (key, subject, frame) in buffer.live {
+            match replayed.get_mut(&key) {
+                Some(count) if *count > 0 => *count -= 1,
+                _ => client.send(&subject, frame),
+            }
+        }
+    }
+
+    // Send a serialized message to every client with a matching subscription,
+    // in the encoding of the first of the client's patterns that matches.
+    // Each message is expected once: the upstream task drops the extra
+    // copies NATS delivers for overlapping subscriptions. `id` is the
+    // publisher's message ID, if it set one.
+    pub fn broadcast(&self, message: &NexStreamMessage, message_json: &str, id: Option<&str>) {
+        let started = Instant::now();
+        let subject = message.subject.as_str();
+        let shard = self.table.shard(subject).read().unwrap();
+        let encoded = Arc::new(Encoded::with_json(message.clone(), message_json));
+        let mut key = None;
+        let mut delivered = 0;
+
+        // Recorded under the shard lock: a subscription added after this
+        // finds the message in its snapshot, and one added before gets it
+        // live with a sequence number the snapshot will cover
+        let sequence = self.history.record(&encoded);
+        self.health.message_received();
+        self.metrics.messages_in.with_label_values(&[self.metrics.subject(subject)]).inc();
+
+        let mut first_matches: HashMap<&str, &RouteKey> = HashMap::new();
+        for route_key in shard.trie.matches(subject) {
+            first_matches
+                .entry(route_key.client_id.as_str())
+                .and_modify(|first| {
+                    if route_key.pattern < first.pattern {
+                        *first = route_key;
+                    }
+                })
+                .or_insert(route_key);
+        }
+
+        for route_key in first_matches.into_values() {
+            let Some(route) = shard.routes.get(route_key) else {
+                continue;
+            };
+            if let Some(batcher) = &route.batcher {
+                route.client.batch(batcher, &encoded, sequence);
+                delivered += 1;
+                continue;
+            }
+            let Some(frame) = encoded.frame(route.encoding) else {
+                continue;
+            };
+            route.client.deliver(message, sequence, frame, id, &mut key);
+            delivered += 1;
+        }
+
+        if delivered > 0 {
+            self.metrics.messages_out.with_label_values(&[self.metrics.subject(subject)]).inc_by(delivered);
+        }
+        self.metrics.fanout_duration.observe(started.elapsed().as_secs_f64());
+    }
+
+    // Record a client's subscription to a pattern, whose route is already
+    // in the table; returns false if it already was subscribed
+    fn add_route(&self, routes: &mut Routes, client_id: &str, pattern: &str) -> bool {
+        let Some(member) = routes.clients.get_mut(client_id) else {
+            return false;
+        };
+        if !member.subscriptions.insert(pattern.to_string()) {
+            return false;
+        }
+
+        let count = routes.demand.entry(pattern.to_string()).or_insert(0);
+        *count += 1;
+        self.metrics.subscriptions.with_label_values(&[pattern]).set(*count as i64);
+        if *count == 1 {
+            routes.send_upstream(UpstreamCommand::Subscribe(pattern.to_string()));
+        }
+        true
+    }
+
+    // Unsubscribe a client from a pattern; returns false if it was not subscribed
+    fn remove_route(&self, routes: &mut Routes, client_id: &str, pattern: &str) -> bool {
+        let Some(member) = routes.clients.get_mut(client_id) else {
+            return false;
+        };
+        if !member.subscriptions.remove(pattern) {
+            return false;
+        }
+        self.table.remove(&RouteKey { client_id: client_id.to_string(), pattern: pattern.to_string() });
+        member.heartbeats.remove(pattern);
+        member.encodings.remove(pattern);
+        member.batchers.remove(pattern);
+        member.client.replays.lock().unwrap().remove(pattern);
+        member.client.held.lock().unwrap().remove(pattern);
+
+        if let Some(count) = routes.demand.get_mut(pattern) {
+            *count -= 1;
+            self.metrics.subscriptions.with_label_values(&[pattern]).set(*count as i64);
+            if *count == 0 {
+                routes.demand.remove(pattern);
+                let _ = self.metrics.subscriptions.remove_label_values(&[pattern]);
+                routes.send_upstream(UpstreamCommand::Unsubscribe(pattern.to_string()));
+            }
+        }
+        true
+    }
+
+    fn remove_all_routes(&self, routes: &mut Routes, client_id: &str) -> Vec<String> {
+        let patterns: Vec<String> = match routes.clients.get(client_id) {
+            Some(member) => member.subscriptions.iter().cloned().collect(),
+            None => return Vec::new(),
+        };
+        for pattern in &patterns {
+            self.remove_route(routes, client_id, pattern);
+        }
+        patterns
+    }
+}
+
+impl Routes {
+    fn check_replay(&self, client_id: &str, pattern: &str, replay: &ReplaySpec) -> Result<(), RequestError> {
+        if self.upstream.is_none() {
+            return Err(RequestError::new(
+                ErrorCode::ReplayUnavailable,
+                "Replay needs an upstream NEX Stream connection",
+            ));
+        }
+        if self.clients[client_id].client.replays.lock().unwrap().contains_key(pattern) {
+            return Err(RequestError::new(
+                ErrorCode::InvalidRequest,
+                format!("A replay of '{}' is already in progress", pattern),
+            ));
+        }
+        if let ReplaySpec::Since(start) = replay {
+            start
+                .to_datetime()
+                .map_err(|e| RequestError::new(ErrorCode::InvalidRequest, e))?;
+        }
+        Ok(())
+    }
+
+    fn send_upstream(&self, command: UpstreamCommand) {
+        if let Some(upstream) = &self.upstream {
+            if upstream.send(command).is_err() {
+                error!("Upstream NATS task is gone; subscription change dropped");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::queue::{Outgoing, QueueConfig, SlowConsumerPolicy};
+
+    #[tokio::test]
+    async fn heartbeats_never_coalesce_with_data() {
+        let registry = Registry::default();
+        let config = QueueConfig { capacity: 1, policy: SlowConsumerPolicy::Coalesce };
+        let queue = Arc::new(ClientQueue::new(config));
+        registry.add_client("client", queue.clone(), Permissions::all());
+        registry.handle_message("client", r#"{"action": "subscribe", "subject": "market.btc-usd.ticker", "heartbeat": true}"#);
+
+        let message = NexStreamMessage::new("market.btc-usd.ticker", json!({ "price": 1.0 }), None);
+        registry.broadcast(&message, &serde_json::to_string(&message).unwrap(), None);
+        registry.send_heartbeats("client");
+        assert_eq!(queue.dropped(), 0);
+
+        let types: Vec<_> = drain(&queue, 3).await.iter().map(|f| f.get("type").cloned().unwrap_or(json!("message"))).collect();
+        assert_eq!(types, vec![json!("subscription_confirmed"), json!("message"), json!("heartbeat")]);
+    }
+
+    // Frames waiting in `queue`, as JSON
+    async fn drain(queue: &ClientQueue, count: usize) -> Vec<serde_json::Value> {
+        let mut values = Vec::new();
+        for _ in 0..count {
+            let Some(Outgoing::Message(frame)) = queue.pop().await else {
+                panic!("the queue holds {} frames", count);
+            };
+            values.push(serde_json::from_str(frame.to_str().unwrap()).unwrap());
+        }
+        values
+    }
+
+    #[tokio::test]
+    async fn held_messages_follow_the_snapshot_without_repeating_it() {
+        let registry = Registry::new(History::new(10, std::time::Duration::from_secs(60)));
+        let config = QueueConfig { capacity: 100, policy: SlowConsumerPolicy::DropOldest };
+        let queue = Arc::new(ClientQueue::new(config));
+        registry.add_client("client", queue.clone(), Permissions::all());
+        registry.handle_message("client", r#"{"action": "subscribe", "subject": "market.btc-usd.ticker"}"#);
+
+        // Messages arriving while a snapshot is read are held back, and
+        // those it turns out to hold are not sent again
+        let pattern = "market.btc-usd.ticker";
+        let client = registry.routes.read().unwrap().clients["client"].client.clone();
+        client.held.lock().unwrap().insert(pattern.to_string(), Vec::new());
+        for price in [1.0, 2.0] {
+            let ticker = NexStreamMessage::new(pattern, json!({ "price": price }), None);
+            registry.broadcast(&ticker, &serde_json::to_string(&ticker).unwrap(), None);
+        }
+        let snapshot = registry.history.snapshot(pattern);
+        let ticker = NexStreamMessage::new(pattern, json!({ "price": 3.0 }), None);
+        registry.broadcast(&ticker, &serde_json::to_string(&ticker).unwrap(), None);
+        client.release(pattern, Encoding::Json, snapshot);
+
+        let frames = drain(&queue, 3).await;
+        assert_eq!(frames[0]["type"], json!("subscription_confirmed"));
+        let snapshot: Vec<_> = frames[1]["messages"].as_array().unwrap().iter().map(|m| m["data"]["price"].clone()).collect();
+        assert_eq!(snapshot, vec![json!(1.0), json!(2.0)]);
+        assert_eq!(frames[2]["data"]["price"], json!(3.0));
+    }
+
+    #[tokio::test]
+    async fn replays_hand_over_to_live_messages_without_losing_repeats() {
+        let (upstream, _commands) = mpsc::unbounded_channel();
+        let registry = Registry::default().with_upstream(upstream);
+        let config = QueueConfig { capacity: 100, policy: SlowConsumerPolicy::DropOldest };
+        let queue = Arc::new(ClientQueue::new(config));
+        registry.add_client("client", queue.clone(), Permissions::all());
+        registry.handle_message("client", r#"{"action": "subscribe", "subject": "market.btc-usd.ticker", "replay": {"last": 5}}"#);
+
+        // The same ticker twice live, while the replay holds one of them
+        let ticker = NexStreamMessage::new("market.btc-usd.ticker", json!({ "price": 1.0 }), None);
+        let ticker_json = serde_json::to_string(&ticker).unwrap();
+        registry.broadcast(&ticker, &ticker_json, None);
+        registry.broadcast(&ticker, &ticker_json, None);
+        assert!(registry.deliver_replayed("client", "market.btc-usd.ticker", &ticker, None));
+
+        // Messages with IDs are matched by ID alone
+        let other = NexStreamMessage::new("market.btc-usd.ticker", json!({ "price": 2.0 }), None);
+        let other_json = serde_json::to_string(&other).unwrap();
+        registry.broadcast(&other, &other_json, Some("a"));
+        registry.broadcast(&other, &other_json, Some("b"));
+        assert!(registry.deliver_replayed("client", "market.btc-usd.ticker", &other, Some("a")));
+        registry.finish_replay("client", "market.btc-usd.ticker", Ok(2));
+
+        let frames = drain(&queue, 6).await;
+        let kinds: Vec<_> = frames.iter().map(|f| f.get("type").cloned().unwrap_or_else(|| f["data"]["price"].clone())).collect();
+        assert_eq!(kinds, vec![json!("subscription_confirmed"), json!(1.0), json!(2.0), json!("replay_complete"), json!(1.0), json!(2.0)]);
+    }
+}
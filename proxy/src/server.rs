@@ -0,0 +1,998 @@
+// The proxy server: the WebSocket proxy and static file listeners, their
+// routes and connection handling, and the settings they run with. The
+// binary parses its arguments and hands them to `Server::start`; tests
+// start servers the same way, on ports of their own.
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use std::error::Error as StdError;
+use std::path::Path;
+
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, Instant};
+use warp::{Filter, http::StatusCode, ws::{Message, WebSocket}};
+use futures::{FutureExt, StreamExt, SinkExt};
+use log::{info, error, warn};
+use clap::{error::ErrorKind, CommandFactory, FromArgMatches, Parser};
+use nats_config::NatsArgs;
+use url::Url;
+
+use crate::auth::{
+    bearer_token, AuthError, Authenticator, Identity, JwtVerifier, SharedAuthenticator, StaticTokens,
+};
+use crate::config::{Config, CONFIG_ENV};
+use crate::cors;
+use crate::health::{Health, Report, Source};
+use crate::history::History;
+use crate::limits::{ConnectionGuard, ConnectionLimiter, TokenBucket};
+use crate::metrics::Metrics;
+use crate::protocol::{ErrorCode, RequestError, ServerReply};
+use crate::publish;
+use crate::queue::{ClientQueue, CloseReason, Outgoing, QueueConfig, SlowConsumerPolicy};
+use crate::registry::{Clients, Registry};
+use crate::shutdown::Shutdown;
+use crate::simulator;
+use crate::tls::{self, ReloadingCert};
+use crate::upstream::supervise_nex_stream;
+
+// Command line arguments, any of which may also come from the configuration
+// file or environment (see `config`)
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// TOML or YAML file of settings; flags override it (env: NEX_PROXY_CONFIG)
+    #[arg(short, long)]
+    pub config: Option<String>,
+
+    /// Port to listen on for WebSocket proxy
+    #[arg(short, long, default_value_t = 3030)]
+    pub proxy_port: u16,
+
+    /// Port to listen on for HTTP server
+    #[arg(short = 'P', long, default_value_t = 54572)]
+    pub http_port: u16,
+
+    /// Enable simulated data mode; `--simulate=false` turns it off when the
+    /// configuration file turns it on
+    #[arg(short, long, num_args = 0..=1, require_equals = true, default_value_t = false, default_missing_value = "true")]
+    pub simulate: bool,
+
+    /// Real NEX Stream URL (if not simulating)
+    #[arg(short, long, default_value = "")]
+    pub nex_url: String,
+    
+    /// Directory to serve static files from
+    #[arg(short = 'd', long, default_value = "../")]
+    pub static_dir: String,
+
+    /// Maximum number of JetStream messages sent for one replay request
+    #[arg(long, default_value_t = 10000)]
+    pub replay_limit: u64,
+
+    /// Recent messages kept per subject for new subscribers (0 disables)
+    #[arg(long, default_value_t = 1000)]
+    pub history_size: usize,
+
+    /// Maximum age in seconds of messages kept per subject
+    #[arg(long, default_value_t = 3600)]
+    pub history_max_age: u64,
+
+    /// Subjects kept in the history, the least recently active making way for new ones (0 disables)
+    #[arg(long, default_value_t = 10000)]
+    pub history_max_subjects: usize,
+
+    /// Most messages sent in one snapshot to a new subscriber, the newest kept (0 disables)
+    #[arg(long, default_value_t = 1000)]
+    pub history_snapshot_size: usize,
+
+    /// Messages queued per client before the slow-consumer policy applies
+    #[arg(long, default_value_t = 1024)]
+    pub client_queue_size: usize,
+
+    /// What to do when a client's queue is full
+    #[arg(long, value_enum, default_value_t = SlowConsumerPolicy::DropOldest)]
+    pub slow_consumer_policy: SlowConsumerPolicy,
+
+    /// Seconds between WebSocket pings and heartbeat messages (0 disables)
+    #[arg(long, default_value_t = 30)]
+    pub ping_interval: u64,
+
+    /// Unanswered pings after which a client is disconnected
+    #[arg(long, default_value_t = 2)]
+    pub max_missed_pongs: u32,
+
+    /// File of accepted bearer tokens, one per line, each optionally followed by allowed subjects
+    #[arg(long)]
+    pub token_file: Option<String>,
+
+    /// File holding the secret for HS256-signed JWTs
+    #[arg(long)]
+    pub jwt_secret_file: Option<String>,
+
+    /// Comma-separated origins allowed to connect (default: any)
+    #[arg(long, value_delimiter = ',')]
+    pub allowed_origins: Vec<String>,
+
+    /// File of bearer tokens accepted on /publish, in the --token-file format (default: local publishers only)
+    #[arg(long)]
+    pub publish_token_file: Option<String>,
+
+    /// File holding the secret for HS256-signed JWTs accepted on /publish
+    #[arg(long)]
+    pub publish_jwt_secret_file: Option<String>,
+
+    /// Control messages per second each client may send (0 disables the limit)
+    #[arg(long, default_value_t = 10.0)]
+    pub max_control_rate: f64,
+
+    /// Control messages a client may send in one burst
+    #[arg(long, default_value_t = 20)]
+    pub control_burst: u32,
+
+    /// Subscriptions each client may hold (0 disables the limit)
+    #[arg(long, default_value_t = 100)]
+    pub max_subscriptions: usize,
+
+    /// WebSocket connections allowed from one IP address (0 disables the limit)
+    #[arg(long, default_value_t = 20)]
+    pub max_connections_per_ip: usize,
+
+    /// Seconds without a message, while clients are subscribed, before /readyz fails
+    #[arg(long, default_value_t = 30)]
+    pub ready_max_message_age: u64,
+
+    /// PEM certificate chain; serves wss:// and https:// on both ports (requires --tls-key)
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<String>,
+
+    /// Seconds between checks for renewed certificate files (0 disables reloading)
+    #[arg(long, default_value_t = 60)]
+    pub tls_reload_interval: u64,
+
+    /// Seconds allowed on SIGINT/SIGTERM for draining upstream and flushing clients
+    #[arg(long, default_value_t = 10)]
+    pub shutdown_timeout: u64,
+
+    /// Address suggested to clients for reconnecting when the proxy shuts down
+    #[arg(long)]
+    pub reconnect_url: Option<String>,
+
+    #[command(flatten)]
+    pub nats: NatsArgs,
+}
+
+// Keepalive settings for WebSocket connections
+#[derive(Debug, Clone, Copy)]
+struct HeartbeatConfig {
+    // Zero disables pings and heartbeat messages
+    interval: Duration,
+    max_missed: u32,
+}
+
+// Per-connection settings chosen on the command line
+#[derive(Debug, Clone, Copy)]
+struct ConnectionConfig {
+    queue: QueueConfig,
+    heartbeat: HeartbeatConfig,
+    // Control messages per second and burst size; None disables the limit
+    control_rate: Option<(f64, f64)>,
+}
+
+// Settings a reload can change, read as each connection or request arrives
+#[derive(Debug, Clone, Copy)]
+struct LiveSettings {
+    connection: ConnectionConfig,
+    ready_max_message_age: Duration,
+}
+
+impl LiveSettings {
+    fn new(args: &Args) -> Self {
+        LiveSettings {
+            connection: ConnectionConfig {
+                queue: QueueConfig {
+                    capacity: args.client_queue_size.max(1),
+                    policy: args.slow_consumer_policy,
+                },
+                heartbeat: HeartbeatConfig {
+                    interval: Duration::from_secs(args.ping_interval),
+                    max_missed: args.max_missed_pongs,
+                },
+                control_rate: (args.max_control_rate > 0.0)
+                    .then(|| (args.max_control_rate, f64::from(args.control_burst.max(1)))),
+            },
+            ready_max_message_age: Duration::from_secs(args.ready_max_message_age),
+        }
+    }
+}
+
+// Everything in the running proxy that a reload can update, and the
+// settings it started with
+#[derive(Clone)]
+pub struct Reloader {
+    started: Args,
+    config: Config,
+    auth: SharedAuthenticator,
+    publish_auth: SharedAuthenticator,
+    settings: Arc<RwLock<LiveSettings>>,
+    clients: Clients,
+    limiter: Arc<ConnectionLimiter>,
+}
+
+// A running proxy: both listeners and whatever feeds them
+pub struct Server {
+    proxy_addr: SocketAddr,
+    http_addr: SocketAddr,
+    clients: Clients,
+    shutdown: Shutdown,
+    servers: JoinHandle<()>,
+    upstream: Option<JoinHandle<()>>,
+    reconnect_url: Option<String>,
+    reloader: Reloader,
+}
+
+impl Server {
+    // Start serving as `args` and `config` say: bind both listeners, then
+    // start the simulator or upstream connection feeding them
+    pub async fn start(args: Args, config: Config) -> Result<Server, Box<dyn StdError>> {
+        // Client authentication; without a token file or JWT secret anyone may connect
+        let auth = build_authenticator(&args).map_err(|e| format!("Invalid authentication settings: {}", e))?;
+        let auth = SharedAuthenticator::new(auth);
+        let publish_auth = build_publish_authenticator(&args).map_err(|e| format!("Invalid publisher authentication settings: {}", e))?;
+        let publish_auth = SharedAuthenticator::new(publish_auth);
+        
+        // TLS for both listeners, picking up renewed certificates as they appear
+        let tls = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => match ReloadingCert::load(cert, key) {
+                Ok(certs) => {
+                    if args.tls_reload_interval > 0 {
+                        certs.watch(Duration::from_secs(args.tls_reload_interval));
+                    }
+                    info!("Serving TLS with the certificate in {}", cert);
+                    Some(certs.acceptor())
+                },
+                Err(e) => return Err(format!("Invalid TLS settings: {}", e).into()),
+            },
+            _ => None,
+        };
+        
+        // Recent messages per subject, sent to new subscribers as a snapshot
+        let history = History::new(args.history_size, Duration::from_secs(args.history_max_age))
+            .with_max_subjects(args.history_max_subjects)
+            .with_max_snapshot(args.history_snapshot_size);
+        let metrics = Arc::new(Metrics::new());
+        let source = if args.simulate {
+            Source::Simulator
+        } else if !args.nex_url.is_empty() {
+            Source::Upstream
+        } else {
+            Source::None
+        };
+        let registry = Registry::new(history)
+            .with_metrics(metrics.clone())
+            .with_health(Arc::new(Health::new(source)))
+            .with_max_subscriptions(args.max_subscriptions);
+        
+        // Triggered by SIGINT or SIGTERM; everything below winds down on it
+        let shutdown = Shutdown::new();
+        let mut upstream = None;
+        
+        // Start data generator if in simulation mode
+        let clients: Clients = if args.simulate {
+            info!("Starting in simulation mode");
+            let clients = Arc::new(registry);
+            tokio::spawn(simulator::run(clients.clone(), config.simulated_instruments(), shutdown.clone()));
+            clients
+        } else if !args.nex_url.is_empty() {
+            // Upstream subscriptions follow client demand reported by the registry
+            info!("Connecting to real NEX Stream at: {}", args.nex_url);
+            check_nats_options(&args).await.map_err(|e| format!("Invalid NATS settings: {}", e))?;
+            let (upstream_tx, upstream_rx) = mpsc::unbounded_channel();
+            let clients = Arc::new(registry.with_upstream(upstream_tx));
+            let nex_url = args.nex_url.clone();
+            let clients_for_upstream = clients.clone();
+            let replay_limit = args.replay_limit;
+            let nats = args.nats.clone();
+            upstream = Some(tokio::spawn(supervise_nex_stream(
+                nex_url,
+                nats,
+                clients_for_upstream,
+                upstream_rx,
+                replay_limit,
+                shutdown.clone(),
+            )));
+            clients
+        } else {
+            warn!("No NEX Stream URL provided and simulation disabled. Proxy will only relay WebSocket connections.");
+            Arc::new(registry)
+        };
+        
+        // WebSocket route
+        let settings = Arc::new(RwLock::new(LiveSettings::new(&args)));
+        let limiter = Arc::new(ConnectionLimiter::new(args.max_connections_per_ip));
+        let ws_settings = settings.clone();
+        let ws_limiter = limiter.clone();
+        let ws_metrics = metrics.clone();
+        let ws_route = warp::path("ws")
+            .and(warp::ws())
+            .and(tls::remote_addr())
+            .and(with_identity(auth.clone()))
+            .and(with_clients(clients.clone()))
+            .map(move |ws: warp::ws::Ws, remote: Option<SocketAddr>, identity: Identity, clients| {
+                // Take a per-address slot up front, held for the life of the connection
+                let slot = remote.map(|addr| (addr.ip(), ws_limiter.try_acquire(addr.ip())));
+                let max_per_ip = ws_limiter.max_per_ip();
+                let config = ws_settings.read().unwrap().connection;
+                let metrics = ws_metrics.clone();
+                ws.on_upgrade(move |socket| async move {
+                    match slot {
+                        Some((ip, None)) => reject_connection(socket, ip, max_per_ip, &metrics).await,
+                        slot => {
+                            let guard = slot.and_then(|(_, guard)| guard);
+                            handle_connection(socket, clients, identity, config, metrics, guard).await
+                        }
+                    }
+                })
+            });
+        
+        // Publisher routes, for producers sending messages in without NATS:
+        // a WebSocket to `/publish` carrying envelopes or to
+        // `/publish/<subject>` carrying payloads, and `POST /publish/<subject>`
+        let publish_shutdown = shutdown.clone();
+        let publish_ws_route = warp::path("publish")
+            .and(warp::path::end())
+            .and(warp::ws())
+            .and(with_publisher(publish_auth.clone()))
+            .and(with_clients(clients.clone()))
+            .map(move |ws: warp::ws::Ws, identity: Identity, clients| {
+                let shutdown = publish_shutdown.clone();
+                ws.on_upgrade(move |socket| publish::handle_publisher(socket, clients, identity, None, shutdown))
+            });
+        let publish_shutdown = shutdown.clone();
+        let publish_subject_ws_route = with_publish_subject(publish_auth.clone())
+            .and(warp::ws())
+            .and(with_clients(clients.clone()))
+            .map(move |subject: String, identity: Identity, ws: warp::ws::Ws, clients| {
+                let shutdown = publish_shutdown.clone();
+                ws.on_upgrade(move |socket| publish::handle_publisher(socket, clients, identity, Some(subject), shutdown))
+            });
+        let publish_post_route = warp::post()
+            .and(with_publish_subject(publish_auth.clone()))
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::content_length_limit(publish::MAX_BODY_BYTES))
+            .and(warp::body::bytes())
+            .and(with_clients(clients.clone()))
+            .map(publish_request);
+        
+        // Prometheus metrics route
+        let metrics_route = warp::path("metrics").map(move || {
+            warp::reply::with_header(metrics.render(), "Content-Type", "text/plain; version=0.0.4")
+        });
+        
+        // Health check routes: `/health` for existing checks, `/healthz` for
+        // liveness and `/readyz` for readiness, the latter two as JSON
+        let health_route = warp::path("health")
+            .map(|| "NEX Stream Proxy is running");
+        let health = clients.health();
+        let healthz_route = warp::path("healthz")
+            .map(move || health_reply(health.liveness()));
+        let ready_clients = clients.clone();
+        let ready_settings = settings.clone();
+        let readyz_route = warp::path("readyz").map(move || {
+            let max_message_age = ready_settings.read().unwrap().ready_max_message_age;
+            let report = ready_clients.health().readiness(ready_clients.has_subscriptions(), max_message_age);
+            health_reply(report)
+        });
+        
+        // Combine routes for proxy server, with CORS for the allowed origins
+        let routes = ws_route
+            .or(publish_post_route)
+            .or(publish_subject_ws_route)
+            .or(publish_ws_route)
+            .or(health_route)
+            .or(healthz_route)
+            .or(readyz_route)
+            .or(metrics_route)
+            .recover(handle_rejection);
+        let cors_auth = auth.clone();
+        let proxy_routes = warp::header::optional::<String>("origin")
+            .and(cors::preflight(auth.clone()).or(routes))
+            .map(move |origin, reply| cors::allow_origin(&cors_auth, origin, reply))
+            .with(warp::log("nex_proxy"));
+        
+        // Start the proxy server
+        let proxy_addr = SocketAddr::from(([0, 0, 0, 0], args.proxy_port));
+        let (proxy_addr, proxy_server) = match &tls {
+            Some(acceptor) => {
+                let listener = bind(proxy_addr).await?;
+                let addr = listener.local_addr()?;
+                (addr, tls::serve(warp::service(proxy_routes), listener, acceptor.clone(), shutdown.clone()).boxed())
+            },
+            None => {
+                let (addr, server) = warp::serve(proxy_routes).try_bind_with_graceful_shutdown(proxy_addr, shutdown.triggered())?;
+                (addr, server.boxed())
+            },
+        };
+        info!("Starting NEX Stream proxy server on {}", proxy_addr);
+        
+        // Create static file server
+        let static_dir = args.static_dir.clone();
+        info!("Serving static files from directory: {}", static_dir);
+        
+        // Favicon handler - simplified approach
+        let static_dir_clone = static_dir.clone();
+        let favicon_path = Path::new(&static_dir_clone).join("favicon.ico");
+        let favicon_path_str = favicon_path.to_string_lossy().to_string();
+        
+        info!("Favicon path: {}", favicon_path_str);
+        
+        // Create a simple route that serves the favicon file directly
+        let favicon_route = warp::path("favicon.ico")
+            .and(warp::get())
+            .map(move || {
+                if favicon_path.exists() {
+                    info!("Serving favicon from: {}", favicon_path_str);
+                    warp::reply::with_header(
+                        warp::reply::html("<link rel='icon' href='data:;base64,iVBORw0KGgo='>"),
+                        "Content-Type", 
+                        "text/html"
+                    )
+                } else {
+                    warn!("Favicon not found at: {}", favicon_path_str);
+                    warp::reply::with_header(
+                        warp::reply::html("<link rel='icon' href='data:;base64,iVBORw0KGgo='>"),
+                        "Content-Type",
+                        "text/html"
+                    )
+                }
+            });
+        
+        // JavaScript files handler
+        let static_dir_for_js = static_dir.clone();
+        let js_files = warp::path::tail()
+            .and_then(move |tail: warp::path::Tail| {
+                let static_dir = static_dir_for_js.clone();
+                async move {
+                    let path_str = tail.as_str();
+                    let file_path = Path::new(&static_dir).join(path_str);
+                
+                    if path_str.ends_with(".js") {
+                        match tokio::fs::read(&file_path).await {
+                            Ok(content) => {
+                                Ok(warp::reply::with_header(
+                                    content,
+                                    "Content-Type",
+                                    "application/javascript",
+                                ))
+                            },
+                            Err(e) => {
+                                error!("Failed to read JS file: {}", e);
+                                Err(warp::reject::not_found())
+                            }
+                        }
+                    } else if path_str.ends_with(".wasm") {
+                        match tokio::fs::read(&file_path).await {
+                            Ok(content) => {
+                                Ok(warp::reply::with_header(
+                                    content,
+                                    "Content-Type",
+                                    "application/wasm",
+                                ))
+                            },
+                            Err(e) => {
+                                error!("Failed to read WASM file: {}", e);
+                                Err(warp::reject::not_found())
+                            }
+                        }
+                    } else {
+                        Err(warp::reject::not_found())
+                    }
+                }
+            });
+        
+        // Static file server with custom MIME type handling
+        let routes = warp::fs::dir(static_dir)
+            .or(favicon_route)
+            .or(js_files);
+        let cors_auth = auth.clone();
+        let static_routes = warp::header::optional::<String>("origin")
+            .and(cors::preflight(auth.clone()).or(routes))
+            .map(move |origin, reply| cors::allow_origin(&cors_auth, origin, reply))
+            .with(warp::log("static_server"));
+        
+        // Start the static file server
+        let static_addr = SocketAddr::from(([0, 0, 0, 0], args.http_port));
+        let (http_addr, static_server) = match tls {
+            Some(acceptor) => {
+                let listener = bind(static_addr).await?;
+                let addr = listener.local_addr()?;
+                (addr, tls::serve(warp::service(static_routes), listener, acceptor, shutdown.clone()).boxed())
+            },
+            None => {
+                let (addr, server) = warp::serve(static_routes).try_bind_with_graceful_shutdown(static_addr, shutdown.triggered())?;
+                (addr, server.boxed())
+            },
+        };
+        info!("Starting static file server on {}", http_addr);
+        
+        // Run both servers concurrently until told to stop
+        let servers = tokio::spawn(async {
+            futures::join!(proxy_server, static_server);
+        });
+        let reconnect_url = args.reconnect_url.clone();
+        Ok(Server {
+            proxy_addr,
+            http_addr,
+            clients: clients.clone(),
+            shutdown,
+            servers,
+            upstream,
+            reconnect_url,
+            reloader: Reloader {
+                started: args,
+                config,
+                auth,
+                publish_auth,
+                settings,
+                clients,
+                limiter,
+            },
+        })
+    }
+
+    // Where the WebSocket proxy and static file server listen, with the
+    // ports picked by the system when 0 was asked for
+    pub fn proxy_addr(&self) -> SocketAddr {
+        self.proxy_addr
+    }
+
+    pub fn http_addr(&self) -> SocketAddr {
+        self.http_addr
+    }
+
+    pub fn clients(&self) -> &Clients {
+        &self.clients
+    }
+
+    // Applies configuration reloads to this server
+    pub fn reloader(&self) -> Reloader {
+        self.reloader.clone()
+    }
+
+    // Stop accepting connections, let the upstream subscriptions hand over
+    // what they already received, then flush and close every client
+    pub async fn stop(self) {
+        self.shutdown.trigger();
+        let _ = self.servers.await;
+        if let Some(upstream) = self.upstream {
+            let _ = upstream.await;
+        }
+        
+        info!("Closing {} client connections", self.clients.client_count());
+        self.clients.shutdown(self.reconnect_url.as_deref());
+        while self.clients.client_count() > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+async fn bind(addr: SocketAddr) -> Result<TcpListener, Box<dyn StdError>> {
+    TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Failed to bind {}: {}", addr, e).into())
+}
+
+// Parse the command line over defaults taken from the configuration file, if
+// any, and `NEX_PROXY_*` environment variables
+pub fn load_args(argv: &[OsString]) -> Result<(Args, Config), clap::Error> {
+    // A first pass only to find the configuration file
+    let path = Args::command()
+        .ignore_errors(true)
+        .try_get_matches_from(argv)
+        .ok()
+        .and_then(|matches| matches.get_one::<String>("config").cloned())
+        .or_else(|| std::env::var(CONFIG_ENV).ok());
+    let invalid = |e: Box<dyn StdError>| Args::command().error(ErrorKind::InvalidValue, format!("Invalid configuration: {}", e));
+    
+    let config = Config::load(path.as_deref().map(Path::new)).map_err(invalid)?;
+    let matches = config.apply_to(Args::command()).try_get_matches_from(argv)?;
+    let mut args = Args::from_arg_matches(&matches)?;
+    args.config = path;
+    validate(&args).map_err(invalid)?;
+    Ok((args, config))
+}
+
+// Checks clap leaves out once values can come from the configuration file,
+// since defaults do not count towards `requires`
+fn validate(args: &Args) -> Result<(), Box<dyn StdError>> {
+    if args.tls_cert.is_some() != args.tls_key.is_some() {
+        return Err("--tls-cert and --tls-key must be given together".into());
+    }
+    if args.nats.nats_cert_file.is_some() != args.nats.nats_key_file.is_some() {
+        return Err("--nats-cert-file and --nats-key-file must be given together".into());
+    }
+    // Port 0 lets the system pick a free port for each
+    if args.proxy_port != 0 && args.proxy_port == args.http_port {
+        return Err(format!("--proxy-port and --http-port are both {}", args.proxy_port).into());
+    }
+    if let Some(url) = &args.reconnect_url {
+        Url::parse(url).map_err(|e| format!("Invalid reconnect URL {}: {}", url, e))?;
+    }
+    Authenticator::default().with_origins(&args.allowed_origins)?;
+    Ok(())
+}
+
+impl Reloader {
+    // Apply limits, keepalive, authentication and allowed origins from a
+    // fresh load of `argv`. New connections and requests see them; open
+    // connections keep the settings they started with, except for the
+    // subscription cap.
+    pub fn reload(&self, argv: &[OsString]) -> Result<(), Box<dyn StdError>> {
+        let (args, config) = load_args(argv)?;
+        self.auth.replace(build_authenticator(&args)?);
+        self.publish_auth.replace(build_publish_authenticator(&args)?);
+        *self.settings.write().unwrap() = LiveSettings::new(&args);
+        self.clients.set_max_subscriptions(args.max_subscriptions);
+        self.limiter.set_max_per_ip(args.max_connections_per_ip);
+        info!("Configuration reloaded");
+        
+        // Compared with startup, so a pending change is repeated on every reload
+        let mut pending = restart_required(&self.started, &args);
+        if config.simulated_instruments() != self.config.simulated_instruments() {
+            pending.push("simulated instruments");
+        }
+        if !pending.is_empty() {
+            warn!("Restart the proxy to apply changes to: {}", pending.join(", "));
+        }
+        Ok(())
+    }
+}
+
+// Changed settings that are only read at startup
+fn restart_required(old: &Args, new: &Args) -> Vec<&'static str> {
+    [
+        ("--proxy-port", old.proxy_port != new.proxy_port),
+        ("--http-port", old.http_port != new.http_port),
+        ("--simulate", old.simulate != new.simulate),
+        ("--nex-url", old.nex_url != new.nex_url),
+        ("--static-dir", old.static_dir != new.static_dir),
+        ("--replay-limit", old.replay_limit != new.replay_limit),
+        ("--history-size", old.history_size != new.history_size),
+        ("--history-max-age", old.history_max_age != new.history_max_age),
+        ("--history-max-subjects", old.history_max_subjects != new.history_max_subjects),
+        ("--history-snapshot-size", old.history_snapshot_size != new.history_snapshot_size),
+        ("--tls-cert", old.tls_cert != new.tls_cert),
+        ("--tls-key", old.tls_key != new.tls_key),
+        ("--tls-reload-interval", old.tls_reload_interval != new.tls_reload_interval),
+        ("--shutdown-timeout", old.shutdown_timeout != new.shutdown_timeout),
+        ("--reconnect-url", old.reconnect_url != new.reconnect_url),
+        ("NATS options", old.nats != new.nats),
+    ]
+    .into_iter()
+    .filter_map(|(name, changed)| changed.then_some(name))
+    .collect()
+}
+
+// Helper function to pass clients to route handlers
+fn with_clients(clients: Clients) -> impl Filter<Extract = (Clients,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || clients.clone())
+}
+
+// Set up the token verifiers and origin allow-list chosen on the command line
+fn build_authenticator(args: &Args) -> Result<Authenticator, Box<dyn StdError>> {
+    let mut auth = Authenticator::default().with_origins(&args.allowed_origins)?;
+    if let Some(path) = &args.token_file {
+        auth = auth.with_verifier(StaticTokens::load(Path::new(path))?);
+        info!("Accepting bearer tokens from {}", path);
+    }
+    if let Some(path) = &args.jwt_secret_file {
+        auth = auth.with_verifier(load_jwt_verifier(path)?);
+        info!("Accepting JWTs signed with the secret in {}", path);
+    }
+    Ok(auth)
+}
+
+fn load_jwt_verifier(path: &str) -> Result<JwtVerifier, Box<dyn StdError>> {
+    let secret = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read JWT secret file {}: {}", path, e))?;
+    Ok(JwtVerifier::new(secret.trim().as_bytes()))
+}
+
+// Set up the tokens and JWTs publishers may use; without any, publishing is only
+// open to connections from this host
+fn build_publish_authenticator(args: &Args) -> Result<Authenticator, Box<dyn StdError>> {
+    let mut auth = Authenticator::default();
+    if let Some(path) = &args.publish_token_file {
+        auth = auth.with_verifier(StaticTokens::load(Path::new(path))?);
+        info!("Accepting publisher tokens from {}", path);
+    }
+    if let Some(path) = &args.publish_jwt_secret_file {
+        auth = auth.with_verifier(load_jwt_verifier(path)?);
+        info!("Accepting publisher JWTs signed with the secret in {}", path);
+    }
+    Ok(auth)
+}
+
+// Reject an unusable URL or NATS TLS and credential options at startup
+// rather than retrying them forever
+async fn check_nats_options(args: &Args) -> Result<(), Box<dyn StdError>> {
+    let url = Url::parse(&args.nex_url).map_err(|e| format!("Invalid NEX Stream URL {}: {}", args.nex_url, e))?;
+    args.nats.connect_options(&url).await?;
+    Ok(())
+}
+
+// Authenticate a WebSocket upgrade from its Origin header and a bearer token
+// in the Authorization header or, for browsers, the `token` query parameter
+fn with_identity(auth: SharedAuthenticator) -> impl Filter<Extract = (Identity,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |origin: Option<String>, authorization: Option<String>, query: HashMap<String, String>| {
+            let auth = auth.current();
+            async move {
+                let token = authorization
+                    .as_deref()
+                    .and_then(bearer_token)
+                    .or_else(|| query.get("token").map(String::as_str));
+                auth.check_origin(origin.as_deref())
+                    .and_then(|_| auth.authenticate(token))
+                    .map_err(|e| {
+                        warn!("Rejected WebSocket client: {}", e);
+                        warp::reject::custom(e)
+                    })
+            }
+        })
+}
+
+// Authenticate a publisher from a bearer token in the Authorization header.
+// Publishers are programs rather than browsers, so there is no Origin or
+// query parameter to look at.
+fn with_publisher(auth: SharedAuthenticator) -> impl Filter<Extract = (Identity,), Error = warp::Rejection> + Clone {
+    tls::remote_addr()
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |remote: Option<SocketAddr>, authorization: Option<String>| {
+            let auth = auth.current();
+            async move {
+                let local = remote.is_some_and(|addr| addr.ip().is_loopback());
+                let result = if local || auth.requires_token() {
+                    auth.authenticate(authorization.as_deref().and_then(bearer_token))
+                } else {
+                    let addr = remote.map_or_else(|| "an unknown address".to_string(), |addr| addr.ip().to_string());
+                    Err(AuthError::NotLocal(addr))
+                };
+                result.map_err(|e| {
+                    warn!("Rejected publisher: {}", e);
+                    warp::reject::custom(e)
+                })
+            }
+        })
+}
+
+// The subject of `/publish/<subject>` and the publisher, who must be
+// allowed to publish to it
+fn with_publish_subject(auth: SharedAuthenticator) -> impl Filter<Extract = (String, Identity), Error = warp::Rejection> + Clone {
+    warp::path!("publish" / String)
+        .and(with_publisher(auth))
+        .and_then(|subject: String, identity: Identity| async move {
+            match publish::check_subject(&subject, &identity) {
+                Ok(()) => Ok((subject, identity)),
+                Err(e) => Err(warp::reject::custom(publish::Rejected(e))),
+            }
+        })
+        .untuple_one()
+}
+
+// Publish the body of `POST /publish/<subject>`: one JSON payload, or one
+// per line when sent as `application/x-ndjson`
+fn publish_request(
+    subject: String,
+    identity: Identity,
+    content_type: Option<String>,
+    body: warp::hyper::body::Bytes,
+    clients: Clients,
+) -> impl warp::Reply {
+    let ndjson = content_type.is_some_and(|t| t.starts_with("application/x-ndjson"));
+    let result = std::str::from_utf8(&body)
+        .map_err(|_| RequestError::new(ErrorCode::InvalidMessage, "Request body is not UTF-8"))
+        .and_then(|body| publish::parse_body(&subject, body, ndjson));
+    match result {
+        Ok(messages) => {
+            for message in &messages {
+                publish::publish(&clients, message);
+            }
+            info!("Published {} messages to {} for {}", messages.len(), subject, identity.name);
+            let reply = ServerReply::published(&subject, messages.len());
+            warp::reply::with_status(warp::reply::json(&reply), StatusCode::OK)
+        }
+        Err(e) => {
+            warn!("Rejected publish to {} from {}: {}", subject, identity.name, e.message);
+            let status = publish::Rejected(e.clone()).status();
+            warp::reply::with_status(warp::reply::json(&ServerReply::error(e)), status)
+        }
+    }
+}
+
+// Serve a health report, with 503 when something is not ok
+fn health_reply(report: Report) -> impl warp::Reply {
+    let status = if report.is_ok() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    warp::reply::with_status(warp::reply::json(&report), status)
+}
+
+// Answer failed authentication and refused publish requests with a JSON
+// error instead of a bare 404
+async fn handle_rejection(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(rejected) = rejection.find::<publish::Rejected>() {
+        let reply = ServerReply::error(rejected.0.clone());
+        return Ok(warp::reply::with_status(warp::reply::json(&reply), rejected.status()));
+    }
+    let Some(e) = rejection.find::<AuthError>() else {
+        return Err(rejection);
+    };
+    let (status, code) = match e {
+        AuthError::OriginNotAllowed(_) | AuthError::NotLocal(_) => (StatusCode::FORBIDDEN, ErrorCode::Forbidden),
+        _ => (StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized),
+    };
+    let reply = ServerReply::error(RequestError::new(code, e.to_string()));
+    Ok(warp::reply::with_status(warp::reply::json(&reply), status))
+}
+
+// Turn away a client over its connection limit, telling it why before closing
+async fn reject_connection(mut ws: WebSocket, ip: IpAddr, max_per_ip: usize, metrics: &Metrics) {
+    warn!("Rejecting connection from {}: already {} open", ip, max_per_ip);
+    metrics.limit_violations.with_label_values(&["connections"]).inc();
+    
+    let error = RequestError::new(
+        ErrorCode::ConnectionLimit,
+        format!("Too many connections from {} (limit {})", ip, max_per_ip),
+    );
+    let _ = ws.send(Message::text(ServerReply::error(error).to_json())).await;
+    let _ = ws.send(Message::close_with(1008u16, "too many connections")).await;
+    let _ = ws.close().await;
+}
+
+// Numbers client IDs, which stay unique for the life of the process
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Handle WebSocket connection
+async fn handle_connection(
+    ws: WebSocket,
+    clients: Clients,
+    identity: Identity,
+    config: ConnectionConfig,
+    metrics: Arc<Metrics>,
+    _slot: Option<ConnectionGuard>,
+) {
+    let heartbeat = config.heartbeat;
+    
+    // Split the socket into sender and receiver
+    let (ws_tx, mut ws_rx) = ws.split();
+    
+    // Use a bounded queue so a stalled client cannot grow memory without limit
+    let queue = Arc::new(ClientQueue::new(config.queue).with_metrics(metrics.clone()));
+    
+    // Generate a client ID
+    let client_id = format!("client-{}", NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed));
+    info!("New client connected: {} ({})", client_id, identity.name);
+    
+    // Register the client so matching messages reach its queue
+    clients.add_client(&client_id, queue.clone(), identity.permissions.clone());
+    
+    // Forward messages from the queue to the WebSocket
+    let writer_queue = queue.clone();
+    tokio::task::spawn(async move {
+        let mut ws_tx = ws_tx;
+        while let Some(outgoing) = writer_queue.pop().await {
+            let msg = match outgoing {
+                Outgoing::Message(msg) => msg,
+                Outgoing::Close(close) => Message::close_with(close.code, close.reason),
+            };
+            if let Err(e) = ws_tx.send(msg).await {
+                error!("WebSocket send error: {}", e);
+                break;
+            }
+        }
+        let _ = ws_tx.close().await;
+    });
+    
+    // Ping on every tick; any frame from the client, a pong or otherwise,
+    // shows it is still there
+    let heartbeat_enabled = !heartbeat.interval.is_zero();
+    let period = if heartbeat_enabled { heartbeat.interval } else { Duration::from_secs(3600) };
+    let mut heartbeats = interval_at(Instant::now() + period, period);
+    let mut missed = 0;
+    
+    // Disconnect the client when its token expires
+    let expiry = identity
+        .expires_at
+        .map(|at| at.duration_since(SystemTime::now()).unwrap_or_default());
+    let token_expired = tokio::time::sleep(expiry.unwrap_or(Duration::from_secs(3600)));
+    tokio::pin!(token_expired);
+    
+    // Limit how fast the client may send control messages
+    let mut control_rate = config.control_rate.map(|(rate, burst)| TokenBucket::new(rate, burst));
+    
+    // Handle incoming messages until the client leaves or is disconnected
+    loop {
+        let result = tokio::select! {
+            result = ws_rx.next() => result,
+            _ = queue.closed() => {
+                warn!("Disconnecting slow client {} after {} dropped messages", client_id, queue.dropped());
+                break;
+            }
+            _ = heartbeats.tick(), if heartbeat_enabled => {
+                if missed >= heartbeat.max_missed {
+                    warn!("Disconnecting client {} after {} unanswered pings", client_id, missed);
+                    queue.close(Some(CloseReason::new(1001, "heartbeat timeout")));
+                    break;
+                }
+                missed += 1;
+                queue.push_reply(Message::ping(Vec::new()));
+                clients.send_heartbeats(&client_id);
+                continue;
+            }
+            _ = &mut token_expired, if expiry.is_some() => {
+                info!("Disconnecting client {}: token expired", client_id);
+                queue.close(Some(CloseReason::new(1008, "token expired")));
+                break;
+            }
+        };
+        match result {
+            Some(Ok(msg)) => {
+                missed = 0;
+                
+                // Refuse control messages beyond the client's rate limit
+                let limited = msg.is_text() && control_rate.as_mut().is_some_and(|bucket| !bucket.try_acquire());
+                if limited {
+                    warn!("Rate limiting control messages from {}", client_id);
+                    metrics.limit_violations.with_label_values(&["rate"]).inc();
+                    let error = RequestError::new(ErrorCode::RateLimited, "Too many control messages; slow down");
+                    queue.push_reply(Message::text(ServerReply::error(error).to_json()));
+                    continue;
+                }
+                
+                // Process the message
+                if let Err(e) = process_message(msg, &client_id, &clients).await {
+                    error!("Error processing message: {}", e);
+                    break;
+                }
+            }
+            Some(Err(e)) => {
+                error!("WebSocket error: {}", e);
+                break;
+            }
+            None => break,
+        }
+    }
+    
+    // Client disconnected
+    info!("Client disconnected: {} ({} messages dropped)", client_id, queue.dropped());
+    clients.remove_client(&client_id);
+    queue.close(None);
+}
+
+// Process incoming WebSocket messages
+async fn process_message(msg: Message, client_id: &str, clients: &Clients) -> Result<(), Box<dyn StdError>> {
+    // Skip processing non-text messages
+    if !msg.is_text() {
+        return Ok(());
+    }
+    
+    let text = match msg.to_str() {
+        Ok(text) => text,
+        Err(_) => return Err("Message is not valid UTF-8".into()),
+    };
+    info!("Received message from {}: {}", client_id, text);
+    
+    // Parse and apply the control message, turning failures into error replies
+    clients.handle_message(client_id, text);
+    
+    Ok(())
+}
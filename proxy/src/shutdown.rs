@@ -0,0 +1,45 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+// Process-wide shutdown signal, triggered once by SIGINT or SIGTERM and
+// watched by the listeners, the upstream connection and the simulator
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Shutdown {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    // Resolves once shutdown has been triggered, straight away if it
+    // already has been
+    pub fn triggered(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut receiver = self.receiver.clone();
+        async move {
+            let _ = receiver.wait_for(|triggered| *triggered).await;
+        }
+    }
+}
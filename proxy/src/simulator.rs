@@ -0,0 +1,175 @@
+// Market simulator for running without an upstream. Every instrument has a
+// fair value taking a random walk at its own tick rate, part of each move
+// coming from one common market factor, so instruments rise and fall
+// together the way crypto pairs tend to. Trades, book updates and tickers
+// come from a simulated order book following that fair value.
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use log::info;
+use market_sim::market::standard_normal;
+use market_sim::{Channel, Feed, Market, MarketConfig};
+use tokio::task::JoinSet;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+
+use crate::protocol::NexStreamMessage;
+use crate::registry::Clients;
+use crate::shutdown::Shutdown;
+
+// One simulated trading pair
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instrument {
+    // Subject template with `{channel}` standing for the channel name
+    pub subject: String,
+    // Trading pair reported in each message, e.g. `BTC-USD`
+    pub pair: String,
+    pub initial_price: f64,
+    // Standard deviation of each fair value move, in percent
+    pub volatility: f64,
+    // Between fair value moves and between published batches of messages
+    pub interval: Duration,
+    // Loading on the common factor, from -1 to 1. The moves of two
+    // instruments ticking together correlate by the product of their loadings.
+    pub correlation: f64,
+    pub market: MarketConfig,
+}
+
+impl Instrument {
+    pub fn subject(&self, channel: Channel) -> String {
+        self.subject.replace("{channel}", channel.name())
+    }
+
+    // Order flow suited to an instrument moving `volatility` percent every
+    // `interval`
+    pub fn market_for(price: f64, volatility: f64, interval: Duration) -> MarketConfig {
+        let per_second = volatility / interval.as_secs_f64().max(1e-3).sqrt();
+        MarketConfig::for_price(price, per_second)
+    }
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        let interval = Duration::from_millis(1000);
+        Instrument {
+            subject: "market.btc-usd.{channel}".to_string(),
+            pair: "BTC-USD".to_string(),
+            initial_price: 30000.0,
+            volatility: 0.3,
+            interval,
+            correlation: 0.7,
+            market: Instrument::market_for(30000.0, 0.3, interval),
+        }
+    }
+}
+
+// The common factor as a Brownian motion, advanced whenever an instrument
+// looks at it. Instruments with different tick rates thereby see the same
+// underlying path, each over its own intervals.
+#[derive(Debug)]
+struct MarketFactor {
+    // Current level and when it was reached
+    state: Mutex<(f64, Instant)>,
+}
+
+impl MarketFactor {
+    fn new() -> Self {
+        MarketFactor {
+            state: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    fn sample(&self) -> (f64, Instant) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.1).as_secs_f64();
+        state.0 += elapsed.sqrt() * standard_normal(&mut rand::thread_rng());
+        state.1 = now;
+        *state
+    }
+}
+
+// An instrument's fair value and order book, and where it last read the
+// market factor
+struct Walk {
+    instrument: Instrument,
+    feed: Feed,
+    fair_value: f64,
+    factor_level: f64,
+    factor_at: Instant,
+}
+
+impl Walk {
+    fn new(instrument: Instrument, factor: &MarketFactor) -> Self {
+        let (factor_level, factor_at) = factor.sample();
+        let market = Market::new(instrument.market.clone(), instrument.initial_price);
+        Walk {
+            feed: Feed::new(market, instrument.pair.clone(), "nex"),
+            fair_value: instrument.initial_price,
+            instrument,
+            factor_level,
+            factor_at,
+        }
+    }
+
+    // Move the fair value, run the order book up to now and report what
+    // happened in it
+    fn step(&mut self, factor: &MarketFactor) -> Vec<NexStreamMessage> {
+        let mut rng = rand::thread_rng();
+
+        // The factor's move since the last tick, scaled to unit variance
+        let (level, at) = factor.sample();
+        let elapsed = at.duration_since(self.factor_at);
+        let common = match elapsed.as_secs_f64() {
+            seconds if seconds > 0.0 => (level - self.factor_level) / seconds.sqrt(),
+            _ => 0.0,
+        };
+        self.factor_level = level;
+        self.factor_at = at;
+
+        let loading = self.instrument.correlation;
+        let shock = loading * common + (1.0 - loading * loading).sqrt() * standard_normal(&mut rng);
+        // Log-normal steps keep the price positive
+        self.fair_value *= (self.instrument.volatility / 100.0 * shock).exp();
+        self.feed.market_mut().set_fair_value(self.fair_value);
+
+        let timestamp = Utc::now().timestamp_millis() as u64;
+        self.feed
+            .advance(elapsed, timestamp)
+            .into_iter()
+            .map(|message| NexStreamMessage::wrap(self.instrument.subject(message.channel), &message.data, Some(timestamp)))
+            .collect()
+    }
+}
+
+// Publish every instrument to `clients` until shutdown
+pub async fn run(clients: Clients, instruments: Vec<Instrument>, shutdown: Shutdown) {
+    info!("Simulating {} instruments", instruments.len());
+    let factor = Arc::new(MarketFactor::new());
+
+    let mut walks = JoinSet::new();
+    for instrument in instruments {
+        info!("Simulating {} on {} every {:?}", instrument.pair, instrument.subject, instrument.interval);
+        let clients = clients.clone();
+        let factor = factor.clone();
+        let stop = shutdown.triggered();
+        walks.spawn(async move {
+            let mut ticks = interval(instrument.interval);
+            // Under load, fall behind rather than catch up in a burst
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            let mut walk = Walk::new(instrument, &factor);
+            tokio::pin!(stop);
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {},
+                    _ = &mut stop => return,
+                }
+                for message in walk.step(&factor) {
+                    let message_json = serde_json::to_string(&message).unwrap();
+                    clients.broadcast(&message, &message_json, None);
+                }
+            }
+        });
+    }
+    while walks.join_next().await.is_some() {}
+}
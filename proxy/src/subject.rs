@@ -0,0 +1,307 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+// Errors for malformed subjects and subscription patterns
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectError {
+    Empty,
+    EmptyToken,
+    Whitespace,
+    Wildcard(String),
+    FullWildcardNotLast,
+}
+
+impl fmt::Display for SubjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubjectError::Empty => write!(f, "subject is empty"),
+            SubjectError::EmptyToken => write!(f, "subject contains an empty token"),
+            SubjectError::Whitespace => write!(f, "subject contains whitespace"),
+            SubjectError::Wildcard(token) => write!(f, "invalid wildcard in token '{}'", token),
+            SubjectError::FullWildcardNotLast => write!(f, "'>' must be the last token"),
+        }
+    }
+}
+
+impl std::error::Error for SubjectError {}
+
+// Validate a subscription pattern: dot-separated tokens where `*` matches
+// exactly one token and `>` (last token only) matches one or more tokens
+pub fn validate_pattern(pattern: &str) -> Result<(), SubjectError> {
+    if pattern.is_empty() {
+        return Err(SubjectError::Empty);
+    }
+    if pattern.chars().any(char::is_whitespace) {
+        return Err(SubjectError::Whitespace);
+    }
+
+    let tokens: Vec<&str> = pattern.split('.').collect();
+    for (i, token) in tokens.iter().enumerate() {
+        match *token {
+            "" => return Err(SubjectError::EmptyToken),
+            "*" => {}
+            ">" if i + 1 == tokens.len() => {}
+            ">" => return Err(SubjectError::FullWildcardNotLast),
+            t if t.contains('*') || t.contains('>') => {
+                return Err(SubjectError::Wildcard(t.to_string()));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Whether a pattern has no wildcards, and so matches only itself
+pub fn is_literal(pattern: &str) -> bool {
+    !pattern.split('.').any(|token| token == "*" || token == ">")
+}
+
+// Which of `shards` a subject belongs to, for state split by subject
+pub fn shard_index(subject: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    subject.hash(&mut hasher);
+    hasher.finish() as usize % shards
+}
+
+// Validate a concrete subject to publish on: a pattern without wildcards
+pub fn validate_subject(subject: &str) -> Result<(), SubjectError> {
+    validate_pattern(subject)?;
+    match subject.split('.').find(|token| *token == "*" || *token == ">") {
+        Some(token) => Err(SubjectError::Wildcard(token.to_string())),
+        None => Ok(()),
+    }
+}
+
+// Check whether a concrete subject matches a subscription pattern
+pub fn matches(pattern: &str, subject: &str) -> bool {
+    let mut pattern_tokens = pattern.split('.');
+    let mut subject_tokens = subject.split('.');
+
+    loop {
+        match (pattern_tokens.next(), subject_tokens.next()) {
+            (Some(">"), Some(_)) => return true,
+            (Some("*"), Some(_)) => {}
+            (Some(p), Some(s)) if p == s => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+// Check whether every subject matched by `pattern` is also matched by
+// `allowed`, e.g. `market.btc-usd.*` is covered by `market.>`
+pub fn is_subset(pattern: &str, allowed: &str) -> bool {
+    let mut pattern_tokens = pattern.split('.');
+    let mut allowed_tokens = allowed.split('.');
+
+    loop {
+        match (pattern_tokens.next(), allowed_tokens.next()) {
+            (Some(_), Some(">")) => return true,
+            (Some(">"), Some(_)) => return false,
+            (Some(_), Some("*")) => {}
+            (Some(p), Some(a)) if p == a => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+// One level of the trie: literal children, the `*` child, and the values
+// subscribed with a `>` at this level or with a pattern ending here
+#[derive(Debug)]
+struct Node<T> {
+    literals: HashMap<String, Node<T>>,
+    single: Option<Box<Node<T>>>,
+    full: HashSet<T>,
+    values: HashSet<T>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Node {
+            literals: HashMap::new(),
+            single: None,
+            full: HashSet::new(),
+            values: HashSet::new(),
+        }
+    }
+}
+
+impl<T> Node<T> {
+    fn is_empty(&self) -> bool {
+        self.literals.is_empty() && self.single.is_none() && self.full.is_empty() && self.values.is_empty()
+    }
+}
+
+// Trie of subscription patterns keyed by subject token, so routing a
+// message costs one walk over its tokens instead of a scan of every pattern
+#[derive(Debug)]
+pub struct SubjectTrie<T> {
+    root: Node<T>,
+}
+
+impl<T> Default for SubjectTrie<T> {
+    fn default() -> Self {
+        SubjectTrie {
+            root: Node::default(),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> SubjectTrie<T> {
+    // Register `value` under `pattern`; returns false if it was already present
+    pub fn insert(&mut self, pattern: &str, value: T) -> Result<bool, SubjectError> {
+        validate_pattern(pattern)?;
+
+        let mut node = &mut self.root;
+        for token in pattern.split('.') {
+            match token {
+                ">" => return Ok(node.full.insert(value)),
+                "*" => node = node.single.get_or_insert_with(Default::default),
+                literal => node = node.literals.entry(literal.to_string()).or_default(),
+            }
+        }
+
+        Ok(node.values.insert(value))
+    }
+
+    // Remove `value` from `pattern`, pruning branches left empty; returns
+    // false if the pair was not registered
+    pub fn remove(&mut self, pattern: &str, value: &T) -> bool {
+        let tokens: Vec<&str> = pattern.split('.').collect();
+        Self::remove_from(&mut self.root, &tokens, value)
+    }
+
+    fn remove_from(node: &mut Node<T>, tokens: &[&str], value: &T) -> bool {
+        let Some((token, rest)) = tokens.split_first() else {
+            return node.values.remove(value);
+        };
+
+        match *token {
+            ">" => node.full.remove(value),
+            "*" => {
+                let Some(child) = node.single.as_deref_mut() else {
+                    return false;
+                };
+                let removed = Self::remove_from(child, rest, value);
+                if child.is_empty() {
+                    node.single = None;
+                }
+                removed
+            }
+            literal => {
+                let Some(child) = node.literals.get_mut(literal) else {
+                    return false;
+                };
+                let removed = Self::remove_from(child, rest, value);
+                if child.is_empty() {
+                    node.literals.remove(literal);
+                }
+                removed
+            }
+        }
+    }
+
+    // Collect every value whose pattern matches the concrete `subject`,
+    // each value reported once even if several of its patterns match
+    pub fn matches(&self, subject: &str) -> HashSet<&T> {
+        let tokens: Vec<&str> = subject.split('.').collect();
+        let mut found = HashSet::new();
+        Self::collect(&self.root, &tokens, &mut found);
+        found
+    }
+
+    fn collect<'a>(node: &'a Node<T>, tokens: &[&str], found: &mut HashSet<&'a T>) {
+        let Some((token, rest)) = tokens.split_first() else {
+            found.extend(node.values.iter());
+            return;
+        };
+
+        // `>` needs at least one remaining token, which we have here
+        found.extend(node.full.iter());
+
+        if let Some(child) = node.literals.get(*token) {
+            Self::collect(child, rest, found);
+        }
+        if let Some(child) = node.single.as_deref() {
+            Self::collect(child, rest, found);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_wildcard_only_in_last_token() {
+        assert_eq!(validate_pattern("market.>"), Ok(()));
+        assert_eq!(validate_pattern("market.>.trades"), Err(SubjectError::FullWildcardNotLast));
+        assert_eq!(validate_pattern(">.trades"), Err(SubjectError::FullWildcardNotLast));
+        assert_eq!(validate_pattern("market.btc>"), Err(SubjectError::Wildcard("btc>".to_string())));
+        assert_eq!(validate_subject("market.>"), Err(SubjectError::Wildcard(">".to_string())));
+
+        // `>` needs at least one token to match, and takes any number
+        assert!(matches("market.>", "market.btc-usd"));
+        assert!(matches("market.>", "market.btc-usd.trades"));
+        assert!(!matches("market.>", "market"));
+        assert!(matches(">", "market"));
+    }
+
+    #[test]
+    fn single_wildcard_matches_exactly_one_token() {
+        assert!(matches("market.*.trades", "market.btc-usd.trades"));
+        assert!(!matches("market.*.trades", "market.btc-usd.spot.trades"));
+        assert!(!matches("market.*", "market.btc-usd.trades"));
+        assert!(!matches("market.*", "market"));
+        assert!(matches("*.*.*", "market.btc-usd.trades"));
+        assert!(!matches("*.*", "market.btc-usd.trades"));
+        assert!(!matches("market.btc-usd", "market.btc-usd.trades"));
+    }
+
+    #[test]
+    fn subsets_of_overlapping_wildcards() {
+        assert!(is_subset("market.btc-usd.trades", "market.*.trades"));
+        assert!(is_subset("market.*.trades", "market.>"));
+        assert!(is_subset("market.*.trades", "market.*.*"));
+        assert!(is_subset("market.btc-usd.>", "market.>"));
+        assert!(is_subset("market.>", "market.>"));
+        assert!(!is_subset("market.>", "market.*.trades"));
+        assert!(!is_subset("market.>", "market.*"));
+        assert!(!is_subset("market.*.trades", "market.btc-usd.trades"));
+        assert!(!is_subset("market.*.*", "market.*.trades"));
+        assert!(!is_subset("market", "market.>"));
+    }
+
+    #[test]
+    fn trie_routes_and_prunes() {
+        let mut trie = SubjectTrie::default();
+        for (pattern, value) in [("market.btc-usd.trades", 1), ("market.*.trades", 2), ("market.>", 3), ("market.*.trades", 4)] {
+            assert_eq!(trie.insert(pattern, value), Ok(true));
+        }
+        assert_eq!(trie.insert("market.>", 3), Ok(false));
+        assert!(trie.insert("market.>.trades", 5).is_err());
+
+        let found = |trie: &SubjectTrie<i32>, subject: &str| {
+            let mut values: Vec<i32> = trie.matches(subject).into_iter().copied().collect();
+            values.sort();
+            values
+        };
+        assert_eq!(found(&trie, "market.btc-usd.trades"), vec![1, 2, 3, 4]);
+        assert_eq!(found(&trie, "market.eth-usd.trades"), vec![2, 3, 4]);
+        assert_eq!(found(&trie, "market.eth-usd.ticker"), vec![3]);
+        assert_eq!(found(&trie, "market"), Vec::<i32>::new());
+
+        assert!(trie.remove("market.*.trades", &2));
+        assert!(!trie.remove("market.*.trades", &2));
+        assert!(!trie.remove("market.eth-usd.trades", &1));
+        assert_eq!(found(&trie, "market.eth-usd.trades"), vec![3, 4]);
+
+        for (pattern, value) in [("market.btc-usd.trades", 1), ("market.>", 3), ("market.*.trades", 4)] {
+            assert!(trie.remove(pattern, &value));
+        }
+        assert!(trie.root.is_empty(), "no stale nodes are left: {:?}", trie.root);
+    }
+}
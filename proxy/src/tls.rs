@@ -0,0 +1,222 @@
+use std::convert::Infallible;
+use std::error::Error as StdError;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use hyper::{Body, Request, Response};
+use log::{debug, error, info, warn};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use warp::Filter;
+
+use crate::shutdown::Shutdown;
+
+// Longest a client may take over the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A certificate and key read from PEM files, replaced when the files
+// change. Only new handshakes see the replacement; established
+// connections, WebSockets included, keep the session they started with.
+pub struct ReloadingCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    // Modification times of the certificate and key last read
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadingCert {
+    pub fn load(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Result<Arc<Self>, Box<dyn StdError>> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let modified = (modified(&cert_path), modified(&key_path));
+        let current = read_certified_key(&cert_path, &key_path)?;
+        Ok(Arc::new(ReloadingCert {
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(current)),
+            modified: Mutex::new(modified),
+        }))
+    }
+
+    // Reread the files if either has changed since they were last read. A
+    // pair that does not load, e.g. one caught halfway through renewal,
+    // leaves the current certificate in place until the files change again.
+    pub fn reload_if_changed(&self) -> bool {
+        let now = (modified(&self.cert_path), modified(&self.key_path));
+        {
+            let mut last = self.modified.lock().unwrap();
+            if *last == now {
+                return false;
+            }
+            *last = now;
+        }
+
+        match read_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().unwrap() = Arc::new(key);
+                info!("Reloaded TLS certificate from {}", self.cert_path.display());
+                true
+            },
+            Err(e) => {
+                warn!("Keeping the current TLS certificate: {}", e);
+                false
+            }
+        }
+    }
+
+    // Check the files for changes every `interval`
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let certs = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                certs.reload_if_changed();
+            }
+        });
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        // WebSockets upgrade over HTTP/1.1 only
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn read_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, Box<dyn StdError>> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+    };
+
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut open(cert_path)?)
+        .map_err(|e| format!("Invalid certificate in {}: {}", cert_path.display(), e))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", cert_path.display()).into());
+    }
+
+    let key = rustls_pemfile::read_all(&mut open(key_path)?)
+        .map_err(|e| format!("Invalid private key in {}: {}", key_path.display(), e))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No private key found in {}", key_path.display()))?;
+    let key = any_supported_type(&key).map_err(|e| format!("Unusable private key in {}: {}", key_path.display(), e))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+// Address of the client on a connection accepted by `serve`, which warp's
+// own `addr::remote` filter cannot see
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+// The client's address, whether warp accepted the connection or `serve` did
+pub fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddr>())
+        .map(|remote: Option<SocketAddr>, peer: Option<PeerAddr>| peer.map(|peer| peer.0).or(remote))
+}
+
+// Serve `service`, usually `warp::service(routes)`, over TLS on `listener`
+// until `shutdown` is triggered, then finish the requests in flight
+pub async fn serve<S>(service: S, listener: TcpListener, acceptor: TlsAcceptor, shutdown: Shutdown)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let addr = listener.local_addr().map_or_else(|_| "TLS listener".to_string(), |addr| addr.to_string());
+    let mut connections = JoinSet::new();
+    let stop = shutdown.triggered();
+    tokio::pin!(stop);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut stop => break,
+            // Reap finished connections as they go
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept connection on {}: {}", addr, e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let mut service = service.clone();
+        let shutdown = shutdown.clone();
+
+        // Handshake off the accept loop so a slow client holds up nobody else
+        connections.spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                },
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", peer);
+                    return;
+                }
+            };
+
+            let service = service_fn(move |mut request: Request<Body>| {
+                request.extensions_mut().insert(PeerAddr(peer));
+                service.call(request)
+            });
+            let connection = Http::new().serve_connection(stream, service).with_upgrades();
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown.triggered() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                debug!("Connection from {} ended with an error: {}", peer, e);
+            }
+        });
+    }
+
+    // Upgraded WebSockets have left hyper by now, so this only waits for
+    // plain requests
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+}
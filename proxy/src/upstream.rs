@@ -0,0 +1,671 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error as StdError;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_nats::jetstream::{self, consumer, response::Response};
+use async_nats::{Event, Subscriber};
+use chrono::Utc;
+use futures::{Stream, StreamExt};
+use log::{debug, error, info, warn};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use nats_config::NatsArgs;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::StreamMap;
+use url::Url;
+
+use crate::health::UpstreamState;
+use crate::protocol::{NexStreamMessage, ReplaySpec};
+use crate::registry::{Clients, MessageKey};
+use crate::shutdown::Shutdown;
+use crate::subject::SubjectTrie;
+
+// Changes in client demand, sent by the registry whenever the first client
+// subscribes to a pattern or the last one leaves it, plus requests to
+// replay JetStream history to a single client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamCommand {
+    Subscribe(String),
+    Unsubscribe(String),
+    Replay {
+        client_id: String,
+        pattern: String,
+        replay: ReplaySpec,
+    },
+}
+
+// First and longest pauses between attempts to reach the NATS server, or
+// to open an upstream subscription the server refused
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// How long a copy of a message still expected on another overlapping
+// subscription is waited for
+const COPY_WINDOW: Duration = Duration::from_secs(5);
+
+// An upstream subscription that yields `None` once when it ends, which
+// outside of shutdown only happens when the NATS client has given up on
+// the connection
+struct UpstreamSubscription {
+    subscriber: Subscriber,
+    ended: bool,
+}
+
+impl Stream for UpstreamSubscription {
+    type Item = Option<async_nats::Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+        match self.subscriber.poll_next_unpin(cx) {
+            Poll::Ready(None) => {
+                self.ended = true;
+                Poll::Ready(Some(None))
+            },
+            other => other.map(|msg| msg.map(Some)),
+        }
+    }
+}
+
+// Why a connected session stopped
+enum SessionEnd {
+    // The proxy is shutting down or the registry went away
+    Closed,
+    // The NATS client gave up reconnecting on its own
+    Lost,
+}
+
+// Keep a connection to a real NEX Stream for as long as the proxy runs and
+// forward messages to clients. Each session resubscribes to every pattern
+// clients currently want; between sessions the supervisor backs off.
+pub async fn supervise_nex_stream(
+    nex_url: String,
+    nats: NatsArgs,
+    clients: Clients,
+    mut commands: mpsc::UnboundedReceiver<UpstreamCommand>,
+    replay_limit: u64,
+    shutdown: Shutdown,
+) {
+    let url = match Url::parse(&nex_url) {
+        Ok(url) => url,
+        Err(e) => {
+            error!("Invalid NEX Stream URL {}: {}", nex_url, e);
+            clients.set_upstream_state(UpstreamState::Failed);
+            return;
+        }
+    };
+
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match run_session(&url, &nats, &clients, &mut commands, replay_limit, &shutdown).await {
+            Ok(SessionEnd::Closed) => {
+                info!("NEX Stream connection closed");
+                return;
+            },
+            Ok(SessionEnd::Lost) => {
+                warn!("Lost the NEX Stream connection");
+                clients.set_upstream_state(UpstreamState::Disconnected);
+                backoff = INITIAL_BACKOFF;
+            },
+            // Stays `Connecting` until the first connection succeeds
+            Err(e) => error!("Failed to connect to NATS server: {}", e),
+        }
+        if shutdown.is_triggered() {
+            return;
+        }
+
+        // Spread out reconnecting proxies so they do not arrive together
+        let delay = backoff.mul_f64(thread_rng().gen_range(0.5..1.0));
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        info!("Reconnecting to NEX Stream in {:.1}s", delay.as_secs_f64());
+        if !wait_for_retry(delay, &clients, &mut commands, &shutdown).await {
+            return;
+        }
+    }
+}
+
+// Sit out the backoff while answering commands. Demand changes need no
+// action since the next session subscribes to whatever is wanted then, but
+// replays cannot be served. Returns false when it is time to stop.
+async fn wait_for_retry(
+    delay: Duration,
+    clients: &Clients,
+    commands: &mut mpsc::UnboundedReceiver<UpstreamCommand>,
+    shutdown: &Shutdown,
+) -> bool {
+    let retry = tokio::time::sleep(delay);
+    tokio::pin!(retry);
+    let stop = shutdown.triggered();
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            _ = &mut retry => return true,
+            _ = &mut stop => return false,
+            command = commands.recv() => match command {
+                Some(UpstreamCommand::Replay { client_id, pattern, .. }) => {
+                    clients.finish_replay(&client_id, &pattern, Err("upstream unavailable".to_string()));
+                },
+                Some(_) => {},
+                None => return false,
+            },
+        }
+    }
+}
+
+// One connection to the NATS server, keeping one upstream subscription per
+// pattern that at least one client wants
+async fn run_session(
+    url: &Url,
+    nats: &NatsArgs,
+    clients: &Clients,
+    commands: &mut mpsc::UnboundedReceiver<UpstreamCommand>,
+    replay_limit: u64,
+    shutdown: &Shutdown,
+) -> Result<SessionEnd, Box<dyn StdError>> {
+    info!("Connecting to NEX Stream at {}", url);
+
+    // TLS and credentials from the command line, read afresh each session
+    let mut options = nats.connect_options(url).await?;
+
+    // Follow the client's own reconnects for metrics, readiness and clients
+    let events = clients.clone();
+    options = options.event_callback(move |event| {
+        record_event(&events, &event);
+        async {}
+    });
+
+    // Connect to NATS server
+    info!("Establishing connection to NATS server...");
+    let client = options.connect(url.as_str()).await?;
+    info!("Successfully connected to NATS server");
+    clients.set_upstream_state(UpstreamState::Connected);
+
+    // Create JetStream context, used to replay history to new subscribers
+    let jetstream = jetstream::new(client.clone());
+
+    // Upstream subscriptions keyed by the pattern clients asked for,
+    // starting with whatever clients wanted before this session
+    let mut subscriptions = Subscriptions::default();
+    for pattern in clients.demanded_patterns() {
+        subscriptions.subscribe(&client, pattern, INITIAL_BACKOFF).await;
+    }
+
+    // Process demand changes, subscription retries and incoming messages
+    info!("Listening for messages from NEX Stream...");
+    let stop = shutdown.triggered();
+    tokio::pin!(stop);
+    loop {
+        let next_retry = subscriptions.next_retry();
+        tokio::select! {
+            _ = &mut stop => {
+                subscriptions.drain(clients, &client).await;
+                return Ok(SessionEnd::Closed);
+            },
+            _ = tokio::time::sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => {
+                subscriptions.retry_due(&client).await;
+            },
+            command = commands.recv() => match command {
+                Some(UpstreamCommand::Subscribe(pattern)) => {
+                    subscriptions.subscribe(&client, pattern, INITIAL_BACKOFF).await;
+                },
+                Some(UpstreamCommand::Unsubscribe(pattern)) => {
+                    subscriptions.unsubscribe(&pattern);
+                },
+                Some(UpstreamCommand::Replay { client_id, pattern, replay }) => {
+                    // The live subscription was requested first on this same
+                    // connection, so nothing falls between replay and live
+                    tokio::spawn(replay_history(
+                        client.clone(),
+                        jetstream.clone(),
+                        clients.clone(),
+                        client_id,
+                        pattern,
+                        replay,
+                        replay_limit,
+                    ));
+                },
+                None => return Ok(SessionEnd::Closed),
+            },
+            Some((pattern, msg)) = subscriptions.streams.next(), if !subscriptions.streams.is_empty() => match msg {
+                Some(msg) => subscriptions.forward(clients, &pattern, msg),
+                None => return Ok(SessionEnd::Lost),
+            }
+        }
+    }
+}
+
+// The upstream subscriptions of one session, the patterns the server
+// refused and when to ask again, and the copies of recent messages still
+// expected on other subscriptions
+#[derive(Default)]
+struct Subscriptions {
+    streams: StreamMap<String, UpstreamSubscription>,
+    patterns: SubjectTrie<String>,
+    // Pattern -> next attempt and the pause before the one after
+    retries: HashMap<String, (Instant, Duration)>,
+    copies: Copies,
+}
+
+impl Subscriptions {
+    // Subscribe upstream unless the session already carries `pattern`,
+    // which happens when a demand change was queued before the session
+    // resubscribed. A refused subscription is tried again after `backoff`.
+    async fn subscribe(&mut self, client: &async_nats::Client, pattern: String, backoff: Duration) {
+        if self.streams.contains_key(&pattern) {
+            return;
+        }
+        match client.subscribe(pattern.clone()).await {
+            Ok(sub) => {
+                info!("Successfully subscribed to {}", pattern);
+                if let Err(e) = self.patterns.insert(&pattern, pattern.clone()) {
+                    warn!("Cannot match messages against {}: {}", pattern, e);
+                }
+                self.streams.insert(pattern, UpstreamSubscription { subscriber: sub, ended: false });
+            },
+            Err(e) => {
+                let delay = backoff.mul_f64(thread_rng().gen_range(0.5..1.0));
+                error!("Failed to subscribe to {}, retrying in {:.1}s: {}", pattern, delay.as_secs_f64(), e);
+                self.retries.insert(pattern, (Instant::now() + delay, (backoff * 2).min(MAX_BACKOFF)));
+            }
+        }
+    }
+
+    // Dropping the subscriber unsubscribes it upstream, and no more copies
+    // arrive on it
+    fn unsubscribe(&mut self, pattern: &str) {
+        self.retries.remove(pattern);
+        if self.streams.remove(pattern).is_some() {
+            self.patterns.remove(pattern, &pattern.to_string());
+            self.copies.forget(pattern);
+            info!("Unsubscribed from {}", pattern);
+        }
+    }
+
+    fn next_retry(&self) -> Option<Instant> {
+        self.retries.values().map(|(at, _)| *at).min()
+    }
+
+    async fn retry_due(&mut self, client: &async_nats::Client) {
+        let now = Instant::now();
+        let due: Vec<_> = self.retries.iter().filter(|(_, (at, _))| *at <= now).map(|(p, (_, b))| (p.clone(), *b)).collect();
+        for (pattern, backoff) in due {
+            self.retries.remove(&pattern);
+            self.subscribe(client, pattern, backoff).await;
+        }
+    }
+
+    // Forward a message that arrived on `pattern` unless it is a copy of
+    // one already forwarded from another overlapping subscription
+    fn forward(&mut self, clients: &Clients, pattern: &str, msg: async_nats::Message) {
+        let Some(message) = parse_message(&msg) else {
+            return;
+        };
+        let id = message_id(&msg.headers);
+        let key = MessageKey::new(&message, id);
+        if self.copies.take(&key, pattern) {
+            return;
+        }
+        let others: HashSet<String> = self.patterns.matches(&message.subject).into_iter().filter(|p| *p != pattern).cloned().collect();
+        self.copies.expect(key, others, Instant::now());
+        broadcast(clients, &message, id);
+    }
+
+    // Unsubscribe from everything, then forward the messages that had
+    // already arrived so clients get them before their connections are
+    // closed
+    async fn drain(mut self, clients: &Clients, client: &async_nats::Client) {
+        info!("Draining NEX Stream subscriptions...");
+        for (pattern, subscription) in self.streams.iter_mut() {
+            if let Err(e) = subscription.subscriber.unsubscribe().await {
+                warn!("Failed to unsubscribe from {}: {}", pattern, e);
+            }
+        }
+        if let Err(e) = client.flush().await {
+            warn!("Failed to flush the NATS connection: {}", e);
+        }
+
+        let mut forwarded = 0;
+        while let Some((pattern, msg)) = self.streams.next().await {
+            if let Some(msg) = msg {
+                self.forward(clients, &pattern, msg);
+                forwarded += 1;
+            }
+        }
+        info!("Drained NEX Stream subscriptions, forwarding {} pending messages", forwarded);
+    }
+}
+
+// NATS delivers a message once per matching subscription on the
+// connection. The first copy is forwarded and the patterns the others will
+// arrive on are remembered by message identity, so each later copy is
+// dropped exactly once, whichever subscription it comes on. Repeats of a
+// payload are separate entries, oldest first.
+#[derive(Default)]
+struct Copies {
+    // Entries for each key, oldest first, by the ID they were added under
+    expected: HashMap<MessageKey, VecDeque<(u64, HashSet<String>)>>,
+    // When each entry was added, to give up on copies that never come
+    added: VecDeque<(Instant, MessageKey, u64)>,
+    next_id: u64,
+}
+
+impl Copies {
+    fn expect(&mut self, key: MessageKey, patterns: HashSet<String>, now: Instant) {
+        self.expire(now);
+        if patterns.is_empty() {
+            return;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.added.push_back((now, key.clone(), id));
+        self.expected.entry(key).or_default().push_back((id, patterns));
+    }
+
+    // Whether a copy of `key` was expected on `pattern`, which it no
+    // longer is
+    fn take(&mut self, key: &MessageKey, pattern: &str) -> bool {
+        let Some(entries) = self.expected.get_mut(key) else {
+            return false;
+        };
+        let Some(index) = entries.iter().position(|(_, patterns)| patterns.contains(pattern)) else {
+            return false;
+        };
+        entries[index].1.remove(pattern);
+        if entries[index].1.is_empty() {
+            entries.remove(index);
+        }
+        if entries.is_empty() {
+            self.expected.remove(key);
+        }
+        true
+    }
+
+    fn forget(&mut self, pattern: &str) {
+        self.expected.retain(|_, entries| {
+            entries.retain_mut(|(_, patterns)| {
+                patterns.remove(pattern);
+                !patterns.is_empty()
+            });
+            !entries.is_empty()
+        });
+    }
+
+    // Drop the entries added more than `COPY_WINDOW` ago. Each goes by its
+    // own ID, since `take` may already have used up older ones for the key.
+    fn expire(&mut self, now: Instant) {
+        while let Some((added, key, id)) = self.added.front() {
+            if now.duration_since(*added) < COPY_WINDOW {
+                break;
+            }
+            if let Some(entries) = self.expected.get_mut(key) {
+                entries.retain(|(entry, _)| entry != id);
+                if entries.is_empty() {
+                    self.expected.remove(key);
+                }
+            }
+            self.added.pop_front();
+        }
+    }
+}
+
+// Follow the NATS client's connection events; it reconnects on its own,
+// resubscribing as it goes, and reports each successful attempt as
+// `Connected`
+fn record_event(clients: &Clients, event: &Event) {
+    match event {
+        Event::Connected => clients.set_upstream_state(UpstreamState::Connected),
+        Event::Disconnected => {
+            warn!("Disconnected from NATS server");
+            clients.set_upstream_state(UpstreamState::Disconnected);
+        },
+        other => warn!("NATS connection event: {}", other),
+    }
+}
+
+// Wrap an upstream NATS message for clients
+fn parse_message(msg: &async_nats::Message) -> Option<NexStreamMessage> {
+    let payload = String::from_utf8_lossy(&msg.payload);
+    debug!("Received message on {}: {}", msg.subject, payload);
+
+    // Try to parse the message
+    let data = match serde_json::from_str::<serde_json::Value>(&payload) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Failed to parse message: {}", e);
+            return None;
+        }
+    };
+
+    // Create a NEX Stream message
+    Some(NexStreamMessage::new(msg.subject.clone(), data, Some(Utc::now().timestamp_millis() as u64)))
+}
+
+// Serialize to JSON and send to all subscribed clients
+fn broadcast(clients: &Clients, message: &NexStreamMessage, id: Option<&str>) {
+    match serde_json::to_string(message) {
+        Ok(message_json) => {
+            clients.broadcast(message, &message_json, id);
+        },
+        Err(e) => {
+            error!("Failed to serialize message: {}", e);
+        }
+    }
+}
+
+// Replay stored history of `pattern` to one client through a JetStream
+// ordered consumer, then hand the client over to live delivery
+async fn replay_history(
+    client: async_nats::Client,
+    jetstream: jetstream::Context,
+    clients: Clients,
+    client_id: String,
+    pattern: String,
+    replay: ReplaySpec,
+    limit: u64,
+) {
+    let outcome = stream_history(&client, &jetstream, &clients, &client_id, &pattern, &replay, limit)
+        .await
+        .map_err(|e| e.to_string());
+    clients.finish_replay(&client_id, &pattern, outcome);
+}
+
+async fn stream_history(
+    client: &async_nats::Client,
+    jetstream: &jetstream::Context,
+    clients: &Clients,
+    client_id: &str,
+    pattern: &str,
+    replay: &ReplaySpec,
+    limit: u64,
+) -> Result<usize, async_nats::Error> {
+    let stream = jetstream.get_stream(find_stream(jetstream, pattern).await?).await?;
+
+    let wanted = match replay {
+        ReplaySpec::Last(n) => (*n).min(limit),
+        ReplaySpec::Since(_) => limit,
+    };
+    if wanted == 0 {
+        return Ok(0);
+    }
+    let mut deliver_policy = match replay {
+        ReplaySpec::Since(start) => {
+            let start = start.to_datetime()?;
+            let nanos = start.timestamp_nanos_opt().ok_or("Replay start out of range")?;
+            consumer::DeliverPolicy::ByStartTime {
+                start_time: time::OffsetDateTime::from_unix_timestamp_nanos(nanos as i128)?,
+            }
+        },
+        // The last message on the pattern is one the server can find itself
+        ReplaySpec::Last(1) => consumer::DeliverPolicy::Last,
+        ReplaySpec::Last(_) => consumer::DeliverPolicy::All,
+    };
+
+    // With more stored than wanted, start where only the wanted most recent
+    // messages are left rather than reading and skipping the rest
+    let available = pending_from(&stream, pattern, deliver_policy).await?;
+    if available == 0 {
+        return Ok(0);
+    }
+    if available > wanted {
+        let start_sequence = start_of_last(&stream, pattern, wanted).await?;
+        deliver_policy = consumer::DeliverPolicy::ByStartSequence { start_sequence };
+    }
+
+    let consumer = stream
+        .create_consumer(consumer::push::OrderedConfig {
+            deliver_subject: client.new_inbox(),
+            filter_subject: pattern.to_string(),
+            deliver_policy,
+            ..Default::default()
+        })
+        .await?;
+
+    let mut messages = consumer.messages().await?;
+    let mut sent = 0;
+    while let Some(msg) = messages.next().await {
+        let msg = msg?;
+        let info = msg.info()?;
+        let caught_up = info.pending == 0;
+
+        match serde_json::from_slice::<serde_json::Value>(&msg.payload) {
+            Ok(data) => {
+                let timestamp = (info.published.unix_timestamp_nanos() / 1_000_000) as u64;
+                let message = NexStreamMessage::new(msg.subject.clone(), data, Some(timestamp));
+                if !clients.deliver_replayed(client_id, pattern, &message, message_id(&msg.headers)) {
+                    debug!("Replay of {} for {} cancelled", pattern, client_id);
+                    return Ok(sent);
+                }
+                sent += 1;
+            },
+            Err(e) => {
+                warn!("Skipping unparseable replayed message on {}: {}", msg.subject, e);
+            }
+        }
+
+        if caught_up || sent as u64 >= wanted {
+            break;
+        }
+    }
+
+    Ok(sent)
+}
+
+// Messages on `pattern` a consumer starting at `deliver_policy` would get,
+// read from a short-lived pull consumer that is never asked for any
+async fn pending_from(
+    stream: &jetstream::stream::Stream,
+    pattern: &str,
+    deliver_policy: consumer::DeliverPolicy,
+) -> Result<u64, async_nats::Error> {
+    let consumer = stream
+        .create_consumer(consumer::pull::Config {
+            filter_subject: pattern.to_string(),
+            deliver_policy,
+            inactive_threshold: Duration::from_secs(5),
+            ..Default::default()
+        })
+        .await?;
+    let info = consumer.cached_info();
+    if let Err(e) = stream.delete_consumer(&info.name).await {
+        debug!("Failed to delete counting consumer {}: {}", info.name, e);
+    }
+    Ok(info.num_pending)
+}
+
+// The latest stream sequence from which at least `wanted` messages on
+// `pattern` remain. A filtered stream cannot be entered at "N messages
+// before the end", so this searches the sequence range, counting with
+// `pending_from` in O(log n) lookups instead of reading the stream.
+async fn start_of_last(stream: &jetstream::stream::Stream, pattern: &str, wanted: u64) -> Result<u64, async_nats::Error> {
+    let state = &stream.cached_info().state;
+    let (mut low, mut high) = (state.first_sequence, state.last_sequence);
+    while low < high {
+        let middle = low + (high - low).div_ceil(2);
+        let from_middle = consumer::DeliverPolicy::ByStartSequence { start_sequence: middle };
+        if pending_from(stream, pattern, from_middle).await? >= wanted {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    Ok(low)
+}
+
+// The publisher's `Nats-Msg-Id`, which JetStream stores with the message
+fn message_id(headers: &Option<async_nats::HeaderMap>) -> Option<&str> {
+    let id = headers.as_ref()?.get(async_nats::header::NATS_MESSAGE_ID)?;
+    Some(id.into())
+}
+
+#[derive(Deserialize)]
+struct StreamNames {
+    streams: Option<Vec<String>>,
+}
+
+// Find the JetStream stream that captures messages on `pattern`
+async fn find_stream(jetstream: &jetstream::Context, pattern: &str) -> Result<String, async_nats::Error> {
+    let request = serde_json::json!({ "subject": pattern });
+    match jetstream.request("STREAM.NAMES".to_string(), &request).await? {
+        Response::Ok(StreamNames { streams }) => streams
+            .and_then(|names| names.into_iter().next())
+            .ok_or_else(|| format!("No JetStream stream stores '{}'", pattern).into()),
+        Response::Err { error } => Err(error.to_string().into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn copies_are_dropped_once_per_overlapping_subscription() {
+        let mut copies = Copies::default();
+        let key = MessageKey::Id("a".to_string());
+
+        // The same payload published twice, arriving first on `>` both times
+        assert!(!copies.take(&key, ">"));
+        copies.expect(key.clone(), patterns(&["market.>", "market.*.ticker"]), Instant::now());
+        assert!(!copies.take(&key, ">"));
+        copies.expect(key.clone(), patterns(&["market.>", "market.*.ticker"]), Instant::now());
+
+        assert!(copies.take(&key, "market.>"));
+        assert!(copies.take(&key, "market.*.ticker"));
+        assert!(copies.take(&key, "market.>"));
+
+        // Once a pattern is unsubscribed its copies are no longer expected
+        copies.forget("market.*.ticker");
+        assert!(!copies.take(&key, "market.*.ticker"));
+        assert!(copies.expected.is_empty());
+    }
+
+    #[test]
+    fn copies_expire_by_their_own_entry() {
+        let mut copies = Copies::default();
+        let key = MessageKey::Id("a".to_string());
+        let start = Instant::now();
+
+        // The first copy is used up before a second message with the same
+        // key arrives, still within the window of the first
+        copies.expect(key.clone(), patterns(&["market.>"]), start);
+        assert!(copies.take(&key, "market.>"));
+        copies.expect(key.clone(), patterns(&["market.>"]), start + COPY_WINDOW / 2);
+
+        // The first entry's time running out leaves the second in place
+        copies.expire(start + COPY_WINDOW);
+        assert!(copies.take(&key, "market.>"));
+        assert!(!copies.take(&key, "market.>"));
+
+        copies.expect(key.clone(), patterns(&["market.>"]), start + COPY_WINDOW);
+        copies.expire(start + COPY_WINDOW * 2);
+        assert!(copies.expected.is_empty());
+        assert!(copies.added.is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+// The simulated market the simple publisher sends to the proxy's `/publish`
+// endpoint over one long-lived WebSocket, kept apart from its command line
+// so tests can run it against a proxy of their own
+use std::error::Error;
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use log::{info, error, warn};
+use market_model::Envelope;
+use market_sim::{envelopes, Feed, Market, MarketConfig};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// Reconnection delays, doubling after each failed attempt
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// A connection to the proxy, opened on first use and reopened after it
+// fails. Messages produced while it is down are dropped rather than queued,
+// as they would be stale by the time it is back.
+pub struct ProxyConnection {
+    url: Url,
+    token: Option<String>,
+    // The sending half, and the task logging what the proxy sends back
+    socket: Option<(SplitSink<Socket, Message>, JoinHandle<()>)>,
+    backoff: Duration,
+    retry_at: Instant,
+}
+
+impl ProxyConnection {
+    // `url` is the proxy's publish endpoint, e.g. `ws://127.0.0.1:3030/publish`
+    pub fn new(url: Url, token: Option<String>) -> Self {
+        ProxyConnection {
+            url,
+            token,
+            socket: None,
+            backoff: MIN_BACKOFF,
+            retry_at: Instant::now(),
+        }
+    }
+
+    // Send `messages`, connecting first if need be. Returns false when they
+    // were dropped because the proxy cannot be reached yet.
+    pub async fn send(&mut self, messages: &[Envelope]) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        // The proxy closing the connection ends the reader first
+        if self.socket.as_ref().is_some_and(|(_, reader)| reader.is_finished()) {
+            warn!("Proxy closed the connection");
+            self.disconnect();
+        }
+        if self.socket.is_none() && !self.connect().await {
+            return Ok(false);
+        }
+
+        let Some((sink, _)) = self.socket.as_mut() else {
+            return Ok(false);
+        };
+        let mut result = Ok(());
+        for message in messages {
+            result = sink.feed(Message::Text(serde_json::to_string(message)?)).await;
+            if result.is_err() {
+                break;
+            }
+        }
+        if let Err(e) = result.and(sink.flush().await) {
+            self.disconnect();
+            return Err(format!("Failed to send to {}: {}", self.url, e).into());
+        }
+        Ok(true)
+    }
+
+    // Try to connect unless still backing off from the last failure
+    async fn connect(&mut self) -> bool {
+        if Instant::now() < self.retry_at {
+            return false;
+        }
+
+        let request = self.url.as_str().into_client_request().map(|mut request| {
+            if let Some(token) = &self.token {
+                if let Ok(value) = format!("Bearer {}", token).parse() {
+                    request.headers_mut().insert(AUTHORIZATION, value);
+                }
+            }
+            request
+        });
+        match request {
+            Ok(request) => match connect_async(request).await {
+                Ok((socket, _)) => {
+                    info!("Connected to {}", self.url);
+                    let (sink, stream) = socket.split();
+                    self.socket = Some((sink, tokio::spawn(log_replies(stream))));
+                    self.backoff = MIN_BACKOFF;
+                    return true;
+                },
+                Err(e) => error!("Failed to connect to {}: {}", self.url, e),
+            },
+            Err(e) => error!("Invalid proxy URL {}: {}", self.url, e),
+        }
+
+        warn!("Retrying in {:?}...", self.backoff);
+        self.retry_at = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        false
+    }
+
+    fn disconnect(&mut self) {
+        if let Some((_, reader)) = self.socket.take() {
+            reader.abort();
+        }
+    }
+}
+
+impl Drop for ProxyConnection {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+// The proxy only answers messages it rejected
+async fn log_replies(mut stream: SplitStream<Socket>) {
+    while let Some(frame) = stream.next().await {
+        match frame {
+            Ok(Message::Text(text)) => warn!("Proxy rejected a message: {}", text),
+            Ok(Message::Close(frame)) => {
+                info!("Proxy is closing the connection: {:?}", frame);
+                break;
+            },
+            Ok(_) => {},
+            Err(e) => {
+                error!("Connection to proxy failed: {}", e);
+                break;
+            },
+        }
+    }
+}
+
+// Publish simulated data to the proxy
+pub async fn publish_simulated_data(
+    mut connection: ProxyConnection,
+    subject: String,
+    pair: String,
+    interval: u64,
+    initial_price: f64,
+    volatility: f64,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!("Starting to publish simulated data to {}", connection.url);
+    info!("Initial price: ${:.2}, Volatility: {:.2}%, Interval: {}ms",
+          initial_price, volatility, interval);
+
+    // Order flow sized to how far the fair value moves in a second
+    let interval = Duration::from_millis(interval.max(1));
+    let per_second = volatility / interval.as_secs_f64().sqrt();
+    let market = Market::new(MarketConfig::for_price(initial_price, per_second), initial_price);
+    let mut feed = Feed::new(market, pair, "simple");
+    let mut last = Instant::now();
+
+    // Publish loop
+    loop {
+        // Move the fair value and run the order book up to now
+        feed.market_mut().walk(volatility);
+        let now = Instant::now();
+        let timestamp = Utc::now().timestamp_millis() as u64;
+        let messages = feed.advance(now - last, timestamp);
+        last = now;
+
+        let mut batch = Vec::with_capacity(messages.len());
+        for envelope in envelopes(&subject, &messages, timestamp) {
+            match envelope {
+                Ok(envelope) => batch.push(envelope),
+                Err(e) => error!("{}", e),
+            }
+        }
+
+        match connection.send(&batch).await {
+            Ok(true) => info!("Published {} messages: {} ${:.2}",
+                              batch.len(), feed.pair(), feed.market().fair_value()),
+            Ok(false) => {},
+            Err(e) => error!("{}", e),
+        }
+
+        // Wait for the next interval
+        sleep(interval).await;
+    }
+}
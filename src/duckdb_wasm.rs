@@ -1,6 +1,7 @@
 use wasm_bindgen::prelude::*;
 use serde::{Serialize, Deserialize};
 use js_sys::Array;
+use market_model::{Side, Trade, Validate};
 use web_sys::console;
 use wasm_bindgen::JsValue;
 
@@ -10,16 +11,6 @@ extern "C" {
     fn log(s: &str);
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct TradeData {
-    pub price: f64,
-    pub size: f64,
-    pub side: String,
-    pub exchange: String,
-    pub pair: String,
-    pub timestamp: u64,
-}
-
 #[derive(Serialize, Deserialize)]
 pub struct QueryResult {
     pub rows: Vec<serde_json::Value>,
@@ -27,7 +18,13 @@ pub struct QueryResult {
 
 #[wasm_bindgen]
 pub struct DuckDBConnection {
-    trades: Vec<TradeData>,
+    trades: Vec<Trade>,
+}
+
+impl Default for DuckDBConnection {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[wasm_bindgen]
@@ -74,19 +71,29 @@ impl DuckDBConnection {
             if row_array.length() >= 6 {
                 let price = row_array.get(0).as_f64().unwrap_or(0.0);
                 let size = row_array.get(1).as_f64().unwrap_or(0.0);
-                let side = row_array.get(2).as_string().unwrap_or_else(|| "unknown".to_string());
+                let side = match row_array.get(2).as_string().as_deref() {
+                    Some("buy") => Side::Buy,
+                    Some("sell") => Side::Sell,
+                    _ => continue,
+                };
                 let exchange = row_array.get(3).as_string().unwrap_or_else(|| "unknown".to_string());
                 let pair = row_array.get(4).as_string().unwrap_or_else(|| "unknown".to_string());
                 let timestamp = row_array.get(5).as_f64().unwrap_or(0.0) as u64;
                 
-                self.trades.push(TradeData {
+                let trade = Trade {
                     price,
                     size,
                     side,
                     exchange,
                     pair,
+                    trade_id: None,
                     timestamp,
-                });
+                };
+                
+                // Skip rows that could not be read as a trade
+                if trade.validate().is_ok() {
+                    self.trades.push(trade);
+                }
             }
         }
         
@@ -102,7 +109,7 @@ impl DuckDBConnection {
         
         for i in 0..100 {
             // Add some randomness to the price
-            price = price + (js_sys::Math::random() - 0.5) * 100.0;
+            price += (js_sys::Math::random() - 0.5) * 100.0;
             
             // Create a time point in the past
             let time = now - ((100 - i) * 60000) as u64;
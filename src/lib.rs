@@ -1,24 +1,85 @@
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsValue;
 use web_sys::{MessageEvent, WebSocket, console};
-use serde::{Serialize, Deserialize};
+use market_model::{Encoding, Envelope as NexStreamMessage, MarketData, Validate};
+use serde::Deserialize;
 use serde_json::json;
 
 mod duckdb_wasm;
 pub use duckdb_wasm::*;
 
-// Define NEX Stream message structure
-#[derive(Serialize, Deserialize, Debug)]
-struct NexStreamMessage {
-    subject: String,
-    data: serde_json::Value,
-    timestamp: Option<u64>,
+// Any frame from the proxy, read only for its `type`; market data has none
+#[derive(Deserialize, Debug)]
+struct Tagged {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+// Replies the proxy sends besides market data, in the subscription's
+// encoding for snapshots, batches and replay outcomes and as JSON otherwise
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Reply {
+    // Recent history, right after a subscription is confirmed
+    Snapshot { messages: Vec<NexStreamMessage> },
+    // Trades the proxy held back and sent together
+    Batch { messages: Vec<NexStreamMessage> },
+    Error { code: String, message: String },
+    // Bookkeeping the chart has no use for
+    #[serde(
+        rename = "subscription_confirmed",
+        alias = "unsubscribed",
+        alias = "unsubscribed_all",
+        alias = "subscriptions",
+        alias = "replay_complete",
+        alias = "published",
+        alias = "lagged",
+        alias = "heartbeat",
+        alias = "upstream_status",
+        alias = "shutdown"
+    )]
+    Control,
+    // Not from the proxy, e.g. Coinbase messages after falling back
+    #[serde(other)]
+    Other,
+}
+
+// Encoding asked of the proxy for market data and snapshots, which then
+// arrive in binary frames; other control replies still come as JSON text
+const WIRE_ENCODING: Encoding = Encoding::Msgpack;
+
+// How often the proxy sends the trades it has batched up; charts need no
+// more than a few redraws a second however busy the market is
+const BATCH_INTERVAL_MS: u64 = 100;
+
+// Hand each message on to the page
+fn forward_all(messages: &[NexStreamMessage]) {
+    for nex_msg in messages {
+        if let Some(transformed_data) = transform_nex_data(nex_msg) {
+            send_to_js(&transformed_data);
+        }
+    }
+}
+
+// Act on a reply from the proxy; false when it isn't one of the proxy's
+fn handle_reply(reply: Reply) -> bool {
+    match reply {
+        Reply::Snapshot { messages } | Reply::Batch { messages } => forward_all(&messages),
+        Reply::Error { code, message } => console::error_1(&format!("NEX Stream proxy error ({}): {}", code, message).into()),
+        Reply::Control => {},
+        Reply::Other => return false,
+    }
+    true
 }
 
 #[wasm_bindgen(start)]
 pub fn start() -> Result<(), JsValue> {
-    // Connect to NEX Stream via our proxy
-    let nex_stream_url = "ws://localhost:3030/ws";
+    // Connect to NEX Stream via our proxy, over TLS when the page itself is
+    // served over HTTPS since browsers block plain WebSockets from there
+    let secure = web_sys::window()
+        .and_then(|window| window.location().protocol().ok())
+        .is_some_and(|protocol| protocol == "https:");
+    let nex_stream_url = if secure { "wss://localhost:3030/ws" } else { "ws://localhost:3030/ws" };
     
     console::log_1(&format!("Connecting to NEX Stream via proxy at {}...", nex_stream_url).into());
     
@@ -31,6 +92,10 @@ pub fn start() -> Result<(), JsValue> {
         }
     };
     
+    // Binary frames arrive as ArrayBuffers rather than Blobs, so they can
+    // be decoded straight away
+    ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
+    
     // Set up connection open handler
     let onopen_callback = Closure::<dyn FnMut()>::new(move || {
         console::log_1(&"WebSocket connection established".into());
@@ -47,17 +112,48 @@ pub fn start() -> Result<(), JsValue> {
     
     // Set up message handler
     let onmessage_callback = Closure::<dyn FnMut(_)>::new(move |event: MessageEvent| {
+        // Market data and replies in the encoding asked for when subscribing
+        if let Some(buffer) = event.data().dyn_ref::<js_sys::ArrayBuffer>() {
+            let bytes = js_sys::Uint8Array::new(buffer).to_vec();
+            let result = match WIRE_ENCODING.decode::<Tagged>(&bytes) {
+                Ok(Tagged { kind: Some(kind) }) => WIRE_ENCODING.decode::<Reply>(&bytes).map(|reply| {
+                    if !handle_reply(reply) {
+                        console::warn_1(&format!("Ignoring binary message of unknown type {}", kind).into());
+                    }
+                }),
+                Ok(Tagged { kind: None }) => WIRE_ENCODING.decode::<NexStreamMessage>(&bytes).map(|nex_msg| forward_all(&[nex_msg])),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                console::warn_1(&format!("Ignoring undecodable binary message: {}", e).into());
+            }
+            return;
+        }
+        
         if let Some(data) = event.data().as_string() {
+            let reply = match serde_json::from_str::<Tagged>(&data) {
+                Ok(Tagged { kind: Some(_) }) => serde_json::from_str::<Reply>(&data).ok(),
+                _ => None,
+            };
+            if let Some(reply) = reply {
+                // Anything the proxy doesn't send is passed through as is
+                if !handle_reply(reply) {
+                    send_to_js(&data);
+                }
+                return;
+            }
+            
             // Try to parse as NEX Stream message
             match serde_json::from_str::<NexStreamMessage>(&data) {
                 Ok(nex_msg) => {
                     // Process NEX Stream message
-                    let transformed_data = transform_nex_data(&nex_msg);
-                    let _ = send_to_js(&transformed_data);
+                    if let Some(transformed_data) = transform_nex_data(&nex_msg) {
+                        send_to_js(&transformed_data);
+                    }
                 },
                 Err(_) => {
                     // If not a NEX Stream message, pass through as is (e.g., Coinbase data)
-                    let _ = send_to_js(&data);
+                    send_to_js(&data);
                 }
             }
         }
@@ -68,7 +164,9 @@ pub fn start() -> Result<(), JsValue> {
     // Try to subscribe to NEX Stream
     let nex_sub_msg = json!({
         "action": "subscribe",
-        "subject": "market.btc-usd.trades"
+        "subject": "market.btc-usd.trades",
+        "encoding": WIRE_ENCODING,
+        "delivery": { "mode": "batch", "interval_ms": BATCH_INTERVAL_MS }
     }).to_string();
     
     // Also set up a fallback subscription for Coinbase
@@ -89,13 +187,33 @@ pub fn start() -> Result<(), JsValue> {
     Ok(())
 }
 
-// Transform NEX Stream data to a format compatible with our application
-fn transform_nex_data(nex_msg: &NexStreamMessage) -> String {
-    // Extract relevant data from NEX Stream message
-    // This is a simplified example - adjust based on actual NEX Stream data format
-    let price = nex_msg.data.get("price")
-        .and_then(|v| v.as_f64())
-        .unwrap_or(0.0);
+// Transform NEX Stream data to a format compatible with our application,
+// or None for messages without a price to chart
+fn transform_nex_data(nex_msg: &NexStreamMessage) -> Option<String> {
+    if let Err(e) = nex_msg.validate() {
+        console::warn_1(&format!("Ignoring message on {}: {}", nex_msg.subject, e).into());
+        return None;
+    }
+
+    // Trades chart their price and tickers the last price or the mid;
+    // anything else that isn't market data may still carry a `price`
+    let price = match nex_msg.market_data() {
+        Ok(market_data) => {
+            if let Err(e) = market_data.validate() {
+                console::warn_1(&format!("Ignoring message on {}: {}", nex_msg.subject, e).into());
+                return None;
+            }
+            match market_data {
+                MarketData::Trade(trade) => trade.price,
+                MarketData::Ticker(ticker) => ticker.price.or_else(|| ticker.mid())?,
+                MarketData::Candle(candle) => candle.close,
+                MarketData::Level2(_) => return None,
+            }
+        },
+        Err(_) => nex_msg.data.get("price")
+            .and_then(|v| v.as_f64())
+            .unwrap_or(0.0),
+    };
     
     let timestamp = nex_msg.timestamp
         .unwrap_or_else(|| js_sys::Date::now() as u64);
@@ -106,13 +224,13 @@ fn transform_nex_data(nex_msg: &NexStreamMessage) -> String {
     let time = date.to_iso_string().as_string().unwrap_or_default();
     
     // Create a message in the format expected by our JavaScript code
-    json!({
+    Some(json!({
         "type": "ticker",
         "price": price.to_string(),
         "time": time,
         "source": "nex_stream",
         "subject": nex_msg.subject
-    }).to_string()
+    }).to_string())
 }
 
 #[wasm_bindgen(module = "/js/duckdb.js")]
@@ -11,4 +11,4 @@ cd ..
 
 # Start the NEX publisher
 echo "Starting NEX publisher..."
-RUST_LOG=info ./nex-publisher/target/release/nex-publisher --nats-url "$NATS_URL" "$@"
\ No newline at end of file
+RUST_LOG=info ./target/release/nex-publisher --nats-url "$NATS_URL" "$@"
\ No newline at end of file
@@ -12,4 +12,4 @@ cd ..
 # Start the server with NEX Stream
 echo "Starting server with NEX Stream at $NEX_URL..."
 cd proxy
-RUST_LOG=info ./target/release/rt-duckdb-coinbase-server --nex-url "$NEX_URL" --proxy-port 3030 --http-port 54572 --static-dir ".." "$@"
\ No newline at end of file
+RUST_LOG=info ../target/release/rt-duckdb-coinbase-server --nex-url "$NEX_URL" --proxy-port 3030 --http-port 54572 --static-dir ".." "$@"
\ No newline at end of file
@@ -5,4 +5,4 @@ echo "Building simple publisher..."
 cd simple-publisher
 cargo build --release
 echo "Starting simple publisher..."
-RUST_LOG=info ./target/release/simple-publisher "$@"
\ No newline at end of file
+RUST_LOG=info ../target/release/simple-publisher "$@"
\ No newline at end of file
@@ -42,10 +42,10 @@ cd proxy
 # Check if we should use simulation mode or NEX Stream
 if [ "$USE_SIMULATION" = "true" ]; then
   echo "Using simulation mode..."
-  RUST_LOG=info ./target/release/rt-duckdb-coinbase-server --simulate --proxy-port 3030 --http-port 54572 --static-dir ".."
+  RUST_LOG=info ../target/release/rt-duckdb-coinbase-server --simulate --proxy-port 3030 --http-port 54572 --static-dir ".."
 else
   echo "Connecting to NEX Stream at $NEX_URL..."
-  RUST_LOG=info ./target/release/rt-duckdb-coinbase-server --nex-url "$NEX_URL" --proxy-port 3030 --http-port 54572 --static-dir ".."
+  RUST_LOG=info ../target/release/rt-duckdb-coinbase-server --nex-url "$NEX_URL" --proxy-port 3030 --http-port 54572 --static-dir ".."
 fi
 
 # Simple publisher cleanup removed - no longer needed
\ No newline at end of file